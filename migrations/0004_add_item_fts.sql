@@ -0,0 +1,53 @@
+alter table item
+    add version_name TEXT default '' not null;
+
+alter table item
+    add description TEXT default '' not null;
+
+alter table item
+    add trained_words TEXT default '' not null;
+
+create virtual table if not exists item_fts using fts5
+(
+    name,
+    model_name,
+    version_name,
+    note,
+    description,
+    trained_words,
+    content = 'item',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2',
+    prefix = '2 3'
+);
+
+create trigger if not exists item_fts_ai
+    after insert
+    on item
+begin
+    insert into item_fts (rowid, name, model_name, version_name, note, description, trained_words)
+    values (new.id, new.name, new.model_name, new.version_name, new.note, new.description, new.trained_words);
+end;
+
+create trigger if not exists item_fts_ad
+    after delete
+    on item
+begin
+    insert into item_fts (item_fts, rowid, name, model_name, version_name, note, description, trained_words)
+    values ('delete', old.id, old.name, old.model_name, old.version_name, old.note, old.description,
+            old.trained_words);
+end;
+
+create trigger if not exists item_fts_au
+    after update of name, model_name, version_name, note, description, trained_words
+    on item
+begin
+    insert into item_fts (item_fts, rowid, name, model_name, version_name, note, description, trained_words)
+    values ('delete', old.id, old.name, old.model_name, old.version_name, old.note, old.description,
+            old.trained_words);
+    insert into item_fts (rowid, name, model_name, version_name, note, description, trained_words)
+    values (new.id, new.name, new.model_name, new.version_name, new.note, new.description, new.trained_words);
+end;
+
+insert into item_fts (item_fts)
+values ('rebuild');
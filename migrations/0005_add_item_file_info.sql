@@ -0,0 +1,12 @@
+alter table item
+    add file_size integer default 0 not null;
+
+alter table item
+    add has_preview integer default false not null;
+
+update item
+set created_at = updated_at
+where created_at is null;
+
+create index if not exists item_blake3_index
+    on item (blake3);
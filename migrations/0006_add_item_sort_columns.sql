@@ -0,0 +1,14 @@
+alter table item
+    add civitai_downloads integer default 0 not null;
+
+alter table item
+    add civitai_rating real default 0 not null;
+
+alter table item
+    add scanned_at integer default 0 not null;
+
+create index if not exists item_updated_at_index
+    on item (updated_at, id);
+
+create index if not exists item_created_at_index
+    on item (created_at, id);
@@ -0,0 +1,10 @@
+create unique index if not exists tag_type_type_uindex
+    on tag_type (type);
+
+insert or ignore into tag_type (type)
+values ('base'),
+       ('type'),
+       ('style'),
+       ('character'),
+       ('format'),
+       ('user');
@@ -0,0 +1,13 @@
+create table if not exists tag_alias
+(
+    id    integer not null
+        constraint tag_alias_pk
+            primary key autoincrement,
+    alias TEXT    not null
+        constraint tag_alias_pk_2
+            unique,
+    tag   integer not null
+        constraint tag_alias_tag_id_fk
+            references tag
+            on update cascade on delete cascade
+);
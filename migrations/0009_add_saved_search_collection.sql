@@ -0,0 +1,39 @@
+create table if not exists saved_search
+(
+    id             integer               not null
+        constraint saved_search_pk
+            primary key autoincrement,
+    name           TEXT                  not null
+        constraint saved_search_pk_2
+            unique,
+    search         TEXT    default ''    not null,
+    tag_only       integer default false not null,
+    duplicate_only integer default false not null
+);
+
+create table if not exists collection
+(
+    id          integer         not null
+        constraint collection_pk
+            primary key autoincrement,
+    title       TEXT            not null,
+    description TEXT default '' not null
+);
+
+create table if not exists collection_item
+(
+    collection integer not null
+        constraint collection_item_collection_id_fk
+            references collection
+            on update cascade on delete cascade,
+    item       integer not null
+        constraint collection_item_item_id_fk
+            references item
+            on update cascade on delete cascade,
+    position   integer not null,
+    constraint collection_item_pk
+        primary key (collection, item)
+);
+
+create index if not exists collection_item_position_index
+    on collection_item (collection, position);
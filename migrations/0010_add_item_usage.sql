@@ -0,0 +1,19 @@
+alter table item
+    add favorite integer default false not null;
+
+alter table item
+    add rating integer
+        constraint item_rating_check
+            check (rating between 1 and 5);
+
+alter table item
+    add last_used_at integer;
+
+alter table item
+    add use_count integer default 0 not null;
+
+alter table item
+    add sha256 TEXT default '' not null;
+
+create index if not exists item_sha256_index
+    on item (sha256);
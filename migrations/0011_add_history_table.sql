@@ -0,0 +1,30 @@
+create table if not exists history
+(
+    id         integer         not null
+        constraint history_pk
+            primary key autoincrement,
+    user       TEXT default '' not null,
+    created_at integer         not null,
+    action     TEXT            not null,
+    -- Not a foreign key so history is kept after item is removed
+    item       integer,
+    old_value  TEXT default '' not null,
+    new_value  TEXT default '' not null
+);
+
+create index if not exists history_item_index
+    on history (item, id);
+
+create trigger if not exists history_no_update
+    before update
+    on history
+begin
+    select raise(abort, 'history is append-only');
+end;
+
+create trigger if not exists history_no_delete
+    before delete
+    on history
+begin
+    select raise(abort, 'history is append-only');
+end;
@@ -0,0 +1,3 @@
+-- Modified time of `.sdmm.json` when it was last written or read, so an unchanged sidecar is not merged again
+alter table item
+    add sidecar_synced_at integer;
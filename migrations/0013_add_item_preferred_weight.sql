@@ -0,0 +1,2 @@
+alter table item
+    add preferred_weight real;
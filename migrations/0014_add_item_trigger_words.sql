@@ -0,0 +1,7 @@
+alter table item
+    add trigger_words_edited integer default false not null;
+
+-- Trigger words are stored one per line. Words of existing items are read again from their `.json` on next start,
+-- since a word may contain a comma.
+insert into app_info (label, value)
+values ('trigger_words_backfilled', 0);
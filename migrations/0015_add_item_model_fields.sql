@@ -0,0 +1,68 @@
+-- Model info which was added as `base:`, `type:` and `format:` tags
+alter table item
+    add model_type TEXT default '' not null;
+
+alter table item
+    add base_model TEXT default '' not null;
+
+alter table item
+    add civitai_model_id integer;
+
+alter table item
+    add civitai_version_id integer;
+
+alter table item
+    add creator TEXT default '' not null;
+
+-- `fp16`, `fp32`, ...
+alter table item
+    add precision TEXT default '' not null;
+
+-- `SafeTensor`, `PickleTensor`, ...
+alter table item
+    add format TEXT default '' not null;
+
+-- `full` or `pruned`
+alter table item
+    add size_type TEXT default '' not null;
+
+alter table item
+    add nsfw integer default false not null;
+
+alter table item
+    add nsfw_level integer default 0 not null;
+
+alter table item
+    add poi integer default false not null;
+
+-- License of Civitai model. NULL if unknown.
+alter table item
+    add allow_no_credit integer;
+
+-- Comma separated, e.g. `Image,RentCivit`
+alter table item
+    add allow_commercial_use TEXT default '' not null;
+
+alter table item
+    add allow_derivatives integer;
+
+alter table item
+    add allow_different_license integer;
+
+create index if not exists item_base_model_index
+    on item (base_model);
+
+create index if not exists item_civitai_model_id_index
+    on item (civitai_model_id);
+
+-- Fields of existing items are read from their `.json` and `.model.json` on next start
+insert into app_info (label, value)
+values ('model_fields_backfilled', 0);
+
+-- Tags of these namespaces are replaced by the fields above and are no longer updated by sync
+delete
+from tag_item
+where tag in (select tag.id
+              from tag
+                       join tag_type on tag_type.id = tag.type
+              where tag_type.type in ('base', 'type', 'format'));
@@ -0,0 +1,12 @@
+create table if not exists followed_creator
+(
+    username        TEXT not null
+        constraint followed_creator_pk
+            primary key,
+    -- Newest Civitai model of creator which is already announced. NULL if not checked yet.
+    newest_model_id integer,
+    checked_at      integer
+);
+
+create index if not exists item_creator_index
+    on item (creator);
@@ -0,0 +1,7 @@
+-- Set when license of Civitai model is different from the previous sync, until user acknowledges it
+alter table item
+    add license_changed_at integer;
+
+-- License before the change, e.g. `commercial:Image derivatives:yes`
+alter table item
+    add previous_license TEXT default '' not null;
@@ -0,0 +1,3 @@
+-- Set when sync finds that the model was removed from Civitai, cleared when it is found again
+alter table item
+    add removed_upstream_at integer;
@@ -0,0 +1,10 @@
+-- BLAKE3 of tensor data of `.safetensors`, without the header with `__metadata__`
+alter table item
+    add tensor_hash TEXT default '' not null;
+
+-- Modified time of file when `tensor_hash` was calculated
+alter table item
+    add tensor_hashed_at integer;
+
+create index if not exists item_tensor_hash_index
+    on item (tensor_hash);
@@ -0,0 +1,21 @@
+-- File of `item` is replaced by a link to the file of `canonical`, which has the same hash
+create table if not exists item_link
+(
+    item            integer not null
+        constraint item_link_pk
+            primary key
+        constraint item_link_item_id_fk
+            references item
+            on update cascade on delete cascade,
+    canonical       integer not null
+        constraint item_link_canonical_id_fk
+            references item
+            on update cascade on delete cascade,
+    -- `hardlink` or `symlink`
+    link_type       TEXT    not null,
+    reclaimed_bytes integer not null,
+    linked_at       integer not null
+);
+
+create index if not exists item_link_canonical_index
+    on item_link (canonical);
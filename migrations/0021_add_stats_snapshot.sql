@@ -0,0 +1,10 @@
+-- Disk usage of each collection per day, to chart library growth
+create table if not exists stats_snapshot
+(
+    day         TEXT    not null,
+    base_label  TEXT    not null,
+    item_count  integer not null,
+    total_bytes integer not null,
+    constraint stats_snapshot_pk
+        primary key (day, base_label)
+);
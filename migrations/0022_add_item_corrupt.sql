@@ -0,0 +1,7 @@
+-- Set when verification finds that the file does not match the hash recorded by Civitai or cannot be read
+alter table item
+    add corrupt_at integer;
+
+-- When the file was last verified
+alter table item
+    add verified_at integer;
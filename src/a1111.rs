@@ -0,0 +1,361 @@
+//! A1111/Forge user metadata. They are stored in `<name>.json` next to the model, together with Civitai info.
+//! Both use the `description` key: the Civitai version description is kept, and the item note is only written there
+//! when Civitai info has no description. A note written by sdmm is not indexed as Civitai description.
+
+use crate::config::Config;
+use crate::db::tag::update_item_note;
+use serde_json::{Map, Value, json, to_string_pretty};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::error;
+
+/// Keys of A1111/Forge user metadata. They are kept when Civitai info is saved to the same `.json`.
+pub const KEYS: [&str; 6] = [
+    "activation text",
+    "preferred weight",
+    "negative text",
+    "notes",
+    "sd version",
+    "description",
+];
+
+/// Values written by sdmm last time. A field is only updated if the UI has not changed it since then.
+const WRITTEN_KEY: &str = "sdmm written";
+
+/// Model types which have user metadata in A1111/Forge
+const MODEL_TYPES: [&str; 4] = ["LORA", "LoCon", "DoRA", "TextualInversion"];
+
+/// Copy user metadata and the values written by sdmm from `from` into `to` if `to` does not have them
+pub fn keep_metadata(from: &Value, to: &mut Value) {
+    let Some(to) = to.as_object_mut() else {
+        return;
+    };
+    for key in KEYS.into_iter().chain([WRITTEN_KEY]) {
+        if !is_empty(&from[key]) && to.get(key).is_none_or(is_empty) {
+            to.insert(key.to_string(), from[key].clone());
+        }
+    }
+}
+
+/// Civitai version description in `info`. Empty if `description` is the item note written by sdmm.
+pub fn civitai_description(info: &Value) -> &str {
+    if info[WRITTEN_KEY]["description"] == info["description"] {
+        return "";
+    }
+    info["description"].as_str().unwrap_or_default()
+}
+
+/// Use note and preferred weight of user metadata if item does not have them
+pub async fn import(conn: &mut SqliteConnection, item_id: i64, info: &Value) -> Result<(), sqlx::Error> {
+    let item = sqlx::query!("SELECT note, preferred_weight FROM item WHERE id = ?", item_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if let Some(notes) = info["notes"].as_str().map(str::trim).filter(|n| !n.is_empty())
+        && item.note.is_empty()
+    {
+        update_item_note(conn, item_id, notes).await?;
+    }
+    if let Some(weight) = info["preferred weight"].as_f64().filter(|w| *w != 0.0)
+        && item.preferred_weight.is_none()
+    {
+        sqlx::query!("UPDATE item SET preferred_weight = ? WHERE id = ?", weight, item_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Write user metadata of item if `sidecar.a1111` is set, e.g. after its trigger words are edited
+pub async fn write_if_enabled(config: &Config, pool: &SqlitePool, item_id: i64) {
+    if !config.sidecar.a1111 {
+        return;
+    }
+    if let Err(e) = async { write(config, &mut *pool.acquire().await?, item_id).await }.await {
+        error!("Failed to write A1111 metadata of item {}: {}", item_id, e);
+    }
+}
+
+/// Write user metadata of every LoRA and embedding. Return number of written files.
+pub async fn write_all(config: &Config, pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+    let ids = sqlx::query_scalar!("SELECT id FROM item WHERE is_checked = true")
+        .fetch_all(pool)
+        .await?;
+    let mut conn = pool.acquire().await?;
+    let mut count = 0;
+    for id in ids {
+        match write(config, &mut conn, id).await {
+            Ok(true) => count += 1,
+            Ok(false) => {}
+            Err(e) => error!("Failed to write A1111 metadata of item {}: {}", id, e),
+        }
+    }
+    Ok(count)
+}
+
+/// Write activation text, preferred weight and description (from note) of item into its `.json`, which is created if
+/// the model has no Civitai info. The model type is guessed from the folder if it is unknown.
+/// Fields changed by the UI and the description from Civitai are not overwritten. Return false if item is not a LoRA or embedding,
+/// or nothing is changed.
+pub async fn write(config: &Config, conn: &mut SqliteConnection, item_id: i64) -> anyhow::Result<bool> {
+    let item = sqlx::query!(
+        "SELECT base_label, path, note, trained_words, preferred_weight, model_type FROM item WHERE id = ?",
+        item_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let Some(base_path) = config.model_paths.get(&item.base_label) else {
+        return Err(anyhow::anyhow!("Unknown base path {}", item.base_label));
+    };
+    let model_path = PathBuf::from(base_path).join(&item.path);
+    let json_path = model_path.with_extension("json");
+
+    let model_type = match item.model_type.as_str() {
+        "" => guess_model_type(&model_path),
+        model_type => model_type,
+    };
+    if !MODEL_TYPES.iter().any(|t| t.eq_ignore_ascii_case(model_type)) {
+        return Ok(false);
+    }
+
+    let mut info: Value = match fs::read_to_string(&json_path).await {
+        Ok(info) => serde_json::from_str(&info).unwrap_or_default(),
+        // Model without Civitai info gets a `.json` with user metadata only
+        Err(e) if e.kind() == ErrorKind::NotFound => Value::Object(Map::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let activation_text = item.trained_words.lines().collect::<Vec<&str>>().join(", ");
+    let values = [
+        ("activation text", json!(activation_text)),
+        (
+            "preferred weight",
+            item.preferred_weight.map(|w| json!(w)).unwrap_or_default(),
+        ),
+        ("description", json!(item.note)),
+    ];
+
+    let Some(obj) = info.as_object_mut() else {
+        return Ok(false);
+    };
+    let mut written = obj
+        .get(WRITTEN_KEY)
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_else(Map::new);
+    let mut is_changed = false;
+    for (key, value) in values {
+        if is_empty(&value) {
+            continue;
+        }
+        // A description not written by sdmm is from Civitai or the UI
+        let current = obj.get(key).unwrap_or(&Value::Null);
+        let is_ui_changed = !is_empty(current) && written.get(key) != Some(current);
+        if !is_ui_changed && *current != value {
+            obj.insert(key.to_string(), value.clone());
+            written.insert(key.to_string(), value);
+            is_changed = true;
+        }
+    }
+    if !is_changed {
+        return Ok(false);
+    }
+    obj.insert(WRITTEN_KEY.to_string(), Value::Object(written));
+
+    fs::write(&json_path, to_string_pretty(&info)?).await?;
+    Ok(true)
+}
+
+/// Model type of a model without Civitai info from its folder, e.g. `models/Lora/a.safetensors`. Empty if unknown.
+fn guess_model_type(model_path: &Path) -> &'static str {
+    let folders = model_path.parent().map(Path::components).into_iter().flatten().rev();
+    for folder in folders {
+        let folder = folder.as_os_str().to_string_lossy().to_lowercase();
+        match folder.as_str() {
+            "lora" | "loras" | "lycoris" => return "LORA",
+            "embedding" | "embeddings" | "textual_inversion" => return "TextualInversion",
+            _ => {}
+        }
+    }
+    ""
+}
+
+/// Prompt to use the model, e.g. `<lora:name:0.8> trigger1, trigger2`.
+/// `name` is the file name without extension.
+pub fn prompt(model_type: &str, name: &str, weight: Option<f64>, trigger_words: &str) -> String {
+    let weight = weight.unwrap_or(1.0);
+    let network = if ["LORA", "LoCon", "DoRA"]
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(model_type))
+    {
+        format!("<lora:{name}:{weight}>")
+    } else if model_type.eq_ignore_ascii_case("Hypernetwork") {
+        format!("<hypernet:{name}:{weight}>")
+    } else if model_type.eq_ignore_ascii_case("TextualInversion") {
+        name.to_string()
+    } else {
+        String::new()
+    };
+    let words = trigger_words.lines().collect::<Vec<&str>>().join(", ");
+    [network, words]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// A1111 saves empty text and weight 0 for unset fields
+fn is_empty(value: &Value) -> bool {
+    value.is_null() || value.as_str().is_some_and(str::is_empty) || value.as_f64() == Some(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{TestDir, test_pool};
+    use std::collections::HashMap;
+
+    fn config(dir: &Path) -> Config {
+        Config {
+            model_paths: HashMap::from([("c".to_string(), dir.to_string_lossy().to_string())]),
+            ..Default::default()
+        }
+    }
+
+    /// Item 1 of `model_type` at `a.safetensors` with note, trigger words and weight
+    async fn item(model_type: &str) -> SqlitePool {
+        let pool = test_pool().await;
+        sqlx::query(
+            r#"INSERT INTO item (id, path, base_label, note, trained_words, preferred_weight, model_type)
+            VALUES (1, 'a.safetensors', 'c', 'my note', ?, 0.8, ?)"#,
+        )
+        .bind("blue hair\nred eyes")
+        .bind(model_type)
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    /// LoRA item 1 and its `.json`
+    async fn lora(dir: &Path, info: Value) -> SqlitePool {
+        let pool = item("LORA").await;
+        std::fs::write(dir.join("a.json"), info.to_string()).unwrap();
+        pool
+    }
+
+    fn read_info(dir: &Path) -> Value {
+        serde_json::from_str(&std::fs::read_to_string(dir.join("a.json")).unwrap()).unwrap()
+    }
+
+    async fn write_item(dir: &Path, pool: &SqlitePool) -> bool {
+        write(&config(dir), &mut pool.acquire().await.unwrap(), 1)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn keep_metadata_fills_missing_user_fields() {
+        let from = json!({
+            "activation text": "old words",
+            "notes": "old notes",
+            "description": "",
+            WRITTEN_KEY: {"activation text": "old words"},
+        });
+        let mut to = json!({"activation text": "", "description": "civitai", "id": 1});
+        keep_metadata(&from, &mut to);
+        assert_eq!(to["activation text"], "old words");
+        assert_eq!(to["notes"], "old notes");
+        assert_eq!(to["description"], "civitai");
+        assert_eq!(to[WRITTEN_KEY]["activation text"], "old words");
+        assert_eq!(to["id"], 1);
+    }
+
+    #[test]
+    fn civitai_description_skips_written_note() {
+        assert_eq!(civitai_description(&json!({"description": "civitai"})), "civitai");
+        let info = json!({"description": "note", WRITTEN_KEY: {"description": "note"}});
+        assert_eq!(civitai_description(&info), "");
+    }
+
+    #[tokio::test]
+    async fn write_fills_user_metadata_once() {
+        let dir = TestDir::new("a1111-write");
+        let pool = lora(&dir, json!({"model": {"type": "LORA"}, "description": ""})).await;
+        assert!(write_item(&dir, &pool).await);
+        let info = read_info(&dir);
+        assert_eq!(info["activation text"], "blue hair, red eyes");
+        assert_eq!(info["preferred weight"], 0.8);
+        assert_eq!(info["description"], "my note");
+        assert_eq!(info[WRITTEN_KEY]["description"], "my note");
+        assert!(!write_item(&dir, &pool).await);
+    }
+
+    #[tokio::test]
+    async fn write_keeps_ui_changes_and_civitai_description() {
+        let dir = TestDir::new("a1111-keep");
+        let info = json!({
+            "model": {"type": "LORA"},
+            "description": "from civitai",
+            "activation text": "edited in ui",
+            WRITTEN_KEY: {"activation text": "blue hair"},
+        });
+        let pool = lora(&dir, info).await;
+        assert!(write_item(&dir, &pool).await);
+        let info = read_info(&dir);
+        assert_eq!(info["activation text"], "edited in ui");
+        assert_eq!(info["description"], "from civitai");
+        assert_eq!(info["preferred weight"], 0.8);
+    }
+
+    #[tokio::test]
+    async fn write_skips_other_model_types() {
+        let dir = TestDir::new("a1111-checkpoint");
+        let pool = item("Checkpoint").await;
+        std::fs::write(dir.join("a.json"), json!({"model": {"type": "Checkpoint"}}).to_string()).unwrap();
+        assert!(!write_item(&dir, &pool).await);
+        assert!(read_info(&dir)["activation text"].is_null());
+    }
+
+    #[tokio::test]
+    async fn write_creates_json_of_local_lora() {
+        let local = TestDir::new("a1111-local");
+        let dir = local.join("Lora");
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = item("").await;
+        assert!(write_item(&dir, &pool).await);
+        let info = read_info(&dir);
+        assert_eq!(info["activation text"], "blue hair, red eyes");
+        assert!(info["model"].is_null());
+    }
+
+    #[tokio::test]
+    async fn import_only_fills_empty_fields() {
+        let pool = test_pool().await;
+        sqlx::query("INSERT INTO item (id, path, base_label, note) VALUES (1, 'a', 'c', ''), (2, 'b', 'c', 'mine')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let info = json!({"notes": " from ui ", "preferred weight": 0.6});
+        let mut conn = pool.acquire().await.unwrap();
+        import(&mut conn, 1, &info).await.unwrap();
+        import(&mut conn, 2, &info).await.unwrap();
+
+        let items: Vec<(String, Option<f64>)> = sqlx::query_as("SELECT note, preferred_weight FROM item ORDER BY id")
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            items,
+            vec![("from ui".to_string(), Some(0.6)), ("mine".to_string(), Some(0.6))]
+        );
+    }
+
+    #[test]
+    fn prompt_by_model_type() {
+        assert_eq!(prompt("LORA", "a", Some(0.8), "x\ny"), "<lora:a:0.8> x, y");
+        assert_eq!(prompt("TextualInversion", "emb", None, ""), "emb");
+        assert_eq!(prompt("Checkpoint", "model", None, "x"), "x");
+    }
+}
@@ -5,20 +5,41 @@ mod item;
 mod maintenance;
 mod tag;
 mod job;
+mod saved_search;
+mod collection;
+mod history;
+mod catalog;
+mod model_group;
+mod creator;
+mod license;
+mod dedupe;
+mod stats;
 
-use crate::civitai::{calculate_blake3, CivitaiFileMetadata, PREVIEW_EXT};
-use crate::db::item::insert_or_update;
-use crate::db::tag::add_tag_from_model_info;
+use crate::civitai::{
+    calculate_blake3, find_preview, get_extension_from_url, strip_html, CivitaiFileMetadata, PREVIEW_EXT,
+};
+use crate::db::item::{
+    get_tensor_hashed_at, insert_or_update, update_civitai_stats, update_file_info, update_model_fields,
+    update_search_text, update_tensor_hash, ModelFields,
+};
+use crate::db::query::{DuplicateBy, GroupBy, SortBy, SortOrder};
+use crate::db::tag::{add_tag_from_model_info, remove_untyped_tag_item};
 use crate::db::DBPool;
 use actix_web::web;
+use actix_web_httpauth::extractors::basic::BasicAuth;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sqlx::SqliteConnection;
 use std::path::{Path, PathBuf};
 use std::time::UNIX_EPOCH;
 use tokio::fs;
 use tracing::error;
 use crate::BASE_PATH_PREFIX;
-use crate::config::Config;
+use crate::config::{Config, SidecarPolicy};
+use crate::{a1111, importer, safetensors, sidecar};
+
+pub use creator::watch_followed_creators;
+pub use stats::record_daily_stats;
 
 pub const TRASH_DIR: &str = ".trash";
 
@@ -28,6 +49,15 @@ pub fn scope_config(cfg: &mut web::ServiceConfig) {
             .configure(maintenance::scope)
             .configure(item::scope)
             .configure(tag::scope)
+            .configure(saved_search::scope)
+            .configure(collection::scope)
+            .configure(history::scope)
+            .configure(catalog::scope)
+            .configure(model_group::scope)
+            .configure(creator::scope)
+            .configure(license::scope)
+            .configure(dedupe::scope)
+            .configure(stats::scope)
             .configure(job::scope)
             .configure(config::scope),
     );
@@ -44,6 +74,60 @@ pub struct SearchQuery {
     pub(crate) search: String,
     tag_only: Option<bool>,
     duplicate_only: Option<bool>,
+    /// `file` (default) or `weights`
+    duplicate_by: Option<DuplicateBy>,
+    /// Only show the newest installed version of each Civitai model
+    collapse_versions: Option<bool>,
+    #[serde(default)]
+    sort: SortBy,
+    order: Option<SortOrder>,
+    /// Cursor from previous page. If set, `page` is ignored, except when sorting by relevance.
+    cursor: Option<String>,
+    /// Id of saved search. Its query is used instead of `search`.
+    saved: Option<i64>,
+    /// Id of collection. Return its items in order.
+    collection_id: Option<i64>,
+    /// Civitai model type, e.g. `LORA`
+    model_type: Option<String>,
+    /// Base model, e.g. `SDXL 1.0`. Also matches base models starting with it.
+    base_model: Option<String>,
+    creator: Option<String>,
+    civitai_model_id: Option<i64>,
+    /// File format, precision or size type, e.g. `SafeTensor`, `fp16`, `pruned`
+    format: Option<String>,
+    nsfw: Option<bool>,
+    /// Count matching items by this field
+    group_by: Option<GroupBy>,
+}
+
+impl SearchQuery {
+    /// Field filters as search query terms, to be AND-ed with `search`
+    fn field_terms(&self) -> anyhow::Result<String> {
+        let mut terms = Vec::new();
+        for (field, value) in [
+            ("type", &self.model_type),
+            ("base", &self.base_model),
+            ("creator", &self.creator),
+            ("format", &self.format),
+        ] {
+            if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+                // A quote would end the quoted value and add more terms
+                if value.contains('"') {
+                    return Err(anyhow::anyhow!("Filter `{field}` must not contain `\"`"));
+                }
+                terms.push(format!(r#"{field}:"{value}""#));
+            }
+        }
+        if let Some(model_id) = self.civitai_model_id {
+            terms.push(format!("model:{model_id}"));
+        }
+        match self.nsfw {
+            Some(true) => terms.push("has:nsfw".to_string()),
+            Some(false) => terms.push("-has:nsfw".to_string()),
+            None => {}
+        }
+        Ok(terms.join(" "))
+    }
 }
 
 #[derive(Deserialize)]
@@ -74,13 +158,104 @@ impl CommonResponse {
     }
 }
 
+/// Name of basic auth user, empty if basic auth is disabled
+fn auth_user(auth: &Option<BasicAuth>) -> String {
+    auth.as_ref().map(|a| a.user_id().to_string()).unwrap_or_default()
+}
+
 fn get_relative_path(base_path: &str, path: &Path) -> Result<String, anyhow::Error> {
     let base = PathBuf::from(base_path);
     let path = path.strip_prefix(&base)?;
     Ok(path.to_str().unwrap_or_default().to_string())
 }
 
-async fn save_model_info(db_pool: &DBPool, path: &Path, label: &str, relative_path: &str) {
+async fn save_model_info(
+    db_pool: &DBPool,
+    path: &Path,
+    label: &str,
+    relative_path: &str,
+    sidecar_policy: SidecarPolicy,
+) {
+    let res = async {
+        let info = read_model_info(path, false).await?;
+        let mut conn = db_pool.sqlite_pool.acquire().await?;
+        save_model_info_with(&mut conn, &info, label, relative_path, sidecar_policy, false).await?;
+        Ok::<_, anyhow::Error>(conn)
+    }
+    .await;
+    match res {
+        Ok(mut conn) => {
+            if let Err(e) = hash_tensors(&mut conn, path, label, relative_path).await {
+                error!("Failed to hash tensors of {}: {}", path.display(), e);
+            }
+        }
+        Err(e) => error!("Failed to save model info of {}: {}", path.display(), e),
+    }
+}
+
+/// Hash tensor data of `.safetensors` if it is not hashed yet or the file is modified after it was hashed.
+/// Not done by [`save_model_info_with`], so a transaction is not kept open while the file is read.
+async fn hash_tensors(
+    conn: &mut SqliteConnection,
+    path: &Path,
+    label: &str,
+    relative_path: &str,
+) -> anyhow::Result<()> {
+    if path.extension().is_none_or(|ext| ext != "safetensors") {
+        return Ok(());
+    }
+    let Some((id, hashed_at)) = get_tensor_hashed_at(conn, label, relative_path).await? else {
+        return Ok(());
+    };
+    let modified_at = fs::metadata(path)
+        .await?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_millis() as i64;
+    if hashed_at == Some(modified_at) {
+        return Ok(());
+    }
+
+    let file = path.to_path_buf();
+    let tensor_hash = tokio::task::spawn_blocking(move || safetensors::tensor_hash(&file)).await??;
+    update_tensor_hash(conn, id, &tensor_hash, modified_at).await?;
+    Ok(())
+}
+
+/// Model info read from the model file and the files next to it, see [`read_model_info`]
+struct LocalModelInfo {
+    name: String,
+    item_info: Value,
+    model_info: Value,
+    blake3: String,
+    sha256: String,
+    file_metadata: CivitaiFileMetadata,
+    file_size: i64,
+    modified_time: i64,
+    has_preview: bool,
+    trained_words: String,
+    sidecar: Option<sidecar::SidecarFile>,
+}
+
+/// Log `res` and use the default value, unless `strict` is set. A scan must not skip the item because of a side file,
+/// or the item would be removed as obsolete with its user data.
+fn or_log<T: Default>(res: anyhow::Result<T>, strict: bool, msg: &str, path: &Path) -> anyhow::Result<T> {
+    match res {
+        Ok(value) => Ok(value),
+        Err(e) if strict => Err(e),
+        Err(e) => {
+            error!("{} {}: {}", msg, path.display(), e);
+            Ok(T::default())
+        }
+    }
+}
+
+/// Import info of other tools and read model info from files. Done before [`save_model_info_with`], so a transaction
+/// is not kept open while the files are read or the model is hashed.
+/// Failures of other tools' files, the sidecar and hashing are only logged unless `strict` is set.
+async fn read_model_info(path: &Path, strict: bool) -> anyhow::Result<LocalModelInfo> {
+    or_log(importer::import(path).await, strict, "Failed to import info of", path)?;
+
     let mut item_json_file = PathBuf::from(path);
     item_json_file.set_extension("json");
     let mut model_json_file = PathBuf::from(path);
@@ -89,24 +264,33 @@ async fn save_model_info(db_pool: &DBPool, path: &Path, label: &str, relative_pa
     let model_info = fs::read_to_string(&model_json_file).await.unwrap_or_default();
 
     let item_parsed: Value = serde_json::from_str(&item_info).unwrap_or_default();
-    let model_parsed: Value = serde_json::from_str(&model_info).unwrap_or_default();
-
-    let base_model = item_parsed["baseModel"].as_str().unwrap_or_default();
+    let mut model_parsed: Value = serde_json::from_str(&model_info).unwrap_or_default();
+    if model_parsed.is_null() {
+        // Info imported from other tools only has a part of model info
+        model_parsed = item_parsed["model"].clone();
+    }
 
     let mut blake3 = item_parsed["files"][0]["hashes"]["BLAKE3"]
         .as_str()
         .unwrap_or_default()
         .to_string()
         .to_lowercase();
+    let mut sha256 = item_parsed["files"][0]["hashes"]["SHA256"]
+        .as_str()
+        .unwrap_or_default()
+        .to_lowercase();
     let mut file_metadata =
         serde_json::from_value::<CivitaiFileMetadata>(item_parsed["files"][0]["metadata"].clone()).unwrap_or_default();
     if let Some(files) = item_parsed["files"].as_array() {
         // If there are more than 1 file, find the metadata by hash
         if files.len() > 1 {
-            blake3 = calculate_blake3(path).unwrap_or_default().to_lowercase();
+            let file = path.to_path_buf();
+            let hash = async { Ok(tokio::task::spawn_blocking(move || calculate_blake3(&file)).await??) }.await;
+            blake3 = or_log(hash, strict, "Failed to hash", path)?.to_lowercase();
             for file in files.iter() {
                 let hash = file["hashes"]["BLAKE3"].as_str().unwrap_or_default().to_lowercase();
                 if blake3 == hash {
+                    sha256 = file["hashes"]["SHA256"].as_str().unwrap_or_default().to_lowercase();
                     file_metadata =
                         serde_json::from_value::<CivitaiFileMetadata>(file["metadata"].clone()).unwrap_or_default();
                 }
@@ -122,32 +306,207 @@ async fn save_model_info(db_pool: &DBPool, path: &Path, label: &str, relative_pa
 
     // Read file metadata on disk
     let mut modified_time = 0;
+    let mut file_size = 0;
     if let Ok(local_metadata) = fs::metadata(path).await {
+        file_size = local_metadata.len() as i64;
         if let Ok(modified) = local_metadata.modified() {
-            modified_time = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            modified_time = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
         }
     }
 
-    match insert_or_update(
-        &db_pool.sqlite_pool,
-        Some(name.as_str()),
+    let mut has_preview = find_preview(path).is_some();
+    if let Some(ext) = item_parsed["images"][0]["url"]
+        .as_str()
+        .and_then(get_extension_from_url)
+    {
+        has_preview |= path.with_extension(ext).exists();
+    }
+
+    Ok(LocalModelInfo {
+        name,
+        trained_words: trigger_words(path, &item_parsed).await.join("\n"),
+        sidecar: or_log(sidecar::load(path).await, strict, "Failed to read sidecar of", path)?,
+        item_info: item_parsed,
+        model_info: model_parsed,
+        blake3,
+        sha256,
+        file_metadata,
+        file_size,
+        modified_time,
+        has_preview,
+    })
+}
+
+/// Save model info read by [`read_model_info`] using `conn`, e.g. in a transaction.
+/// Failures of A1111 metadata and the sidecar are only logged unless `strict` is set.
+async fn save_model_info_with(
+    conn: &mut SqliteConnection,
+    info: &LocalModelInfo,
+    label: &str,
+    relative_path: &str,
+    sidecar_policy: SidecarPolicy,
+    strict: bool,
+) -> anyhow::Result<()> {
+    let (item_parsed, model_parsed) = (&info.item_info, &info.model_info);
+    let id = insert_or_update(
+        &mut *conn,
+        Some(info.name.as_str()),
         relative_path,
         label,
-        blake3.as_str(),
-        modified_time as i64,
+        info.blake3.as_str(),
+        info.modified_time,
     )
-    .await
-    {
-        Ok(id) => {
-            let tags = vec![base_model.to_string()];
-            if let Err(e) =
-                add_tag_from_model_info(&db_pool.sqlite_pool, id, &tags, &model_parsed, &file_metadata).await
-            {
-                error!("Failed to insert tag: {}", e);
-            }
+    .await?;
+    update_file_info(conn, id, info.file_size, info.has_preview, &info.sha256).await?;
+
+    let downloads = item_parsed["stats"]["downloadCount"].as_i64().unwrap_or_default();
+    let rating = item_parsed["stats"]["rating"].as_f64().unwrap_or_default();
+    update_civitai_stats(conn, id, downloads, rating).await?;
+
+    let model_name = model_parsed["name"].as_str().unwrap_or_default();
+    let version_name = item_parsed["name"].as_str().unwrap_or_default();
+    let description = format!(
+        "{} {}",
+        strip_html(model_parsed["description"].as_str().unwrap_or_default()),
+        strip_html(a1111::civitai_description(item_parsed))
+    );
+    update_search_text(
+        conn,
+        id,
+        model_name,
+        version_name,
+        description.trim(),
+        info.trained_words.as_str(),
+    )
+    .await?;
+
+    let path = Path::new(relative_path);
+    let imported = a1111::import(conn, id, item_parsed).await.map_err(anyhow::Error::from);
+    or_log(imported, strict, "Failed to import A1111 metadata of", path)?;
+    let applied = sidecar::apply(conn, id, info.sidecar.as_ref(), sidecar_policy).await;
+    or_log(applied, strict, "Failed to apply sidecar of", path)?;
+
+    let fields = ModelFields::from_info(item_parsed, model_parsed, &info.file_metadata);
+    update_model_fields(conn, id, &fields).await?;
+    add_tag_from_model_info(conn, id, model_parsed).await?;
+    Ok(())
+}
+
+/// Store trigger words of items scanned by older version one per line, from their `.json`. Only run once.
+pub async fn backfill_trigger_words(config: &Config, db_pool: &DBPool) -> anyhow::Result<()> {
+    let pool = &db_pool.sqlite_pool;
+    let is_backfilled = sqlx::query_scalar!("SELECT value FROM app_info WHERE label = 'trigger_words_backfilled'")
+        .fetch_optional(pool)
+        .await?;
+    if is_backfilled != Some(0) {
+        return Ok(());
+    }
+
+    let items = sqlx::query!(
+        "SELECT id, path, base_label FROM item WHERE trained_words != '' AND trigger_words_edited = false"
+    )
+    .fetch_all(pool)
+    .await?;
+    for item in items {
+        let (model, json, _, _) = get_abs_path(config, &item.base_label, &item.path);
+        // Words of an item without `.json` are kept as they are
+        let Ok(item_info) = fs::read_to_string(&json).await else {
+            continue;
+        };
+        let item_info: Value = serde_json::from_str(&item_info).unwrap_or_default();
+        let words = trigger_words(Path::new(&model), &item_info).await.join("\n");
+        sqlx::query!("UPDATE item SET trained_words = ? WHERE id = ?", words, item.id)
+            .execute(pool)
+            .await?;
+    }
+
+    sqlx::query!("UPDATE app_info SET value = 1 WHERE label = 'trigger_words_backfilled'")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Fill model fields of items scanned by older version from their `.json` and `.model.json`. Only run once.
+pub async fn backfill_model_fields(config: &Config, db_pool: &DBPool) -> anyhow::Result<()> {
+    let pool = &db_pool.sqlite_pool;
+    let is_backfilled = sqlx::query_scalar!("SELECT value FROM app_info WHERE label = 'model_fields_backfilled'")
+        .fetch_optional(pool)
+        .await?;
+    if is_backfilled != Some(0) {
+        return Ok(());
+    }
+
+    let items = sqlx::query!("SELECT id, path, base_label, blake3 FROM item WHERE is_checked = true")
+        .fetch_all(pool)
+        .await?;
+    let mut conn = pool.acquire().await?;
+    for item in items {
+        let (_, json, model_json, _) = get_abs_path(config, &item.base_label, &item.path);
+        let Ok(item_info) = fs::read_to_string(&json).await else {
+            continue;
+        };
+        let item_info: Value = serde_json::from_str(&item_info).unwrap_or_default();
+        let mut model_info: Value = match fs::read_to_string(&model_json).await {
+            Ok(model_info) => serde_json::from_str(&model_info).unwrap_or_default(),
+            Err(_) => Value::Null,
+        };
+        if model_info.is_null() {
+            model_info = item_info["model"].clone();
         }
-        Err(e) => error!("Failed to insert item: {}", e),
+        let files = item_info["files"].as_array().cloned().unwrap_or_default();
+        let file = files
+            .iter()
+            .find(|file| {
+                file["hashes"]["BLAKE3"]
+                    .as_str()
+                    .is_some_and(|hash| hash.eq_ignore_ascii_case(&item.blake3))
+            })
+            .or(files.first());
+        let file_metadata = file
+            .and_then(|file| serde_json::from_value::<CivitaiFileMetadata>(file["metadata"].clone()).ok())
+            .unwrap_or_default();
+
+        let fields = ModelFields::from_info(&item_info, &model_info, &file_metadata);
+        update_model_fields(&mut conn, item.id, &fields).await?;
+        // Older versions added these values as tags without namespace, which are not updated anymore
+        remove_untyped_tag_item(&mut conn, item.id, &fields.legacy_tags()).await?;
     }
+
+    sqlx::query!("UPDATE app_info SET value = 1 WHERE label = 'model_fields_backfilled'")
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Trigger words from Civitai, A1111 activation text or safetensors metadata, in that order
+async fn trigger_words(path: &Path, item_info: &Value) -> Vec<String> {
+    let words = item_info["trainedWords"]
+        .as_array()
+        .map(|words| {
+            words
+                .iter()
+                .filter_map(|w| w.as_str())
+                .map(|w| w.trim().to_string())
+                .filter(|w| !w.is_empty())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+    if !words.is_empty() {
+        return words;
+    }
+
+    let words = safetensors::split_words(item_info["activation text"].as_str().unwrap_or_default());
+    if !words.is_empty() || path.extension().unwrap_or_default() != "safetensors" {
+        return words;
+    }
+
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || safetensors::read_metadata(&path))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .map(|metadata| safetensors::trigger_words(&metadata))
+        .unwrap_or_default()
 }
 
 /// Return abs path of (model, json) and http path of preview
@@ -175,3 +534,68 @@ fn get_abs_path(config: &Config, label: &str, rel_path: &str) -> (String, String
 
     (model, json, model_json, preview)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::item::mark_obsolete_unscanned;
+    use crate::db::{TestDir, test_pool};
+
+    #[tokio::test]
+    async fn scan_keeps_item_with_malformed_sidecar() {
+        let pool = test_pool().await;
+        sqlx::query("INSERT INTO item (id, path, base_label, note, scanned_at) VALUES (1, 'a.ckpt', 'c', 'mine', 0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let dir = TestDir::new("api-scan");
+        let model = dir.join("a.ckpt");
+        std::fs::write(&model, "model").unwrap();
+        std::fs::write(sidecar::sidecar_path(&model), "{").unwrap();
+
+        let db_pool = DBPool { sqlite_pool: pool };
+        save_model_info(&db_pool, &model, "c", "a.ckpt", SidecarPolicy::Merge).await;
+        // Items not saved by the scan started at 1 are obsolete
+        mark_obsolete_unscanned(&db_pool.sqlite_pool, 1).await.unwrap();
+        let (is_checked, note): (bool, String) = sqlx::query_as("SELECT is_checked, note FROM item WHERE id = 1")
+            .fetch_one(&db_pool.sqlite_pool)
+            .await
+            .unwrap();
+        assert!(is_checked);
+        assert_eq!(note, "mine");
+    }
+
+    #[tokio::test]
+    async fn backfill_removes_flat_tags_of_model_info() {
+        let pool = test_pool().await;
+        sqlx::query(
+            r#"INSERT INTO item (id, path, base_label) VALUES (1, 'a.safetensors', 'c');
+            INSERT INTO tag (id, name) VALUES (1, 'lora'), (2, 'sdxl_1.0'), (3, 'safetensor'), (4, 'fp16'),
+                (5, 'pruned'), (6, 'nsfw'), (7, 'anime'), (8, 'mine');
+            INSERT INTO tag_item (item, tag) VALUES (1, 1), (1, 2), (1, 3), (1, 4), (1, 5), (1, 6), (1, 7), (1, 8)"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let dir = TestDir::new("api-backfill");
+        let info = serde_json::json!({
+            "baseModel": "SDXL 1.0",
+            "files": [{"metadata": {"format": "SafeTensor", "fp": "fp16", "size": "pruned"}}],
+            "model": {"type": "LORA", "nsfw": true, "tags": ["anime"]},
+        });
+        std::fs::write(dir.join("a.json"), info.to_string()).unwrap();
+        let config = Config {
+            model_paths: std::collections::HashMap::from([("c".to_string(), dir.to_string_lossy().to_string())]),
+            ..Default::default()
+        };
+
+        let db_pool = DBPool { sqlite_pool: pool };
+        backfill_model_fields(&config, &db_pool).await.unwrap();
+        let tags: Vec<String> =
+            sqlx::query_scalar("SELECT tag.name FROM tag_item JOIN tag ON tag.id = tag_item.tag ORDER BY tag.name")
+                .fetch_all(&db_pool.sqlite_pool)
+                .await
+                .unwrap();
+        assert_eq!(tags, vec!["anime", "mine"]);
+    }
+}
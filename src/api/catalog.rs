@@ -0,0 +1,102 @@
+use crate::api::auth_user;
+use crate::db::DBPool;
+use crate::db::catalog::{Catalog, ImportReport};
+use crate::{ConfigData, db, sidecar};
+use actix_web::http::header::{CONTENT_DISPOSITION, ContentType};
+use actix_web::web::Data;
+use actix_web::{HttpResponse, Responder, get, post, web};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_lab::extract::Query;
+use serde::{Deserialize, Serialize};
+
+/// Max size of imported catalog
+const MAX_CATALOG_SIZE: usize = 256 * 1024 * 1024;
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/catalog")
+            .app_data(web::PayloadConfig::new(MAX_CATALOG_SIZE))
+            .service(export)
+            .service(import),
+    );
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// `json` (default) or `ndjson`
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    dry_run: Option<bool>,
+}
+
+#[derive(Serialize, Default)]
+struct ImportResponse {
+    report: Option<ImportReport>,
+    err: Option<String>,
+}
+
+#[get("export")]
+async fn export(db_pool: Data<DBPool>, params: Query<ExportQuery>) -> impl Responder {
+    let is_ndjson = params.format.as_deref() == Some("ndjson");
+    let body = match db::catalog::export(&db_pool.sqlite_pool).await {
+        Ok(catalog) if is_ndjson => catalog.to_ndjson(),
+        Ok(catalog) => serde_json::to_string(&catalog).map_err(anyhow::Error::from),
+        Err(e) => Err(e.into()),
+    };
+
+    match body {
+        Ok(body) => {
+            let file_name = if is_ndjson { "sdmm-catalog.ndjson" } else { "sdmm-catalog.json" };
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .insert_header((CONTENT_DISPOSITION, format!("attachment; filename=\"{file_name}\"")))
+                .body(body)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to export catalog: {e}")),
+    }
+}
+
+/// Import JSON or NDJSON catalog from request body
+#[post("import")]
+async fn import(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    params: Query<ImportQuery>,
+    body: String,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    let catalog = match Catalog::parse(&body) {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            return web::Json(ImportResponse {
+                err: Some(format!("{e}")),
+                ..Default::default()
+            });
+        }
+    };
+
+    let user = auth_user(&auth);
+    match db::catalog::import(&db_pool.sqlite_pool, &user, &catalog, params.dry_run.unwrap_or(false)).await {
+        Ok(report) => {
+            if !report.dry_run {
+                sidecar::write_all_if_enabled(
+                    &*config.config.read().await,
+                    &db_pool.sqlite_pool,
+                    &report.updated_items,
+                )
+                .await;
+            }
+            web::Json(ImportResponse {
+                report: Some(report),
+                err: None,
+            })
+        }
+        Err(e) => web::Json(ImportResponse {
+            err: Some(format!("Failed to import catalog: {e}")),
+            ..Default::default()
+        }),
+    }
+}
@@ -0,0 +1,109 @@
+use crate::api::{CommonResponse, DeleteRequest};
+use crate::db;
+use crate::db::DBPool;
+use crate::db::collection::Collection;
+use actix_web::web::Data;
+use actix_web::{Responder, get, post, web};
+use actix_web_lab::extract::Query;
+use serde::{Deserialize, Serialize};
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/collection")
+            .service(get_all)
+            .service(add)
+            .service(update)
+            .service(delete)
+            .service(set_items)
+            .service(add_items)
+            .service(remove_items),
+    );
+}
+
+#[derive(Serialize, Default)]
+struct CollectionResponse {
+    collections: Vec<Collection>,
+    err: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CollectionItems {
+    /// Collection id
+    id: i64,
+    items: Vec<i64>,
+}
+
+#[get("")]
+async fn get_all(db_pool: Data<DBPool>) -> impl Responder {
+    let res = match db::collection::list(&db_pool.sqlite_pool).await {
+        Ok(collections) => CollectionResponse { collections, err: None },
+        Err(e) => CollectionResponse {
+            err: Some(format!("Failed to list collections: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+#[post("add")]
+async fn add(db_pool: Data<DBPool>, data: web::Json<Collection>) -> impl Responder {
+    if data.title.trim().is_empty() {
+        return web::Json(CommonResponse::from_err("Title must not be empty"));
+    }
+    match db::collection::add(&db_pool.sqlite_pool, &data).await {
+        Ok(id) => web::Json(CommonResponse::from_msg(&id.to_string())),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to add collection: {e}"))),
+    }
+}
+
+#[post("update")]
+async fn update(db_pool: Data<DBPool>, data: web::Json<Collection>) -> impl Responder {
+    if data.title.trim().is_empty() {
+        return web::Json(CommonResponse::from_err("Title must not be empty"));
+    }
+    match db::collection::update(&db_pool.sqlite_pool, &data).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to update collection: {e}"))),
+    }
+}
+
+#[get("delete")]
+async fn delete(db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> impl Responder {
+    let mut err_str = String::new();
+    for id in params.ids.iter() {
+        if let Err(e) = db::collection::delete(&db_pool.sqlite_pool, *id).await {
+            err_str.push_str(&format!("{e}\n"));
+        }
+    }
+
+    let err = if err_str.is_empty() { None } else { Some(err_str) };
+    web::Json(CommonResponse {
+        err,
+        ..Default::default()
+    })
+}
+
+/// Replace items of collection. Order of `items` is kept.
+#[post("items")]
+async fn set_items(db_pool: Data<DBPool>, data: web::Json<CollectionItems>) -> impl Responder {
+    match db::collection::set_items(&db_pool.sqlite_pool, data.id, &data.items).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to update collection: {e}"))),
+    }
+}
+
+#[post("add_items")]
+async fn add_items(db_pool: Data<DBPool>, data: web::Json<CollectionItems>) -> impl Responder {
+    match db::collection::add_items(&db_pool.sqlite_pool, data.id, &data.items).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to add items: {e}"))),
+    }
+}
+
+#[post("remove_items")]
+async fn remove_items(db_pool: Data<DBPool>, data: web::Json<CollectionItems>) -> impl Responder {
+    match db::collection::remove_items(&db_pool.sqlite_pool, data.id, &data.items).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to remove items: {e}"))),
+    }
+}
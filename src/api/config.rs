@@ -1,9 +1,13 @@
 use crate::ConfigData;
-use crate::api::CommonResponse;
+use crate::api::{CommonResponse, auth_user};
 use crate::civitai::CivitaiEnums;
 use crate::config::Config;
+use crate::db;
+use crate::db::DBPool;
+use crate::db::history::Action;
 use actix_web::web::Data;
 use actix_web::{Responder, get, post, web};
+use actix_web_httpauth::extractors::basic::BasicAuth;
 use reqwest::Client;
 use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use tracing::error;
@@ -40,9 +44,35 @@ async fn get(config_data: Data<ConfigData>) -> impl Responder {
 }
 
 #[post("update")]
-async fn update(config_data: Data<ConfigData>, data: web::Json<Config>) -> impl Responder {
+async fn update(
+    config_data: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<Config>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
     let mut config = config_data.config.write().await;
+    let old_value = redacted_json(&config);
     *config = data.into_inner();
+    let new_value = redacted_json(&config);
+    if old_value != new_value {
+        let res = match db_pool.sqlite_pool.acquire().await {
+            Ok(mut conn) => {
+                db::history::add(
+                    &mut conn,
+                    &auth_user(&auth),
+                    Action::Config,
+                    None,
+                    &old_value,
+                    &new_value,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            error!("Failed to record config update: {}", e);
+        }
+    }
     if let Err(e) = config.save(&config_data.config_path, true) {
         web::Json(CommonResponse {
             err: Some(format!("Failed to save config: {e}")),
@@ -55,3 +85,15 @@ async fn update(config_data: Data<ConfigData>, data: web::Json<Config>) -> impl
         })
     }
 }
+
+/// Config as JSON without secrets, used for history. Keys are sorted so it can be compared.
+fn redacted_json(config: &Config) -> String {
+    let mut config = config.clone();
+    if !config.civitai.api_key.is_empty() {
+        config.civitai.api_key = "***".to_string();
+    }
+    if !config.api.basic_auth_pass.is_empty() {
+        config.api.basic_auth_pass = "***".to_string();
+    }
+    serde_json::to_value(&config).unwrap_or_default().to_string()
+}
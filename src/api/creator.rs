@@ -0,0 +1,179 @@
+use crate::ConfigData;
+use crate::api::CommonResponse;
+use crate::civitai::get_creator_models;
+use crate::config::Config;
+use crate::db;
+use crate::db::DBPool;
+use crate::db::creator::{CreatorCount, FollowedCreator};
+use crate::db::job::{JobState, add_job, update_job};
+use crate::ui::Broadcaster;
+use actix_web::web::Data;
+use actix_web::{Responder, get, post, rt, web};
+use reqwest::Client;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/creator")
+            .service(get_all)
+            .service(followed)
+            .service(follow)
+            .service(unfollow)
+            .service(check),
+    );
+}
+
+#[derive(Serialize, Default)]
+struct CreatorResponse {
+    creators: Vec<CreatorCount>,
+    err: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct FollowedResponse {
+    creators: Vec<FollowedCreator>,
+    err: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FollowRequest {
+    /// Civitai username
+    username: String,
+}
+
+#[get("")]
+async fn get_all(db_pool: Data<DBPool>) -> impl Responder {
+    let res = match db::creator::list(&db_pool.sqlite_pool).await {
+        Ok(creators) => CreatorResponse { creators, err: None },
+        Err(e) => CreatorResponse {
+            err: Some(format!("Failed to list creators: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+#[get("followed")]
+async fn followed(db_pool: Data<DBPool>) -> impl Responder {
+    let res = match db::creator::list_followed(&db_pool.sqlite_pool).await {
+        Ok(creators) => FollowedResponse { creators, err: None },
+        Err(e) => FollowedResponse {
+            err: Some(format!("Failed to list followed creators: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+#[post("follow")]
+async fn follow(db_pool: Data<DBPool>, data: web::Json<FollowRequest>) -> impl Responder {
+    let username = data.username.trim();
+    if username.is_empty() {
+        return web::Json(CommonResponse::from_err("Username must not be empty"));
+    }
+    match db::creator::follow(&db_pool.sqlite_pool, username).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to follow creator: {e}"))),
+    }
+}
+
+#[post("unfollow")]
+async fn unfollow(db_pool: Data<DBPool>, data: web::Json<FollowRequest>) -> impl Responder {
+    match db::creator::unfollow(&db_pool.sqlite_pool, data.username.trim()).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to unfollow creator: {e}"))),
+    }
+}
+
+/// Check new models of followed creators now
+#[get("check")]
+async fn check(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: Data<Broadcaster>) -> impl Responder {
+    let config = config.config.read().await.clone();
+    rt::spawn(async move {
+        check_followed(&config, &db_pool, &broadcaster).await;
+    });
+    web::Json(CommonResponse::from_msg("Checking in background"))
+}
+
+/// Check new models of followed creators every `check_followed_hours` of config
+pub async fn watch_followed_creators(config: Config, db_pool: Arc<DBPool>, broadcaster: Arc<Broadcaster>) {
+    if config.civitai.check_followed_hours == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(config.civitai.check_followed_hours * 60 * 60));
+    loop {
+        interval.tick().await;
+        check_followed(&config, &db_pool, &broadcaster).await;
+    }
+}
+
+/// Announce models of followed creators which are newer than the last check, as a job.
+/// Models found by the first check of a creator are not announced.
+async fn check_followed(config: &Config, db_pool: &DBPool, broadcaster: &Broadcaster) {
+    let pool = &db_pool.sqlite_pool;
+    let creators = match db::creator::list_followed(pool).await {
+        Ok(creators) if creators.is_empty() => return,
+        Ok(creators) => creators,
+        Err(e) => {
+            error!("Failed to list followed creators: {}", e);
+            return;
+        }
+    };
+
+    let id = add_job(pool, "Check followed creators", "").await;
+    let client = Client::new();
+    let mut headers = HeaderMap::new();
+    if let Ok(bearer) = HeaderValue::from_str(&format!("Bearer {}", config.civitai.api_key)) {
+        headers.insert(AUTHORIZATION, bearer);
+    }
+
+    let mut new_count = 0;
+    let mut errors = Vec::new();
+    for creator in creators {
+        let models = match get_creator_models(&client, &headers, &creator.username).await {
+            Ok(models) => models,
+            Err(e) => {
+                errors.push(format!("{}: {}", creator.username, e));
+                continue;
+            }
+        };
+        // Never move back, or a deleted newest model would announce older ones again
+        let newest = models
+            .iter()
+            .filter_map(|model| model["id"].as_i64())
+            .max()
+            .max(creator.newest_model_id);
+        if let Some(last_newest) = creator.newest_model_id {
+            for model in models.iter() {
+                if model["id"].as_i64().is_some_and(|id| id > last_newest) {
+                    new_count += 1;
+                    broadcaster
+                        .info(&format!(
+                            "New {} from {}: {} https://civitai.com/models/{}",
+                            model["type"].as_str().unwrap_or("model"),
+                            creator.username,
+                            model["name"].as_str().unwrap_or_default(),
+                            model["id"]
+                        ))
+                        .await;
+                }
+            }
+        }
+        if let Err(e) = db::creator::set_checked(pool, &creator.username, newest).await {
+            errors.push(format!("{}: {}", creator.username, e));
+        }
+    }
+
+    if let Ok(id) = id {
+        let (desc, state) = if errors.is_empty() {
+            (format!("Found {new_count} new models"), JobState::Succeed)
+        } else {
+            (errors.join("\n"), JobState::Failed)
+        };
+        let _ = update_job(pool, id, &desc, state).await;
+    }
+}
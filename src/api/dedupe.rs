@@ -0,0 +1,280 @@
+use crate::ConfigData;
+use crate::api::{CommonResponse, get_abs_path};
+use crate::civitai::calculate_blake3;
+use crate::config::Config;
+use crate::db;
+use crate::db::DBPool;
+use crate::db::item_link::{DuplicateGroup, ItemLink};
+use crate::db::job::{JobState, add_job, update_job};
+use crate::dedupe::{self, LinkType};
+use crate::ui::Broadcaster;
+use actix_web::web::Data;
+use actix_web::{Responder, get, post, rt, web};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/dedupe").service(groups).service(links).service(run));
+}
+
+#[derive(Serialize, Default)]
+struct GroupsResponse {
+    groups: Vec<DuplicateGroup>,
+    err: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct LinksResponse {
+    links: Vec<ItemLink>,
+    err: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DedupeRequest {
+    /// BLAKE3 of groups to deduplicate. All groups if empty.
+    #[serde(default)]
+    blake3: Vec<String>,
+    /// Item whose file is kept. Only its group is deduplicated.
+    keep: Option<i64>,
+    /// Only verify hashes and report what would be reclaimed
+    #[serde(default)]
+    dry_run: bool,
+}
+
+struct LinkResult {
+    id: i64,
+    /// `None` if nothing is changed
+    link_type: Option<LinkType>,
+    /// File was already the same file as the canonical one
+    already_linked: bool,
+    err: Option<String>,
+}
+
+struct GroupResult {
+    blake3: String,
+    links: Vec<LinkResult>,
+    reclaimed_bytes: i64,
+    err: Option<String>,
+}
+
+/// Groups of items with the same file
+#[get("")]
+async fn groups(db_pool: Data<DBPool>) -> impl Responder {
+    let res = match db::item_link::list_groups(&db_pool.sqlite_pool).await {
+        Ok(list) => GroupsResponse {
+            groups: list,
+            err: None,
+        },
+        Err(e) => GroupsResponse {
+            err: Some(format!("Failed to list duplicates: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+/// Items whose file is replaced by a link
+#[get("links")]
+async fn links(db_pool: Data<DBPool>) -> impl Responder {
+    let res = match db::item_link::list(&db_pool.sqlite_pool).await {
+        Ok(list) => LinksResponse { links: list, err: None },
+        Err(e) => LinksResponse {
+            err: Some(format!("Failed to list links: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+/// Keep one file of each group and replace the others by links to it, in background
+#[post("")]
+async fn run(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    data: web::Json<DedupeRequest>,
+) -> impl Responder {
+    if data.blake3.is_empty() && data.keep.is_some() {
+        return web::Json(CommonResponse::from_err("`keep` needs the `blake3` of its group"));
+    }
+    let config = config.config.read().await.clone();
+    let data = data.into_inner();
+    rt::spawn(async move {
+        broadcaster.warn("Deduplicating files...").await;
+        dedupe(&config, &db_pool, &broadcaster, data).await;
+    });
+    web::Json(CommonResponse::from_msg("Deduplicating in background"))
+}
+
+/// Deduplicate the groups of `data` as a job. Files which failed are listed in the job description.
+async fn dedupe(config: &Config, db_pool: &DBPool, broadcaster: &Broadcaster, data: DedupeRequest) {
+    let pool = &db_pool.sqlite_pool;
+    let title = if data.dry_run { "Dedupe (dry run)" } else { "Dedupe" };
+    let id = add_job(pool, title, "").await;
+    let hashes = if !data.blake3.is_empty() {
+        data.blake3
+    } else {
+        match db::item_link::list_groups(pool).await {
+            Ok(list) => list.into_iter().map(|group| group.blake3).collect(),
+            Err(e) => {
+                let msg = format!("Failed to list duplicates: {e}");
+                if let Ok(id) = id {
+                    let _ = update_job(pool, id, &msg, JobState::Failed).await;
+                }
+                broadcaster.error(&msg).await;
+                return;
+            }
+        }
+    };
+
+    let (mut reclaimed_bytes, mut linked_count) = (0, 0);
+    let mut errors = Vec::new();
+    for (i, blake3) in hashes.iter().enumerate() {
+        let group = dedupe_group(config, pool, blake3, data.keep, data.dry_run).await;
+        reclaimed_bytes += group.reclaimed_bytes;
+        if let Some(err) = group.err {
+            errors.push(format!("{}: {}", group.blake3, err));
+        }
+        for link in group.links {
+            if link.link_type.is_some() {
+                linked_count += 1;
+            }
+            if let Some(err) = link.err {
+                errors.push(format!("{}: item {}: {}", group.blake3, link.id, err));
+            }
+        }
+        broadcaster
+            .info(&format!("Deduplicated {}/{} groups", i + 1, hashes.len()))
+            .await;
+    }
+
+    let reclaimed_mb = reclaimed_bytes / 1024 / 1024;
+    let mut desc = if data.dry_run {
+        format!("{reclaimed_mb} MB would be reclaimed, {} failed", errors.len())
+    } else {
+        format!(
+            "{linked_count} files linked, {reclaimed_mb} MB reclaimed, {} failed",
+            errors.len()
+        )
+    };
+    for err in errors.iter() {
+        desc.push_str(&format!("\n{err}"));
+    }
+    if let Ok(id) = id {
+        let _ = update_job(pool, id, &desc, JobState::Succeed).await;
+    }
+    if errors.is_empty() {
+        broadcaster
+            .info(&format!("Finished deduplicating, {reclaimed_mb} MB reclaimed"))
+            .await;
+    } else {
+        broadcaster
+            .error(&format!(
+                "Failed to deduplicate {} groups or files, see the job",
+                errors.len()
+            ))
+            .await;
+    }
+}
+
+async fn dedupe_group(
+    config: &Config,
+    pool: &SqlitePool,
+    blake3: &str,
+    keep: Option<i64>,
+    dry_run: bool,
+) -> GroupResult {
+    let mut res = GroupResult {
+        blake3: blake3.to_string(),
+        links: Vec::new(),
+        reclaimed_bytes: 0,
+        err: None,
+    };
+    let items = match db::item_link::group_items(pool, blake3).await {
+        Ok(items) if items.len() > 1 => items,
+        Ok(_) => {
+            res.err = Some("No duplicated items".to_string());
+            return res;
+        }
+        Err(e) => {
+            res.err = Some(format!("Failed to list items: {e}"));
+            return res;
+        }
+    };
+    let paths = items
+        .iter()
+        .map(|item| PathBuf::from(get_abs_path(config, &item.base_label, &item.path).0))
+        .collect::<Vec<PathBuf>>();
+
+    // A file which is not a symlink, so removing another item never breaks the links
+    let canonical = match keep {
+        Some(keep) => items.iter().position(|item| item.id == keep),
+        None => paths.iter().position(|path| !path.is_symlink()),
+    };
+    let Some(canonical) = canonical else {
+        res.err = Some("No item to keep".to_string());
+        return res;
+    };
+    let (canonical_id, canonical_path) = (items[canonical].id, &paths[canonical]);
+    match verify(canonical_path, blake3).await {
+        Ok(true) => {}
+        Ok(false) => {
+            res.err = Some(format!("File of item {canonical_id} does not match its hash"));
+            return res;
+        }
+        Err(e) => {
+            res.err = Some(format!("Failed to hash file of item {canonical_id}: {e}"));
+            return res;
+        }
+    }
+
+    for (item, path) in items.iter().zip(paths.iter()) {
+        if item.id == canonical_id {
+            continue;
+        }
+        let mut link = LinkResult {
+            id: item.id,
+            link_type: None,
+            already_linked: false,
+            err: None,
+        };
+        let linked: anyhow::Result<Option<LinkType>> = async {
+            if dedupe::is_same_file(canonical_path, path)? {
+                link.already_linked = true;
+                return Ok(None);
+            }
+            // File may be changed since it was scanned
+            if !verify(path, blake3).await? {
+                return Err(anyhow!("File does not match its hash"));
+            }
+            if dry_run {
+                return Ok(None);
+            }
+            let link_type = dedupe::link(canonical_path, path)?;
+            let mut conn = pool.acquire().await?;
+            db::item_link::add(&mut conn, item.id, canonical_id, link_type.as_str(), item.file_size).await?;
+            Ok(Some(link_type))
+        }
+        .await;
+        match linked {
+            Ok(link_type) => {
+                link.link_type = link_type;
+                if !link.already_linked {
+                    res.reclaimed_bytes += item.file_size;
+                }
+            }
+            Err(e) => link.err = Some(format!("{e}")),
+        }
+        res.links.push(link);
+    }
+    res
+}
+
+/// Hash file again and compare with `blake3`
+async fn verify(path: &Path, blake3: &str) -> anyhow::Result<bool> {
+    let path = path.to_path_buf();
+    let hash = tokio::task::spawn_blocking(move || calculate_blake3(&path)).await??;
+    Ok(hash.eq_ignore_ascii_case(blake3))
+}
@@ -0,0 +1,91 @@
+use crate::api::{CommonResponse, auth_user};
+use crate::db::DBPool;
+use crate::db::history::{History, item_state, record_item_changes};
+use crate::db::tag::{update_item_note, update_tag_item};
+use crate::sidecar;
+use crate::{ConfigData, db};
+use actix_web::web::Data;
+use actix_web::{Responder, get, post, web};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_lab::extract::Query;
+use serde::{Deserialize, Serialize};
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/history").service(get_history).service(revert));
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Item id. List all history if not set.
+    item: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize, Default)]
+struct HistoryResponse {
+    history: Vec<History>,
+    total: i64,
+    err: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RevertRequest {
+    item: i64,
+    /// Tags and note are reverted to the state right after this history entry
+    history: i64,
+}
+
+#[get("")]
+async fn get_history(
+    config_data: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    query_params: Query<HistoryQuery>,
+) -> impl Responder {
+    let config = config_data.config.read().await;
+    let limit = query_params.limit.unwrap_or(config.api.per_page as i64);
+    let offset = query_params.offset.unwrap_or(0);
+    let mut res = HistoryResponse::default();
+    match db::history::list(&db_pool.sqlite_pool, query_params.item, limit, offset).await {
+        Ok((history, total)) => {
+            res.history = history;
+            res.total = total;
+        }
+        Err(e) => {
+            res.err = Some(format!("Failed to get history: {e}"));
+        }
+    }
+    web::Json(res)
+}
+
+#[post("revert")]
+async fn revert(
+    config_data: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<RevertRequest>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    match revert_item(&db_pool, &auth_user(&auth), data.item, data.history).await {
+        Ok(_) => {
+            sidecar::write_if_enabled(&*config_data.config.read().await, &db_pool.sqlite_pool, data.item).await;
+            web::Json(CommonResponse::from_msg("Item reverted"))
+        }
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to revert item: {e}"))),
+    }
+}
+
+async fn revert_item(db_pool: &DBPool, user: &str, item: i64, history: i64) -> anyhow::Result<()> {
+    let (tags, note) = db::history::item_state_at(&db_pool.sqlite_pool, item, history).await?;
+
+    let mut tx = db_pool.sqlite_pool.begin().await?;
+    let before = item_state(&mut tx, item).await?;
+    if let Some(tags) = tags {
+        update_tag_item(&mut tx, item, &tags).await?;
+    }
+    if let Some(note) = note {
+        update_item_note(&mut tx, item, &note).await?;
+    }
+    record_item_changes(&mut tx, user, item, &before).await?;
+    tx.commit().await?;
+    Ok(())
+}
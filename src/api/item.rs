@@ -1,19 +1,32 @@
-use crate::api::{CommonResponse, DeleteRequest, SearchQuery, TRASH_DIR, get_abs_path};
-use crate::civitai::{FileType, PREVIEW_EXT, download_file, file_type, get_extension_from_url, get_item_info};
+use crate::api::{CommonResponse, DeleteRequest, SearchQuery, TRASH_DIR, auth_user, get_abs_path};
+use crate::civitai::{
+    FileType, PREVIEW_IMAGE_EXTS, RemovedUpstream, download_file, file_type, find_preview, get_extension_from_url,
+    get_item_info,
+};
+use crate::config::Config;
 use crate::db::DBPool;
+use crate::db::collection::Collection;
+use crate::db::history::{Action, item_state, record_item_changes};
+use crate::db::item::{GroupCount, Item, SearchOptions};
 use crate::db::job::{JobState, add_job, update_job};
-use crate::db::tag::{TagCount, update_item_note, update_tag_item};
+use crate::db::query::DuplicateBy;
+use crate::db::tag::{TagCount, USER_TAG_TYPE, add_tag_item, remove_tag_item, update_item_note, update_tag_item};
+use crate::disk::{SpaceLimit, quota_collection};
+use crate::sidecar::{self, SIDECAR_EXT};
 use crate::ui::Broadcaster;
-use crate::{ConfigData, api, db};
+use crate::{ConfigData, a1111, api, db, importer};
 use actix_web::web::Data;
 use actix_web::{Responder, get, post, rt, web};
+use actix_web_httpauth::extractors::basic::BasicAuth;
 use actix_web_lab::extract::Query;
 use reqwest::Client;
 use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sqlx::{SqliteConnection, SqlitePool};
 use std::cmp::max;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use tracing::error;
@@ -25,15 +38,26 @@ pub fn scope(cfg: &mut web::ServiceConfig) {
             .service(saved_location)
             .service(civitai_download)
             .service(delete)
-            .service(update),
+            .service(update)
+            .service(batch)
+            .service(favorite)
+            .service(rating)
+            .service(trigger_words)
+            .service(preferred_weight)
+            .service(used),
     );
 }
 
 #[derive(Serialize)]
 struct SearchResponse {
     items: Vec<ModelInfo>,
+    total: i64,
     total_page: i64,
+    next_cursor: Option<String>,
     tags: Vec<TagCount>,
+    collections: Vec<Collection>,
+    /// Number of matching items by value of `group_by`
+    groups: Vec<GroupCount>,
     err: Option<String>,
 }
 
@@ -47,6 +71,38 @@ struct ModelInfo {
     info: String,
     description: String,
     note: String,
+    snippet: Option<String>,
+    favorite: bool,
+    rating: Option<i64>,
+    last_used_at: Option<i64>,
+    use_count: i64,
+    trigger_words: Vec<String>,
+    preferred_weight: Option<f64>,
+    /// Ready to paste prompt, e.g. `<lora:name:0.8> trigger1, trigger2`
+    prompt: String,
+    model_type: String,
+    base_model: String,
+    civitai_model_id: Option<i64>,
+    civitai_version_id: Option<i64>,
+    creator: String,
+    file_size: i64,
+    precision: String,
+    format: String,
+    size_type: String,
+    nsfw: bool,
+    nsfw_level: i64,
+    poi: bool,
+    allow_no_credit: Option<bool>,
+    allow_commercial_use: String,
+    allow_derivatives: Option<bool>,
+    allow_different_license: Option<bool>,
+    /// Set when license changed between syncs
+    license_changed_at: Option<i64>,
+    previous_license: String,
+    /// Set when sync found that the model was removed from Civitai
+    removed_upstream_at: Option<i64>,
+    /// Set when verification found that the file is corrupt
+    corrupt_at: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -56,6 +112,85 @@ struct ItemUpdate {
     note: String,
 }
 
+/// Items selected by `ids`, or by `search` if `ids` is empty
+#[derive(Deserialize)]
+pub(super) struct Selection {
+    #[serde(default)]
+    ids: Vec<i64>,
+    search: Option<String>,
+    tag_only: Option<bool>,
+    duplicate_only: Option<bool>,
+    duplicate_by: Option<DuplicateBy>,
+}
+
+/// Operations applied to a selection of items
+#[derive(Deserialize)]
+struct BatchRequest {
+    #[serde(flatten)]
+    selection: Selection,
+    /// Tags to add, separated by space
+    #[serde(default)]
+    add_tags: String,
+    /// Tags to remove, separated by space
+    #[serde(default)]
+    remove_tags: String,
+    note: Option<String>,
+    /// Directory to move items into. Must be inside a base path.
+    move_to: Option<String>,
+    #[serde(default)]
+    delete: bool,
+    #[serde(default)]
+    resync: bool,
+    /// Write A1111/Forge user metadata of LoRAs and embeddings
+    #[serde(default)]
+    write_a1111: bool,
+}
+
+#[derive(Serialize)]
+struct BatchItemResult {
+    id: i64,
+    err: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct BatchResponse {
+    results: Vec<BatchItemResult>,
+    err: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FavoriteUpdate {
+    id: i64,
+    favorite: bool,
+}
+
+#[derive(Deserialize)]
+struct RatingUpdate {
+    id: i64,
+    /// From 1 to 5. Remove rating if not set.
+    rating: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TriggerWordsUpdate {
+    id: i64,
+    /// Use words from Civitai or model metadata again on next scan if not set
+    words: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct PreferredWeightUpdate {
+    id: i64,
+    /// Remove preferred weight if not set
+    weight: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct UsedQuery {
+    /// BLAKE3, SHA256 or AutoV2 hash of model file
+    hash: String,
+}
+
 #[derive(Deserialize)]
 struct SavedLocationQuery {
     model_type: String,
@@ -75,6 +210,8 @@ struct CivitaiDownloadQuery {
     name: String,
     blake3: String,
     dest: String,
+    /// Size of the file from Civitai model version, to refuse the download before it starts
+    size_kb: Option<f64>,
 }
 
 #[get("")]
@@ -89,6 +226,9 @@ async fn get_items(
     let offset = page * limit;
     let mut ret = Vec::new();
     let mut err = None;
+    let mut next_cursor = None;
+    let mut groups = Vec::new();
+    let field_terms = query_params.field_terms();
 
     let (items, total) = if let Some(item_id) = query_params.id {
         match db::item::get_by_id(&db_pool.sqlite_pool, item_id).await {
@@ -98,20 +238,60 @@ async fn get_items(
                 (Vec::new(), 0)
             }
         }
+    } else if let Some(collection_id) = query_params.collection_id {
+        match db::collection::get_items(&db_pool.sqlite_pool, collection_id, limit, offset).await {
+            Ok(res) => res,
+            Err(e) => {
+                err = Some(format!("{}", e));
+                (Vec::new(), 0)
+            }
+        }
+    } else if let Err(e) = field_terms.as_ref() {
+        err = Some(format!("{}", e));
+        (Vec::new(), 0)
     } else {
-        let tag_only = query_params.tag_only.unwrap_or(false);
-        let duplicate_only = query_params.duplicate_only.unwrap_or(false);
-        match db::item::search(
-            &db_pool.sqlite_pool,
-            &query_params.search,
+        let saved_search = match query_params.saved {
+            Some(id) => match db::saved_search::get(&db_pool.sqlite_pool, id).await {
+                Ok(saved_search) => Some(saved_search),
+                Err(e) => {
+                    err = Some(format!("Failed to get saved search: {e}"));
+                    None
+                }
+            },
+            None => None,
+        };
+        let opts = match saved_search.as_ref() {
+            Some(saved_search) => SearchOptions {
+                search: &saved_search.search,
+                tag_only: saved_search.tag_only,
+                duplicate_only: saved_search.duplicate_only,
+                ..Default::default()
+            },
+            None => SearchOptions {
+                search: &query_params.search,
+                tag_only: query_params.tag_only.unwrap_or(false),
+                duplicate_only: query_params.duplicate_only.unwrap_or(false),
+                duplicate_by: query_params.duplicate_by.unwrap_or_default(),
+                ..Default::default()
+            },
+        };
+        let opts = SearchOptions {
+            filters: field_terms.as_deref().unwrap_or_default(),
+            sort: query_params.sort,
+            order: query_params.order,
             limit,
             offset,
-            tag_only,
-            duplicate_only,
-        )
-        .await
-        {
-            Ok((i, t)) => (i, t),
+            cursor: query_params.cursor.as_deref().filter(|c| !c.is_empty()),
+            group_by: query_params.group_by,
+            collapse_versions: query_params.collapse_versions.unwrap_or(false),
+            ..opts
+        };
+        match db::item::search(&db_pool.sqlite_pool, &opts).await {
+            Ok(res) => {
+                next_cursor = res.next_cursor;
+                groups = res.groups;
+                (res.items, res.total)
+            }
             Err(e) => {
                 err = Some(format!("{}", e));
                 (Vec::new(), 0)
@@ -141,27 +321,68 @@ async fn get_items(
                 }
             }
         }
-        let mut abs_preview = PathBuf::from(&model_url);
-        abs_preview.set_extension(PREVIEW_EXT);
-        if !abs_preview.exists() {
-            preview_url.clear();
+        match find_preview(Path::new(&model_url)) {
+            Some(abs_preview) => {
+                let ext = abs_preview.extension().unwrap_or_default();
+                preview_url = Path::new(&preview_url)
+                    .with_extension(ext)
+                    .to_str()
+                    .unwrap_or_default()
+                    .to_string();
+            }
+            None => preview_url.clear(),
         }
 
         let model_info = fs::read_to_string(&model_json_url).await.unwrap_or_default();
         let model_parsed: Value = serde_json::from_str(model_info.as_str()).unwrap_or_default();
         let description = model_parsed["description"].as_str().unwrap_or_default().to_string();
+        let name = item.name.unwrap_or_default();
+        let stem = Path::new(&name)
+            .file_stem()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default();
+        let prompt = a1111::prompt(&item.model_type, stem, item.preferred_weight, &item.trained_words);
 
         item_ids.insert(item.id);
 
         ret.push(ModelInfo {
             id: item.id,
-            name: item.name.unwrap_or_default(),
+            name,
             path: model_url,
             preview: preview_url,
             video_preview,
             info: item_info,
             description,
             note: item.note.clone(),
+            snippet: item.snippet,
+            favorite: item.favorite,
+            rating: item.rating,
+            last_used_at: item.last_used_at,
+            use_count: item.use_count,
+            trigger_words: item.trained_words.lines().map(|w| w.to_string()).collect(),
+            preferred_weight: item.preferred_weight,
+            prompt,
+            model_type: item.model_type,
+            base_model: item.base_model,
+            civitai_model_id: item.civitai_model_id,
+            civitai_version_id: item.civitai_version_id,
+            creator: item.creator,
+            file_size: item.file_size,
+            precision: item.precision,
+            format: item.format,
+            size_type: item.size_type,
+            nsfw: item.nsfw,
+            nsfw_level: item.nsfw_level,
+            poi: item.poi,
+            allow_no_credit: item.allow_no_credit,
+            allow_commercial_use: item.allow_commercial_use,
+            allow_derivatives: item.allow_derivatives,
+            allow_different_license: item.allow_different_license,
+            license_changed_at: item.license_changed_at,
+            previous_license: item.previous_license,
+            removed_upstream_at: item.removed_upstream_at,
+            corrupt_at: item.corrupt_at,
         })
     }
 
@@ -177,10 +398,19 @@ async fn get_items(
             })
     };
 
+    let collections = db::collection::list(&db_pool.sqlite_pool).await.unwrap_or_else(|e| {
+        error!("Failed to list collections: {e}");
+        Vec::new()
+    });
+
     web::Json(SearchResponse {
         items: ret,
-        total_page: total / limit + 1,
+        total,
+        total_page: max(1, (total + limit - 1) / limit),
+        next_cursor,
         tags,
+        collections,
+        groups,
         err,
     })
 }
@@ -257,6 +487,23 @@ async fn civitai_download(
         });
     }
 
+    let used_bytes = match quota_collection(&config, &dest_dir) {
+        Some(label) => match db::stats::collection_bytes(&db_pool.sqlite_pool, label).await {
+            Ok(used_bytes) => used_bytes as u64,
+            Err(e) => return web::Json(CommonResponse::from_err(&format!("Failed to get size of {label}: {e}"))),
+        },
+        None => 0,
+    };
+    let limit = SpaceLimit::new(&config, &dest_dir, used_bytes);
+    if let Some(size_kb) = params.size_kb
+        && let Err(e) = limit.check((size_kb * 1024.0) as u64)
+    {
+        return web::Json(CommonResponse::from_err(&format!(
+            "Cannot download {}: {e}",
+            params.name
+        )));
+    }
+
     if let Err(e) = fs::create_dir_all(&dest_dir).await {
         return web::Json(CommonResponse {
             err: Some(format!("Failed to create {dest_dir:?}: {e}")),
@@ -297,6 +544,7 @@ async fn civitai_download(
             &config.model_paths,
             blake3_lowercase.as_ref(),
             config.civitai.max_retries,
+            Some(&limit),
         )
         .await
         {
@@ -320,7 +568,7 @@ async fn civitai_download(
         for (label, base_path) in config.model_paths.iter() {
             if path.starts_with(PathBuf::from(base_path)) {
                 let relative_path = api::get_relative_path(base_path, &path).unwrap_or_default();
-                api::save_model_info(&db_pool, &path, label, relative_path.as_str()).await;
+                api::save_model_info(&db_pool, &path, label, relative_path.as_str(), config.sidecar.policy).await;
                 break;
             }
         }
@@ -333,59 +581,631 @@ async fn civitai_download(
 }
 
 #[get("delete")]
-async fn delete(config: Data<ConfigData>, db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> impl Responder {
+async fn delete(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    params: Query<DeleteRequest>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
     let config = config.config.read().await;
+    let user = auth_user(&auth);
     for id in params.ids.iter() {
-        let Ok((rel_path, label)) = db::item::mark_obsolete(&db_pool.sqlite_pool, *id).await else {
-            continue;
-        };
-        let Some(base_path) = config.model_paths.get(&label) else {
-            continue;
-        };
-        let base_path = PathBuf::from(base_path);
-        let model_file = base_path.join(rel_path);
-        let trash_dir = base_path.join(TRASH_DIR);
+        if let Err(e) = trash_item(&config, &db_pool.sqlite_pool, &user, *id).await {
+            error!("Failed to delete item {}: {}", id, e);
+        }
+    }
 
-        if let Err(e) = fs::create_dir_all(&trash_dir).await {
-            error!("Failed to create {:?}: {}", trash_dir, e);
+    web::Json("")
+}
+
+#[post("update")]
+async fn update(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<ItemUpdate>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    let mut tx = match db_pool.sqlite_pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            error!("Failed to update item: {}", e);
             return web::Json("");
         }
+    };
+    let before = match item_state(&mut tx, data.item_id).await {
+        Ok(before) => before,
+        Err(e) => {
+            error!("Failed to update item: {}", e);
+            return web::Json("");
+        }
+    };
+
+    if let Err(e) = update_tag_item(&mut tx, data.item_id, data.tags.as_str()).await {
+        error!("Failed to update tag: {}", e);
+    }
+
+    if let Err(e) = update_item_note(&mut tx, data.item_id, data.note.as_str()).await {
+        error!("Failed to update note: {}", e);
+    }
+
+    if let Err(e) = record_item_changes(&mut tx, &auth_user(&auth), data.item_id, &before).await {
+        error!("Failed to record history: {}", e);
+        return web::Json("");
+    }
+    if let Err(e) = tx.commit().await {
+        error!("Failed to update item: {}", e);
+        return web::Json("");
+    }
+    sidecar::write_if_enabled(&*config.config.read().await, &db_pool.sqlite_pool, data.item_id).await;
+
+    web::Json("")
+}
+
+#[post("favorite")]
+async fn favorite(config: Data<ConfigData>, db_pool: Data<DBPool>, data: web::Json<FavoriteUpdate>) -> impl Responder {
+    match db::item::set_favorite(&db_pool.sqlite_pool, data.id, data.favorite).await {
+        Ok(_) => {
+            sidecar::write_if_enabled(&*config.config.read().await, &db_pool.sqlite_pool, data.id).await;
+            web::Json(CommonResponse::default())
+        }
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to update favorite: {e}"))),
+    }
+}
+
+#[post("rating")]
+async fn rating(config: Data<ConfigData>, db_pool: Data<DBPool>, data: web::Json<RatingUpdate>) -> impl Responder {
+    if let Some(rating) = data.rating
+        && !(1..=5).contains(&rating)
+    {
+        return web::Json(CommonResponse::from_err("Rating must be from 1 to 5"));
+    }
+    match db::item::set_rating(&db_pool.sqlite_pool, data.id, data.rating).await {
+        Ok(_) => {
+            sidecar::write_if_enabled(&*config.config.read().await, &db_pool.sqlite_pool, data.id).await;
+            web::Json(CommonResponse::default())
+        }
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to update rating: {e}"))),
+    }
+}
 
-        if let Ok(files) = list_same_filename(&model_file) {
-            if let Err(e) = move_to_dir(&files, &trash_dir).await {
-                error!("Failed to move file to trash directory: {}", e);
+#[post("trigger_words")]
+async fn trigger_words(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<TriggerWordsUpdate>,
+) -> impl Responder {
+    match db::item::set_trigger_words(&db_pool.sqlite_pool, data.id, data.words.as_deref()).await {
+        Ok(_) => {
+            let config = config.config.read().await;
+            sidecar::write_if_enabled(&config, &db_pool.sqlite_pool, data.id).await;
+            a1111::write_if_enabled(&config, &db_pool.sqlite_pool, data.id).await;
+            web::Json(CommonResponse::default())
+        }
+        Err(e) => web::Json(CommonResponse::from_err(&format!(
+            "Failed to update trigger words: {e}"
+        ))),
+    }
+}
+
+#[post("preferred_weight")]
+async fn preferred_weight(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<PreferredWeightUpdate>,
+) -> impl Responder {
+    match db::item::set_preferred_weight(&db_pool.sqlite_pool, data.id, data.weight).await {
+        Ok(_) => {
+            let config = config.config.read().await;
+            sidecar::write_if_enabled(&config, &db_pool.sqlite_pool, data.id).await;
+            a1111::write_if_enabled(&config, &db_pool.sqlite_pool, data.id).await;
+            web::Json(CommonResponse::default())
+        }
+        Err(e) => web::Json(CommonResponse::from_err(&format!(
+            "Failed to update preferred weight: {e}"
+        ))),
+    }
+}
+
+/// Called by external tools when a model is used, e.g. `POST /api/item/used?hash=<sha256>`
+#[post("used")]
+async fn used(db_pool: Data<DBPool>, params: Query<UsedQuery>) -> impl Responder {
+    let hash = params.hash.trim();
+    // AutoV2 is the shortest accepted hash
+    if hash.len() < 10 {
+        return web::Json(CommonResponse::from_err("Hash must have at least 10 characters"));
+    }
+    match db::item::mark_used(&db_pool.sqlite_pool, hash).await {
+        Ok(0) => web::Json(CommonResponse::from_err("Model not found")),
+        Ok(count) => web::Json(CommonResponse::from_msg(&count.to_string())),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to update usage: {e}"))),
+    }
+}
+
+/// Apply operations to selected items.
+///
+/// All changes are done in one transaction, nothing is changed if any item fails: moved and deleted files are moved
+/// back, and files written by resync and A1111 metadata are restored. Destinations of all moved items are checked
+/// before any file is touched.
+#[post("batch")]
+async fn batch(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<BatchRequest>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    let pool = &db_pool.sqlite_pool;
+    let user = auth_user(&auth);
+    let items = match select_items(pool, &data.selection).await {
+        Ok(items) if items.is_empty() => {
+            return web::Json(BatchResponse {
+                err: Some("No item selected".to_string()),
+                ..Default::default()
+            });
+        }
+        Ok(items) => items,
+        Err(e) => {
+            return web::Json(BatchResponse {
+                err: Some(format!("Failed to select items: {e}")),
+                ..Default::default()
+            });
+        }
+    };
+
+    let add_tags = data
+        .add_tags
+        .split_whitespace()
+        .map(|t| t.to_lowercase())
+        .collect::<Vec<String>>();
+    let remove_tags = data
+        .remove_tags
+        .split_whitespace()
+        .map(|t| t.to_lowercase())
+        .collect::<Vec<String>>();
+    let is_data_changed = !add_tags.is_empty() || !remove_tags.is_empty() || data.note.is_some();
+
+    let config = config.config.read().await;
+    let destinations = match data.move_to.as_ref() {
+        Some(dest) if !data.delete => match plan_moves(&config, &items, dest) {
+            Ok(destinations) => destinations,
+            Err((id, e)) => return web::Json(failed_batch(&items, id, e)),
+        },
+        _ => items.iter().map(|_| None).collect(),
+    };
+
+    let client = Client::new();
+    let mut headers = HeaderMap::new();
+    if data.resync
+        && let Ok(bearer) = HeaderValue::from_str(&format!("Bearer {}", config.civitai.api_key))
+    {
+        headers.insert(AUTHORIZATION, bearer);
+    }
+
+    let mut journal = FileJournal::default();
+    let res: Result<(), (i64, anyhow::Error)> = async {
+        // Civitai is asked and files are read before the transaction starts, so it is not kept open while waiting for
+        // the network or hashing models
+        let is_resync = data.resync && !data.delete;
+        // Whether the model of item is removed from Civitai, if Civitai was asked
+        let mut removed_upstream = HashMap::new();
+        let mut local_infos = HashMap::new();
+        if is_resync {
+            for item in items.iter() {
+                match resync_files(&config, &client, &headers, item, &mut journal).await {
+                    Ok(true) => {
+                        removed_upstream.insert(item.id, false);
+                    }
+                    Ok(false) => {}
+                    Err(e) if e.is::<RemovedUpstream>() => {
+                        removed_upstream.insert(item.id, true);
+                    }
+                    Err(e) => return Err((item.id, e)),
+                }
+                let (model_path, _, _, _) = get_abs_path(&config, &item.base_label, &item.path);
+                let info = api::read_model_info(Path::new(&model_path), true)
+                    .await
+                    .map_err(|e| (item.id, e))?;
+                local_infos.insert(item.id, info);
             }
         }
 
-        // Remove *.model.json file
-        let model_json = model_file.with_extension("model.json");
-        if let Err(e) = move_to_dir(&[model_json], &trash_dir).await {
-            error!("Failed to move to trash directory: {}", e);
+        let mut tx = pool.begin().await.map_err(|e| (0, e.into()))?;
+        for (item, destination) in items.iter().zip(destinations.iter()) {
+            let res: anyhow::Result<()> = async {
+                if is_data_changed {
+                    update_item_data(&mut tx, &user, item.id, &add_tags, &remove_tags, data.note.as_deref()).await?;
+                }
+                if data.delete {
+                    return move_to_trash(&config, &mut tx, &mut journal, &user, item.id).await;
+                }
+                let (mut label, mut relative_path) = (item.base_label.as_str(), item.path.as_str());
+                if let Some(destination) = destination {
+                    move_item(&mut tx, &mut journal, &user, item, destination).await?;
+                    (label, relative_path) = (destination.label.as_str(), destination.relative_path.as_str());
+                }
+                let (_, json_path, _, _) = get_abs_path(&config, label, relative_path);
+                if let Some(removed) = removed_upstream.get(&item.id) {
+                    db::item::set_removed_upstream(&mut tx, item.id, *removed).await?;
+                }
+                if let Some(info) = local_infos.get(&item.id) {
+                    api::save_model_info_with(&mut tx, info, label, relative_path, config.sidecar.policy, true).await?;
+                }
+                if data.write_a1111 || (data.resync && config.sidecar.a1111) {
+                    journal.keep(Path::new(&json_path)).await?;
+                    a1111::write(&config, &mut tx, item.id).await?;
+                }
+                Ok(())
+            }
+            .await;
+            res.map_err(|e| (item.id, e))?;
         }
+        tx.commit().await.map_err(|e| (0, e.into()))
+    }
+    .await;
+    if let Err((id, e)) = res {
+        journal.undo().await;
+        return web::Json(failed_batch(&items, id, e));
     }
 
-    web::Json("")
+    if is_data_changed && !data.delete {
+        for item in items.iter() {
+            sidecar::write_if_enabled(&config, pool, item.id).await;
+        }
+    }
+    let results = items
+        .iter()
+        .map(|item| BatchItemResult { id: item.id, err: None })
+        .collect();
+    web::Json(BatchResponse { results, err: None })
 }
 
-#[post("update")]
-async fn update(db_pool: Data<DBPool>, data: web::Json<ItemUpdate>) -> impl Responder {
-    if let Err(e) = update_tag_item(&db_pool.sqlite_pool, data.item_id, data.tags.as_str()).await {
-        error!("Failed to update tag: {}", e);
+/// Response of a batch which is rolled back because item `id` failed
+fn failed_batch(items: &[Item], id: i64, e: anyhow::Error) -> BatchResponse {
+    let results = items
+        .iter()
+        .map(|item| BatchItemResult {
+            id: item.id,
+            err: Some(if item.id == id { format!("{e}") } else { "Rolled back".to_string() }),
+        })
+        .collect();
+    BatchResponse {
+        results,
+        err: Some(format!("Failed to update item {id}: {e}")),
     }
+}
 
-    if let Err(e) = update_item_note(&db_pool.sqlite_pool, data.item_id, data.note.as_str()).await {
-        error!("Failed to update note: {}", e);
+pub(super) async fn select_items(pool: &SqlitePool, data: &Selection) -> anyhow::Result<Vec<Item>> {
+    let mut items = Vec::new();
+    if !data.ids.is_empty() {
+        for id in data.ids.iter() {
+            items.push(db::item::get_by_id(pool, *id).await?);
+        }
+        return Ok(items);
     }
 
-    web::Json("")
+    let Some(search) = data.search.as_ref() else {
+        return Ok(items);
+    };
+    let limit = 500;
+    loop {
+        let opts = SearchOptions {
+            search,
+            tag_only: data.tag_only.unwrap_or(false),
+            duplicate_only: data.duplicate_only.unwrap_or(false),
+            duplicate_by: data.duplicate_by.unwrap_or_default(),
+            limit,
+            offset: items.len() as i64,
+            ..Default::default()
+        };
+        let res = db::item::search(pool, &opts).await?;
+        let count = res.items.len() as i64;
+        items.extend(res.items);
+        if count < limit {
+            break;
+        }
+    }
+    Ok(items)
+}
+
+/// Update tags and note of item
+async fn update_item_data(
+    conn: &mut SqliteConnection,
+    user: &str,
+    id: i64,
+    add_tags: &Vec<String>,
+    remove_tags: &[String],
+    note: Option<&str>,
+) -> anyhow::Result<()> {
+    let before = item_state(conn, id).await?;
+    add_tag_item(conn, id, add_tags, Some(USER_TAG_TYPE)).await?;
+    remove_tag_item(conn, id, remove_tags).await?;
+    if let Some(note) = note {
+        update_item_note(conn, id, note).await?;
+    }
+    record_item_changes(conn, user, id, &before).await?;
+    Ok(())
+}
+
+/// Move item files to trash directory and mark it as obsolete
+async fn trash_item(config: &Config, pool: &SqlitePool, user: &str, id: i64) -> anyhow::Result<()> {
+    trash_items(config, pool, user, &[id]).await
+}
+
+/// Move files of items to trash directory and mark them as obsolete. Nothing is changed if any item fails.
+pub(super) async fn trash_items(config: &Config, pool: &SqlitePool, user: &str, ids: &[i64]) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    let mut journal = FileJournal::default();
+    for id in ids {
+        if let Err(e) = move_to_trash(config, &mut tx, &mut journal, user, *id).await {
+            journal.undo().await;
+            return Err(e);
+        }
+    }
+    if let Err(e) = tx.commit().await {
+        journal.undo().await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Move item files to trash directory and mark it as obsolete in the transaction of `conn`
+async fn move_to_trash(
+    config: &Config,
+    conn: &mut SqliteConnection,
+    journal: &mut FileJournal,
+    user: &str,
+    id: i64,
+) -> anyhow::Result<()> {
+    let symlinks = db::item_link::count_symlinks_to(conn, id).await?;
+    if symlinks > 0 {
+        return Err(anyhow::anyhow!(
+            "{symlinks} deduplicated items are symlinks to the file of item {id}"
+        ));
+    }
+    let (rel_path, label) = db::item::mark_obsolete(conn, id).await?;
+    db::history::add(conn, user, Action::Delete, Some(id), &format!("{label}/{rel_path}"), "").await?;
+    let Some(base_path) = config.model_paths.get(&label) else {
+        return Err(anyhow::anyhow!("Unknown base path {label}"));
+    };
+    let base_path = PathBuf::from(base_path);
+    let model_file = base_path.join(rel_path);
+    let trash_dir = base_path.join(TRASH_DIR);
+    fs::create_dir_all(&trash_dir).await?;
+
+    move_to_dir(&item_files(&model_file)?, &trash_dir, journal).await
+}
+
+/// Where an item is moved by a batch
+struct Destination {
+    label: String,
+    relative_path: String,
+    dir: PathBuf,
+    /// Files of the item to move
+    files: Vec<PathBuf>,
+}
+
+/// Check where every item is moved before any file is touched. The destination must be inside a base path, and no
+/// file may be overwritten, also not by another item of the batch. `None` if item is already there.
+/// Return id of failed item.
+fn plan_moves(config: &Config, items: &[Item], dest: &str) -> Result<Vec<Option<Destination>>, (i64, anyhow::Error)> {
+    let dest_dir = PathBuf::from(dest);
+    let mut targets = HashSet::new();
+    items
+        .iter()
+        .map(|item| plan_move(config, item, &dest_dir, &mut targets).map_err(|e| (item.id, e)))
+        .collect()
+}
+
+fn plan_move(
+    config: &Config,
+    item: &Item,
+    dest_dir: &Path,
+    targets: &mut HashSet<PathBuf>,
+) -> anyhow::Result<Option<Destination>> {
+    let Some((label, base_path)) = config
+        .model_paths
+        .iter()
+        .find(|(_, base_path)| dest_dir.starts_with(PathBuf::from(base_path)))
+    else {
+        return Err(anyhow::anyhow!("Destination path must be inside base path"));
+    };
+
+    let (model_path, _, _, _) = get_abs_path(config, &item.base_label, &item.path);
+    let model_file = PathBuf::from(model_path);
+    let Some(file_name) = model_file.file_name() else {
+        return Err(anyhow::anyhow!("Invalid item path {}", item.path));
+    };
+    let new_path = dest_dir.join(file_name);
+    if new_path == model_file {
+        return Ok(None);
+    }
+
+    let files = item_files(&model_file)?;
+    let mut new_files = vec![new_path.clone()];
+    new_files.extend(
+        files
+            .iter()
+            .map(|file| dest_dir.join(file.file_name().unwrap_or_default()))
+            .filter(|file| *file != new_path),
+    );
+    for file in new_files {
+        if file.exists() {
+            return Err(anyhow::anyhow!("{} already exists", file.display()));
+        }
+        if !targets.insert(file.clone()) {
+            return Err(anyhow::anyhow!(
+                "{} is also the destination of another item",
+                file.display()
+            ));
+        }
+    }
+
+    Ok(Some(Destination {
+        label: label.clone(),
+        relative_path: api::get_relative_path(base_path, &new_path)?,
+        dir: dest_dir.to_path_buf(),
+        files,
+    }))
+}
+
+/// Move item files into its planned destination and update its location. Items linked by dedupe with symlinks are
+/// not moved, their links would break.
+async fn move_item(
+    conn: &mut SqliteConnection,
+    journal: &mut FileJournal,
+    user: &str,
+    item: &Item,
+    destination: &Destination,
+) -> anyhow::Result<()> {
+    if db::item_link::is_symlink(conn, item.id).await? {
+        return Err(anyhow::anyhow!(
+            "File of item {} is a relative symlink to a deduplicated item",
+            item.id
+        ));
+    }
+    let symlinks = db::item_link::count_symlinks_to(conn, item.id).await?;
+    if symlinks > 0 {
+        return Err(anyhow::anyhow!(
+            "{symlinks} deduplicated items are symlinks to the file of item {}",
+            item.id
+        ));
+    }
+    fs::create_dir_all(&destination.dir).await?;
+    move_to_dir(&destination.files, &destination.dir, journal).await?;
+
+    db::item::update_path(conn, item.id, &destination.label, &destination.relative_path).await?;
+    db::history::add(
+        conn,
+        user,
+        Action::Move,
+        Some(item.id),
+        &format!("{}/{}", item.base_label, item.path),
+        &format!("{}/{}", destination.label, destination.relative_path),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Download Civitai info of item before the transaction of a batch. Files next to the model are kept in `journal`, so
+/// they are restored if the batch fails. Return true if the info was downloaded, see [`get_item_info`].
+async fn resync_files(
+    config: &Config,
+    client: &Client,
+    headers: &HeaderMap,
+    item: &Item,
+    journal: &mut FileJournal,
+) -> anyhow::Result<bool> {
+    let (path, _, _, _) = get_abs_path(config, &item.base_label, &item.path);
+    let path = PathBuf::from(path);
+    let before = info_files(config, &path)?;
+    for file in before.iter() {
+        journal.keep(file).await?;
+    }
+    let res = get_item_info(&path, client, headers, None, config).await;
+    // e.g. a preview of another type
+    for file in info_files(config, &path)? {
+        if !before.contains(&file) {
+            journal.0.push(FileUndo::Restore(file, None));
+        }
+    }
+    res
+}
+
+/// Files next to the model which are written by sync, e.g. `.json` and preview. Other models of the same name are not
+/// included.
+fn info_files(config: &Config, model_file: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = item_files(model_file)?;
+    files.retain(|file| {
+        !file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| config.extensions.contains(ext))
+    });
+    for ext in ["json", "model.json"].into_iter().chain(PREVIEW_IMAGE_EXTS) {
+        let file = model_file.with_extension(ext);
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+/// A file change which can be undone
+enum FileUndo {
+    /// File is renamed from the first path to the second
+    Rename(PathBuf, PathBuf),
+    /// File had this content, or did not exist
+    Restore(PathBuf, Option<Vec<u8>>),
+}
+
+/// File changes done together with a transaction, so they can be undone when it is rolled back
+#[derive(Default)]
+struct FileJournal(Vec<FileUndo>);
+
+impl FileJournal {
+    async fn rename(&mut self, from: &Path, to: &Path) -> std::io::Result<()> {
+        fs::rename(from, to).await?;
+        self.0.push(FileUndo::Rename(from.to_path_buf(), to.to_path_buf()));
+        Ok(())
+    }
+
+    /// Keep the content of `path` before it is written
+    async fn keep(&mut self, path: &Path) -> std::io::Result<()> {
+        let content = match fs::read(path).await {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        self.0.push(FileUndo::Restore(path.to_path_buf(), content));
+        Ok(())
+    }
+
+    /// Undo the changes, newest first. Errors are only logged.
+    async fn undo(self) {
+        for change in self.0.into_iter().rev() {
+            let (path, res) = match change {
+                FileUndo::Rename(from, to) => {
+                    let res = fs::rename(&to, &from).await;
+                    (from, res)
+                }
+                FileUndo::Restore(path, Some(content)) => {
+                    let res = fs::write(&path, content).await;
+                    (path, res)
+                }
+                FileUndo::Restore(path, None) => match fs::remove_file(&path).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => (path, Err(e)),
+                    _ => (path, Ok(())),
+                },
+            };
+            if let Err(e) = res {
+                error!("Failed to restore {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Model file and the files which belong to it, e.g. its info, preview, sidecar and the files of other tools
+fn item_files(model_file: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = list_same_filename(model_file)?;
+    let exts = [
+        "model.json",
+        SIDECAR_EXT,
+        importer::CIVITAI_HELPER_EXT,
+        importer::STABILITY_MATRIX_EXT,
+    ];
+    for ext in exts.into_iter().chain(importer::PREVIEW_EXTS) {
+        let file = model_file.with_extension(ext);
+        if file.exists() {
+            files.push(file);
+        }
+    }
+    Ok(files)
 }
 
-async fn move_to_dir(files: &[PathBuf], dir: &PathBuf) -> anyhow::Result<()> {
+async fn move_to_dir(files: &[PathBuf], dir: &Path, journal: &mut FileJournal) -> anyhow::Result<()> {
     for file in files {
         let file_name = file.file_name().unwrap_or_default();
         if !file_name.is_empty() {
-            let dest = dir.join(file_name);
-            fs::rename(file, dest).await?;
+            journal.rename(file, &dir.join(file_name)).await?;
         }
     }
 
@@ -1,9 +1,9 @@
 use crate::api::CommonResponse;
-use crate::db::job::Job;
 use crate::db::DBPool;
-use crate::{db, ConfigData};
+use crate::db::job::Job;
+use crate::{ConfigData, db};
 use actix_web::web::{Data, Query};
-use actix_web::{get, web, Responder};
+use actix_web::{Responder, get, web};
 use serde::{Deserialize, Serialize};
 
 pub fn scope(cfg: &mut web::ServiceConfig) {
@@ -0,0 +1,142 @@
+use crate::api::CommonResponse;
+use crate::api::item::{Selection, select_items};
+use crate::db;
+use crate::db::DBPool;
+use crate::db::item::Item;
+use actix_web::http::header::CONTENT_DISPOSITION;
+use actix_web::web::Data;
+use actix_web::{HttpResponse, Responder, post, web};
+use actix_web_lab::extract::Query;
+use serde::{Deserialize, Serialize};
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/license").service(report).service(acknowledge));
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    /// `csv` (default) or `json`
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AcknowledgeRequest {
+    ids: Vec<i64>,
+}
+
+/// License of one item in the report
+#[derive(Serialize)]
+struct LicenseRow {
+    id: i64,
+    name: String,
+    collection: String,
+    path: String,
+    model_type: String,
+    base_model: String,
+    creator: String,
+    /// Page of the model version on Civitai, empty if the model is not from Civitai
+    url: String,
+    /// Comma separated, e.g. `Image,RentCivit`
+    allow_commercial_use: String,
+    allow_derivatives: Option<bool>,
+    allow_different_license: Option<bool>,
+    allow_no_credit: Option<bool>,
+    license_changed_at: Option<i64>,
+    previous_license: String,
+}
+
+impl From<Item> for LicenseRow {
+    fn from(item: Item) -> Self {
+        let url = match (item.civitai_model_id, item.civitai_version_id) {
+            (Some(model), Some(version)) => format!("https://civitai.com/models/{model}?modelVersionId={version}"),
+            (Some(model), None) => format!("https://civitai.com/models/{model}"),
+            _ => String::new(),
+        };
+        Self {
+            id: item.id,
+            name: item.name.unwrap_or_default(),
+            collection: item.base_label,
+            path: item.path,
+            model_type: item.model_type,
+            base_model: item.base_model,
+            creator: item.creator,
+            url,
+            allow_commercial_use: item.allow_commercial_use,
+            allow_derivatives: item.allow_derivatives,
+            allow_different_license: item.allow_different_license,
+            allow_no_credit: item.allow_no_credit,
+            license_changed_at: item.license_changed_at,
+            previous_license: item.previous_license,
+        }
+    }
+}
+
+const CSV_HEADER: &str = "id,name,collection,path,model_type,base_model,creator,url,allow_commercial_use,\
+    allow_derivatives,allow_different_license,allow_no_credit,license_changed_at,previous_license";
+
+fn to_csv(rows: &[LicenseRow]) -> String {
+    let opt_bool = |v: Option<bool>| v.map(|v| v.to_string()).unwrap_or_default();
+    let mut csv = format!("{CSV_HEADER}\n");
+    for row in rows {
+        let fields = [
+            row.id.to_string(),
+            row.name.clone(),
+            row.collection.clone(),
+            row.path.clone(),
+            row.model_type.clone(),
+            row.base_model.clone(),
+            row.creator.clone(),
+            row.url.clone(),
+            row.allow_commercial_use.clone(),
+            opt_bool(row.allow_derivatives),
+            opt_bool(row.allow_different_license),
+            opt_bool(row.allow_no_credit),
+            row.license_changed_at.map(|v| v.to_string()).unwrap_or_default(),
+            row.previous_license.clone(),
+        ];
+        csv.push_str(&fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quote field if it contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// License report of selected items as CSV or JSON
+#[post("report")]
+async fn report(db_pool: Data<DBPool>, params: Query<ReportQuery>, data: web::Json<Selection>) -> impl Responder {
+    let rows = match select_items(&db_pool.sqlite_pool, &data).await {
+        Ok(items) => items.into_iter().map(LicenseRow::from).collect::<Vec<_>>(),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to select items: {e}")),
+    };
+
+    let (content_type, file_name, body) = match params.format.as_deref() {
+        Some("json") => match serde_json::to_string(&rows) {
+            Ok(body) => ("application/json", "licenses.json", body),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to write report: {e}")),
+        },
+        _ => ("text/csv; charset=utf-8", "licenses.csv", to_csv(&rows)),
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((CONTENT_DISPOSITION, format!("attachment; filename=\"{file_name}\"")))
+        .body(body)
+}
+
+/// Clear the warning of items whose license changed
+#[post("acknowledge")]
+async fn acknowledge(db_pool: Data<DBPool>, data: web::Json<AcknowledgeRequest>) -> impl Responder {
+    match db::item::acknowledge_license_change(&db_pool.sqlite_pool, &data.ids).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!(
+            "Failed to acknowledge license change: {e}"
+        ))),
+    }
+}
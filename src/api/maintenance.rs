@@ -1,20 +1,25 @@
-
-
-use crate::api::{get_abs_path, CommonResponse, TRASH_DIR};
-use crate::civitai::{get_item_info, update_model_info};
-use crate::db::job::{add_job, update_job, JobState};
+use crate::api::{CommonResponse, TRASH_DIR, auth_user, get_abs_path};
+use crate::civitai::{RemovedUpstream, SyncReport, download_file, get_item_info, update_model_info};
+use crate::config::Config;
 use crate::db::DBPool;
+use crate::db::item::ItemPath;
+use crate::db::job::{JobState, add_job, update_job};
+use crate::disk::{SpaceLimit, quota_collection};
 use crate::ui::Broadcaster;
-use crate::{api, db, ConfigData, StopHandle};
+use crate::verify::{self, RateLimit, Verdict};
+use crate::{ConfigData, StopHandle, a1111, api, db};
 use actix_web::web::{Data, Query};
-use actix_web::{get, rt, web, HttpResponse, Responder};
+use actix_web::{HttpResponse, Responder, get, rt, web};
+use actix_web_httpauth::extractors::basic::BasicAuth;
 use jwalk::{Parallelism, WalkDir};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use reqwest::Client;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use serde::Deserialize;
+use serde_json::Value;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs;
 use tokio::sync::{RwLock, Semaphore};
 use tracing::{error, info};
@@ -27,7 +32,9 @@ pub fn scope(cfg: &mut web::ServiceConfig) {
             .service(sync_civitai)
             .service(restart)
             .service(force_restart)
-            .service(empty_trash),
+            .service(empty_trash)
+            .service(apply_implications)
+            .service(verify_files),
     );
 }
 
@@ -37,6 +44,15 @@ struct SyncCivitaiQuery {
     id: Option<i64>,
 }
 
+#[derive(Deserialize)]
+struct VerifyQuery {
+    /// Download corrupted Civitai models again
+    #[serde(default)]
+    redownload: bool,
+    /// Max MB read per second by all files being hashed, unlimited if not set
+    mb_per_sec: Option<u64>,
+}
+
 #[get("scan")]
 async fn scan_folder(
     config: Data<ConfigData>,
@@ -54,7 +70,9 @@ async fn scan_folder(
 async fn remove_orphan(db_pool: Data<DBPool>, broadcaster: Data<Broadcaster>) -> impl Responder {
     broadcaster.warn("Removing orphaned item...").await;
     let deleted_items = db::item::clean(&db_pool.sqlite_pool).await.unwrap_or_default();
-    broadcaster.info(&format!("Removed {} orphaned items", deleted_items)).await;
+    broadcaster
+        .info(&format!("Removed {} orphaned items", deleted_items))
+        .await;
 
     web::Json(format!(
         "{{
@@ -85,13 +103,39 @@ async fn sync_civitai(
                 );
                 let path = Path::new(&path);
                 broadcaster.info("Start to sync Civitai...").await;
-                if let Err(e) = get_item_info(path, &client, &headers, None, &config).await {
-                    broadcaster
-                        .error(&format!("Failed to get model info {}: {}", &path.display(), e))
+                match get_item_info(path, &client, &headers, None, &config).await {
+                    Err(e) if e.is::<RemovedUpstream>() => {
+                        set_removed_upstream(&db_pool, id, true).await;
+                        broadcaster
+                            .warn(&format!("{e}, its info is kept: {}", &path.display()))
+                            .await;
+                    }
+                    Err(e) => {
+                        broadcaster
+                            .error(&format!("Failed to get model info {}: {}", &path.display(), e))
+                            .await;
+                    }
+                    Ok(is_fetched) => {
+                        if is_fetched {
+                            set_removed_upstream(&db_pool, id, false).await;
+                        }
+                        api::save_model_info(
+                            &db_pool,
+                            path,
+                            item.base_label.as_str(),
+                            item.path.as_str(),
+                            config.sidecar.policy,
+                        )
                         .await;
-                } else {
-                    api::save_model_info(&db_pool, &path, item.base_label.as_str(), item.path.as_str()).await;
-                    broadcaster.info(&format!("Synced model {}", &path.display())).await;
+                        if config.sidecar.a1111
+                            && let Err(e) =
+                                async { a1111::write(&config, &mut *db_pool.sqlite_pool.acquire().await?, id).await }
+                                    .await
+                        {
+                            error!("Failed to write A1111 metadata of {}: {}", path.display(), e);
+                        }
+                        broadcaster.info(&format!("Synced model {}", &path.display())).await;
+                    }
                 }
             }
             Err(e) => return web::Json(CommonResponse::from_err(e.to_string().as_str())),
@@ -101,17 +145,282 @@ async fn sync_civitai(
             broadcaster.info("Start to sync Civitai...").await;
             let id = add_job(&db_pool.sqlite_pool, "Sync Civitai", "").await;
             let config = config_data.config.read().await.clone();
-            let _ = update_model_info(&config).await;
+            let report = update_model_info(&config).await.unwrap_or_default();
             if let Ok(id) = id {
                 let _ = update_job(&db_pool.sqlite_pool, id, "", JobState::Succeed).await;
             }
             broadcaster.info("Finish syncing model info from Civitai").await;
-            scan(config_data, db_pool, &broadcaster).await;
+            scan(config_data, db_pool.clone(), &broadcaster).await;
+            mark_removed_upstream(&config, &db_pool, &report, &broadcaster).await;
+            if config.sidecar.a1111 {
+                write_a1111(&config, &db_pool, &broadcaster).await;
+            }
         });
     }
     web::Json(CommonResponse::from_msg(""))
 }
 
+/// Mark item as removed from Civitai, or clear the mark
+async fn set_removed_upstream(db_pool: &DBPool, id: i64, removed: bool) {
+    let res =
+        async { db::item::set_removed_upstream(&mut *db_pool.sqlite_pool.acquire().await?, id, removed).await }.await;
+    if let Err(e) = res {
+        error!("Failed to mark item {} as removed from Civitai: {}", id, e);
+    }
+}
+
+/// Mark items of models which sync found removed from Civitai, and clear the mark of models which are found again
+async fn mark_removed_upstream(config: &Config, db_pool: &DBPool, report: &SyncReport, broadcaster: &Broadcaster) {
+    for (paths, removed) in [(&report.found, false), (&report.removed, true)] {
+        for path in paths {
+            for (label, base_path) in config.model_paths.iter() {
+                let Ok(relative_path) = api::get_relative_path(base_path, path) else {
+                    continue;
+                };
+                if let Ok(Some(id)) = db::item::get_id_by_path(&db_pool.sqlite_pool, label, &relative_path).await {
+                    set_removed_upstream(db_pool, id, removed).await;
+                }
+            }
+        }
+    }
+    if !report.removed.is_empty() {
+        broadcaster
+            .warn(&format!(
+                "{} models were removed from Civitai, their info is kept",
+                report.removed.len()
+            ))
+            .await;
+    }
+}
+
+/// Write A1111/Forge user metadata of all LoRAs and embeddings as a job
+async fn write_a1111(config: &Config, db_pool: &DBPool, broadcaster: &Broadcaster) {
+    let id = add_job(&db_pool.sqlite_pool, "Write A1111 metadata", "").await;
+    match a1111::write_all(config, &db_pool.sqlite_pool).await {
+        Ok(count) => {
+            if let Ok(id) = id {
+                let _ = update_job(
+                    &db_pool.sqlite_pool,
+                    id,
+                    &format!("Updated {count} files"),
+                    JobState::Succeed,
+                )
+                .await;
+            }
+            broadcaster
+                .info(&format!("Updated A1111 metadata of {count} models"))
+                .await;
+        }
+        Err(e) => {
+            if let Ok(id) = id {
+                let _ = update_job(&db_pool.sqlite_pool, id, &format!("{e}"), JobState::Failed).await;
+            }
+            broadcaster.error(&format!("Failed to write A1111 metadata: {e}")).await;
+        }
+    }
+}
+
+#[get("verify")]
+async fn verify_files(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    params: Query<VerifyQuery>,
+) -> impl Responder {
+    let config = config.config.read().await.clone();
+    let bytes_per_sec = params.mb_per_sec.unwrap_or_default() * 1024 * 1024;
+    rt::spawn(async move {
+        broadcaster.warn("Verifying files...").await;
+        verify(&config, &db_pool, &broadcaster, params.redownload, bytes_per_sec).await;
+    });
+    web::Json(CommonResponse::from_msg("Verifying in background"))
+}
+
+/// Hash every item again and compare with the hashes recorded by Civitai, as a job. Items which do not match or
+/// cannot be read get the `corrupt` status and tag, and are downloaded again if `redownload` is set.
+async fn verify(config: &Config, db_pool: &DBPool, broadcaster: &Broadcaster, redownload: bool, bytes_per_sec: u64) {
+    let pool = &db_pool.sqlite_pool;
+    let id = add_job(pool, "Verify files", "").await;
+    let items = match db::item::list_paths(pool).await {
+        Ok(items) => items,
+        Err(e) => {
+            let msg = format!("Failed to list items: {e}");
+            if let Ok(id) = id {
+                let _ = update_job(pool, id, &msg, JobState::Failed).await;
+            }
+            broadcaster.error(&msg).await;
+            return;
+        }
+    };
+
+    let semaphore = Arc::new(Semaphore::new(config.parallel));
+    // Shared by the parallel workers, so they read at most `bytes_per_sec` together
+    let limit = Arc::new(RateLimit::new(bytes_per_sec));
+    let mut handles = Vec::new();
+    for item in items {
+        let (path, json, _, _) = get_abs_path(config, &item.base_label, &item.path);
+        let semaphore = semaphore.clone();
+        let limit = limit.clone();
+        handles.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire().await;
+            let verdict = verify_file(PathBuf::from(path), PathBuf::from(json), limit).await;
+            (item, verdict)
+        }));
+    }
+
+    let (mut ok_count, mut unknown_count) = (0, 0);
+    let mut corrupt = Vec::new();
+    for handle in handles {
+        let (item, verdict) = match handle.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Failed to verify file: {e}");
+                continue;
+            }
+        };
+        let is_corrupt = matches!(verdict, Verdict::Corrupt(_));
+        let res = async { db::item::set_corrupt(&mut *pool.acquire().await?, item.id, is_corrupt).await }.await;
+        if let Err(e) = res {
+            error!("Failed to mark item {} as verified: {}", item.id, e);
+        }
+        match verdict {
+            Verdict::Ok => ok_count += 1,
+            Verdict::Unknown => unknown_count += 1,
+            Verdict::Corrupt(reason) => corrupt.push((item, reason)),
+        }
+    }
+
+    let mut fixed_count = 0;
+    if redownload && !corrupt.is_empty() {
+        let client = Client::new();
+        let mut headers = HeaderMap::new();
+        if let Ok(bearer) = HeaderValue::from_str(&format!("Bearer {}", config.civitai.api_key)) {
+            headers.insert(AUTHORIZATION, bearer);
+        }
+        for (item, reason) in corrupt.iter_mut() {
+            broadcaster
+                .info(&format!("Downloading {}/{} again", item.base_label, item.path))
+                .await;
+            match redownload_item(config, db_pool, &client, &headers, item).await {
+                Ok(_) => {
+                    fixed_count += 1;
+                    reason.clear();
+                }
+                Err(e) => reason.push_str(&format!(", failed to download again: {e}")),
+            }
+        }
+        corrupt.retain(|(_, reason)| !reason.is_empty());
+    }
+
+    let mut desc = format!(
+        "{} files match, {} have no Civitai hash, {} are corrupt",
+        ok_count,
+        unknown_count,
+        corrupt.len()
+    );
+    if redownload {
+        desc.push_str(&format!(", {fixed_count} were downloaded again"));
+    }
+    for (item, reason) in corrupt.iter() {
+        desc.push_str(&format!("\n{}/{}: {}", item.base_label, item.path, reason));
+    }
+    if let Ok(id) = id {
+        let _ = update_job(pool, id, &desc, JobState::Succeed).await;
+    }
+    if corrupt.is_empty() {
+        broadcaster.info("Finished verifying files").await;
+    } else {
+        broadcaster
+            .error(&format!(
+                "Found {} corrupted files, search status:corrupt to list them",
+                corrupt.len()
+            ))
+            .await;
+    }
+}
+
+/// Compare model file at `path` with its Civitai info at `json`. Files which cannot be read are corrupt.
+async fn verify_file(path: PathBuf, json: PathBuf, limit: Arc<RateLimit>) -> Verdict {
+    let hashes = match tokio::task::spawn_blocking(move || verify::hash_file(&path, &limit)).await {
+        Ok(Ok(hashes)) => hashes,
+        Ok(Err(e)) => return Verdict::Corrupt(format!("Failed to read file: {e}")),
+        Err(e) => return Verdict::Corrupt(format!("Failed to read file: {e}")),
+    };
+    let info = fs::read_to_string(&json)
+        .await
+        .ok()
+        .and_then(|info| serde_json::from_str::<Value>(&info).ok())
+        .unwrap_or_default();
+    verify::check(&info, &hashes)
+}
+
+/// Download the Civitai file of corrupted item again, and clear the mark if the new file matches
+async fn redownload_item(
+    config: &Config,
+    db_pool: &DBPool,
+    client: &Client,
+    headers: &HeaderMap,
+    item: &ItemPath,
+) -> anyhow::Result<()> {
+    let pool = &db_pool.sqlite_pool;
+    let (path, json, _, _) = get_abs_path(config, &item.base_label, &item.path);
+    let path = PathBuf::from(path);
+    let info: Value = serde_json::from_str(&fs::read_to_string(&json).await?)?;
+    let file = verify::expected_file(&info, &path).ok_or(anyhow::anyhow!("Civitai info has no file to download"))?;
+    let url = file["downloadUrl"]
+        .as_str()
+        .ok_or(anyhow::anyhow!("Civitai file has no download URL"))?;
+    let blake3 = file["hashes"]["BLAKE3"].as_str().unwrap_or_default().to_lowercase();
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let used_bytes = match quota_collection(config, dir) {
+        Some(label) => {
+            // The corrupted file is replaced, so the usage of its collection does not grow by its size
+            let replaced_bytes =
+                if label == item.base_label { db::item::get_by_id(pool, item.id).await?.file_size } else { 0 };
+            (db::stats::collection_bytes(pool, label).await? - replaced_bytes).max(0) as u64
+        }
+        None => 0,
+    };
+    let limit = SpaceLimit::new(config, dir, used_bytes);
+    // Size in Civitai info is checked first, so the corrupted file is kept if the new one does not fit
+    if let Some(size_kb) = file["sizeKB"].as_f64() {
+        limit.check((size_kb * 1024.0) as u64)?;
+    }
+    download_file(
+        url,
+        &path,
+        client,
+        headers,
+        &config.model_paths,
+        &blake3,
+        config.civitai.max_retries,
+        Some(&limit),
+    )
+    .await?;
+
+    if let Verdict::Corrupt(reason) = verify_file(path, PathBuf::from(json), Arc::new(RateLimit::new(0))).await {
+        return Err(anyhow::anyhow!(reason));
+    }
+    db::item::set_corrupt(&mut *pool.acquire().await?, item.id, false).await?;
+    Ok(())
+}
+
+#[get("apply_implications")]
+async fn apply_implications(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    broadcaster: Data<Broadcaster>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    let user = auth_user(&auth);
+    rt::spawn(async move {
+        broadcaster.info("Applying tag implications to existing items...").await;
+        api::tag::apply_implications(&config, &db_pool, &broadcaster, &user, None).await;
+    });
+    web::Json(CommonResponse::from_msg(""))
+}
+
 #[get("empty_trash")]
 async fn empty_trash(config: Data<ConfigData>, broadcaster: Data<Broadcaster>) -> impl Responder {
     broadcaster.warn("Emptying trash...").await;
@@ -149,15 +458,12 @@ async fn scan(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: &Bro
 
     let config = config.config.read().await;
     let valid_ext = config.extensions.iter().collect::<HashSet<_>>();
+    // Items are marked obsolete after scanning, so they are still listed while scan is running
+    let scan_started_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
 
-    if let Err(e) = db::item::mark_obsolete_all(&db_pool.sqlite_pool).await {
-        let msg = format!("Failed to mark all item for reload: {e}");
-        if let Ok(id) = id {
-            let _ = update_job(&db_pool.sqlite_pool, id, msg.as_str(), JobState::Failed).await;
-        }
-        broadcaster.error(format!("Scan failed. {}", &msg).as_str()).await;
-        return;
-    }
     let mut handles = Vec::new();
     let semaphore = Arc::new(Semaphore::new(config.parallel));
     for (label, base_path) in config.model_paths.iter() {
@@ -169,7 +475,8 @@ async fn scan(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: &Bro
             .into_iter()
             .flatten()
         {
-            if entry.file_type().is_file() {
+            // Symlinks to files are items replaced by dedupe
+            if entry.file_type().is_file() || (entry.file_type().is_symlink() && entry.path().is_file()) {
                 let path = entry.path();
                 let Ok(relative_path) = api::get_relative_path(base_path, &path) else {
                     continue;
@@ -180,11 +487,19 @@ async fn scan(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: &Bro
                     let semaphore = semaphore.clone();
                     let db_pool = db_pool.clone();
                     let label = label.clone();
+                    let sidecar_policy = config.sidecar.policy;
 
                     let handle = tokio::spawn(async move {
                         if let Ok(_permit) = semaphore.acquire().await {
                             info!("Found {path:?}");
-                            api::save_model_info(&db_pool, &path, label.as_str(), relative_path.as_str()).await;
+                            api::save_model_info(
+                                &db_pool,
+                                &path,
+                                label.as_str(),
+                                relative_path.as_str(),
+                                sidecar_policy,
+                            )
+                            .await;
                         }
                     });
                     handles.push(handle);
@@ -200,6 +515,15 @@ async fn scan(config: Data<ConfigData>, db_pool: Data<DBPool>, broadcaster: &Bro
         }
     }
 
+    if let Err(e) = db::item::mark_obsolete_unscanned(&db_pool.sqlite_pool, scan_started_ms).await {
+        let msg = format!("Failed to mark missing items: {e}");
+        if let Ok(id) = id {
+            let _ = update_job(&db_pool.sqlite_pool, id, msg.as_str(), JobState::Failed).await;
+        }
+        broadcaster.error(format!("Scan failed. {}", &msg).as_str()).await;
+        return;
+    }
+
     if let Ok(id) = id {
         let _ = update_job(&db_pool.sqlite_pool, id, "", JobState::Succeed).await;
     }
@@ -0,0 +1,146 @@
+use crate::ConfigData;
+use crate::api::item::trash_items;
+use crate::api::{CommonResponse, auth_user, get_abs_path};
+use crate::db;
+use crate::db::DBPool;
+use crate::db::model_group::{LocalVersion, ModelGroup};
+use actix_web::web::Data;
+use actix_web::{Responder, get, post, web};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_lab::extract::Query;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs;
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/model_group")
+            .service(get_all)
+            .service(versions)
+            .service(prune),
+    );
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    /// Only list models with at least this number of installed versions
+    min_items: Option<i64>,
+}
+
+#[derive(Serialize, Default)]
+struct ModelGroupResponse {
+    groups: Vec<ModelGroup>,
+    err: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelQuery {
+    /// Civitai model id
+    id: i64,
+}
+
+/// Version of Civitai model from `modelVersions` of `.model.json`
+#[derive(Serialize)]
+struct AvailableVersion {
+    civitai_version_id: i64,
+    name: String,
+    base_model: String,
+    published_at: String,
+    /// Local item of this version. `None` if it is not installed.
+    item_id: Option<i64>,
+}
+
+#[derive(Serialize, Default)]
+struct VersionsResponse {
+    /// Newest first
+    installed: Vec<LocalVersion>,
+    /// As listed by Civitai, empty if `.model.json` is not downloaded
+    available: Vec<AvailableVersion>,
+    err: Option<String>,
+}
+
+#[get("")]
+async fn get_all(db_pool: Data<DBPool>, params: Query<ListQuery>) -> impl Responder {
+    let res = match db::model_group::list(&db_pool.sqlite_pool, params.min_items.unwrap_or(1)).await {
+        Ok(groups) => ModelGroupResponse { groups, err: None },
+        Err(e) => ModelGroupResponse {
+            err: Some(format!("Failed to list models: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+/// Installed versions of Civitai model and the versions which are available on Civitai
+#[get("versions")]
+async fn versions(config: Data<ConfigData>, db_pool: Data<DBPool>, params: Query<ModelQuery>) -> impl Responder {
+    let installed = match db::model_group::local_versions(&db_pool.sqlite_pool, params.id).await {
+        Ok(installed) => installed,
+        Err(e) => {
+            return web::Json(VersionsResponse {
+                err: Some(format!("Failed to list versions: {e}")),
+                ..Default::default()
+            });
+        }
+    };
+
+    let mut available = Vec::new();
+    if let Some(newest) = installed.first() {
+        let config = config.config.read().await;
+        let (_, _, model_json, _) = get_abs_path(&config, &newest.base_label, &newest.path);
+        let model_info = fs::read_to_string(&model_json).await.unwrap_or_default();
+        let model_info: Value = serde_json::from_str(&model_info).unwrap_or_default();
+        for version in model_info["modelVersions"].as_array().into_iter().flatten() {
+            let Some(version_id) = version["id"].as_i64() else {
+                continue;
+            };
+            available.push(AvailableVersion {
+                civitai_version_id: version_id,
+                name: version["name"].as_str().unwrap_or_default().to_string(),
+                base_model: version["baseModel"].as_str().unwrap_or_default().to_string(),
+                published_at: version["publishedAt"]
+                    .as_str()
+                    .or(version["createdAt"].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                item_id: installed
+                    .iter()
+                    .find(|item| item.civitai_version_id == Some(version_id))
+                    .map(|item| item.item_id),
+            });
+        }
+    }
+
+    web::Json(VersionsResponse {
+        installed,
+        available,
+        err: None,
+    })
+}
+
+/// Move all installed versions of Civitai model except the newest one to trash
+#[post("prune")]
+async fn prune(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<ModelQuery>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    let pool = &db_pool.sqlite_pool;
+    let installed = match db::model_group::local_versions(pool, data.id).await {
+        Ok(installed) => installed,
+        Err(e) => return web::Json(CommonResponse::from_err(&format!("Failed to list versions: {e}"))),
+    };
+    let older = installed.iter().skip(1).map(|item| item.item_id).collect::<Vec<i64>>();
+    if older.is_empty() {
+        return web::Json(CommonResponse::from_msg("Nothing to delete"));
+    }
+
+    let config = config.config.read().await;
+    match trash_items(&config, pool, &auth_user(&auth), &older).await {
+        Ok(_) => web::Json(CommonResponse::from_msg(&format!("Deleted {} items", older.len()))),
+        Err(e) => web::Json(CommonResponse::from_err(&format!(
+            "Failed to delete older versions: {e}"
+        ))),
+    }
+}
@@ -0,0 +1,101 @@
+use crate::api::{CommonResponse, DeleteRequest};
+use crate::db;
+use crate::db::DBPool;
+use crate::db::saved_search::SavedSearch;
+use actix_web::web::Data;
+use actix_web::{Responder, get, post, web};
+use actix_web_lab::extract::Query;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tracing::error;
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/saved_search")
+            .service(get_all)
+            .service(add)
+            .service(update)
+            .service(delete),
+    );
+}
+
+#[derive(Serialize)]
+struct SavedSearchCount {
+    #[serde(flatten)]
+    saved_search: SavedSearch,
+    count: i64,
+}
+
+#[derive(Serialize, Default)]
+struct SavedSearchResponse {
+    saved_searches: Vec<SavedSearchCount>,
+    err: Option<String>,
+}
+
+/// List saved searches with number of items currently matching each of them
+async fn list_with_count(pool: &SqlitePool) -> anyhow::Result<Vec<SavedSearchCount>> {
+    let mut ret = Vec::new();
+    for saved_search in db::saved_search::list(pool).await? {
+        let count = db::saved_search::count_items(pool, &saved_search)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to count items of saved search {}: {e}", saved_search.name);
+                0
+            });
+        ret.push(SavedSearchCount { saved_search, count });
+    }
+    Ok(ret)
+}
+
+#[get("")]
+async fn get_all(db_pool: Data<DBPool>) -> impl Responder {
+    let res = match list_with_count(&db_pool.sqlite_pool).await {
+        Ok(saved_searches) => SavedSearchResponse {
+            saved_searches,
+            err: None,
+        },
+        Err(e) => SavedSearchResponse {
+            err: Some(format!("Failed to list saved searches: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+#[post("add")]
+async fn add(db_pool: Data<DBPool>, data: web::Json<SavedSearch>) -> impl Responder {
+    if data.name.trim().is_empty() {
+        return web::Json(CommonResponse::from_err("Name must not be empty"));
+    }
+    match db::saved_search::add(&db_pool.sqlite_pool, &data).await {
+        Ok(id) => web::Json(CommonResponse::from_msg(&id.to_string())),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to save search: {e}"))),
+    }
+}
+
+#[post("update")]
+async fn update(db_pool: Data<DBPool>, data: web::Json<SavedSearch>) -> impl Responder {
+    if data.name.trim().is_empty() {
+        return web::Json(CommonResponse::from_err("Name must not be empty"));
+    }
+    match db::saved_search::update(&db_pool.sqlite_pool, &data).await {
+        Ok(_) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to update saved search: {e}"))),
+    }
+}
+
+#[get("delete")]
+async fn delete(db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> impl Responder {
+    let mut err_str = String::new();
+    for id in params.ids.iter() {
+        if let Err(e) = db::saved_search::delete(&db_pool.sqlite_pool, *id).await {
+            err_str.push_str(&format!("{e}\n"));
+        }
+    }
+
+    let err = if err_str.is_empty() { None } else { Some(err_str) };
+    web::Json(CommonResponse {
+        err,
+        ..Default::default()
+    })
+}
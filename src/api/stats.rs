@@ -0,0 +1,142 @@
+use crate::ConfigData;
+use crate::api::TRASH_DIR;
+use crate::db;
+use crate::db::DBPool;
+use crate::db::stats::{Duplicates, LargestItem, Snapshot, Totals, Usage};
+use actix_web::web::Data;
+use actix_web::{Responder, get, web};
+use actix_web_lab::extract::Query;
+use jwalk::WalkDir;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+pub fn scope(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/stats").service(get_stats).service(history));
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// Number of largest items, 20 by default
+    largest: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Number of days, 90 by default
+    days: Option<i64>,
+}
+
+/// Files in trash directory of a collection
+#[derive(Serialize)]
+struct TrashUsage {
+    base_label: String,
+    file_count: i64,
+    total_bytes: i64,
+}
+
+#[derive(Serialize, Default)]
+struct StatsResponse {
+    totals: Totals,
+    collections: Vec<Usage>,
+    folders: Vec<Usage>,
+    base_models: Vec<Usage>,
+    model_types: Vec<Usage>,
+    tags: Vec<Usage>,
+    duplicates: Duplicates,
+    trash: Vec<TrashUsage>,
+    largest: Vec<LargestItem>,
+    err: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct HistoryResponse {
+    snapshots: Vec<Snapshot>,
+    err: Option<String>,
+}
+
+/// Disk usage of the library
+#[get("")]
+async fn get_stats(config: Data<ConfigData>, db_pool: Data<DBPool>, params: Query<StatsQuery>) -> impl Responder {
+    let model_paths = config.config.read().await.model_paths.clone();
+    let trash = match tokio::task::spawn_blocking(move || trash_usage(&model_paths)).await {
+        Ok(trash) => trash,
+        Err(e) => {
+            return web::Json(StatsResponse {
+                err: Some(format!("Failed to read trash: {e}")),
+                ..Default::default()
+            });
+        }
+    };
+
+    let pool = &db_pool.sqlite_pool;
+    let res: Result<StatsResponse, sqlx::Error> = async {
+        Ok(StatsResponse {
+            totals: db::stats::totals(pool).await?,
+            collections: db::stats::by_collection(pool).await?,
+            folders: db::stats::by_folder(pool).await?,
+            base_models: db::stats::by_base_model(pool).await?,
+            model_types: db::stats::by_model_type(pool).await?,
+            tags: db::stats::by_tag(pool).await?,
+            duplicates: db::stats::duplicates(pool).await?,
+            trash,
+            largest: db::stats::largest(pool, params.largest.unwrap_or(20)).await?,
+            err: None,
+        })
+    }
+    .await;
+    web::Json(res.unwrap_or_else(|e| StatsResponse {
+        err: Some(format!("Failed to get stats: {e}")),
+        ..Default::default()
+    }))
+}
+
+/// Daily snapshots of disk usage per collection
+#[get("history")]
+async fn history(db_pool: Data<DBPool>, params: Query<HistoryQuery>) -> impl Responder {
+    let res = match db::stats::list_snapshots(&db_pool.sqlite_pool, params.days.unwrap_or(90)).await {
+        Ok(snapshots) => HistoryResponse { snapshots, err: None },
+        Err(e) => HistoryResponse {
+            err: Some(format!("Failed to list snapshots: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+fn trash_usage(model_paths: &HashMap<String, String>) -> Vec<TrashUsage> {
+    let mut usages = model_paths
+        .iter()
+        .map(|(label, base_path)| {
+            let mut usage = TrashUsage {
+                base_label: label.clone(),
+                file_count: 0,
+                total_bytes: 0,
+            };
+            let trash_dir = PathBuf::from(base_path).join(TRASH_DIR);
+            for entry in WalkDir::new(trash_dir).skip_hidden(false).into_iter().flatten() {
+                if let Some(metadata) = entry.metadata().ok().filter(|metadata| metadata.is_file()) {
+                    usage.file_count += 1;
+                    usage.total_bytes += metadata.len() as i64;
+                }
+            }
+            usage
+        })
+        .collect::<Vec<_>>();
+    usages.sort_by(|a, b| a.base_label.cmp(&b.base_label));
+    usages
+}
+
+/// Save snapshot of disk usage every hour, so the snapshot of each day has the usage at the end of the day
+pub async fn record_daily_stats(db_pool: Arc<DBPool>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        if let Err(e) = db::stats::save_snapshot(&db_pool.sqlite_pool).await {
+            error!("Failed to save stats snapshot: {}", e);
+        }
+    }
+}
@@ -1,13 +1,16 @@
-
-
-use crate::api::{CommonResponse, DeleteRequest};
+use crate::api::{CommonResponse, DeleteRequest, auth_user};
 use crate::db;
-use crate::db::tag::Tag;
 use crate::db::DBPool;
+use crate::db::job::{JobState, add_job, update_job};
+use crate::db::tag::{Tag, TagCount, TagType};
+use crate::ui::Broadcaster;
+use crate::{ConfigData, sidecar};
 use actix_web::web::Data;
-use actix_web::{get, post, web, Responder};
+use actix_web::{Responder, get, post, rt, web};
+use actix_web_httpauth::extractors::basic::BasicAuth;
 use actix_web_lab::extract::Query;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use tracing::error;
 
 pub fn scope(cfg: &mut web::ServiceConfig) {
@@ -16,7 +19,13 @@ pub fn scope(cfg: &mut web::ServiceConfig) {
             .service(get_all)
             .service(get)
             .service(update)
-            .service(delete),
+            .service(delete)
+            .service(merge)
+            .service(implication_preview)
+            .service(get_types)
+            .service(add_type)
+            .service(update_type)
+            .service(delete_type),
     );
 }
 
@@ -26,15 +35,70 @@ struct TagResponse {
     err: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct TagListQuery {
+    /// Group tags by tag type
+    group: Option<bool>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TagListResponse {
+    List(Vec<TagCount>),
+    /// Tags without type are grouped under empty string
+    Group(BTreeMap<String, Vec<TagCount>>),
+}
+
+#[derive(Deserialize)]
+struct ImplicationPreviewQuery {
+    tag: String,
+    /// Dependencies to add, separated by space
+    deps: String,
+}
+
+#[derive(Serialize, Default)]
+struct ImplicationPreviewResponse {
+    affected_items: i64,
+    err: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TagMerge {
+    /// Tag name which is merged and becomes an alias
+    from: String,
+    into: String,
+}
+
+#[derive(Deserialize)]
+struct TagTypeAdd {
+    name: String,
+}
+
+#[derive(Serialize, Default)]
+struct TagTypeResponse {
+    types: Vec<TagType>,
+    err: Option<String>,
+}
+
 #[get("")]
-async fn get_all(db_pool: Data<DBPool>) -> impl Responder {
-    let get_all = db::tag::list_tags(&db_pool.sqlite_pool, &[])
-        .await
-        .unwrap_or_else(|e| {
-            error!("Failed to list tags: {e}");
-            Vec::new()
-        });
-    web::Json(get_all)
+async fn get_all(db_pool: Data<DBPool>, params: Query<TagListQuery>) -> impl Responder {
+    let get_all = db::tag::list_tags(&db_pool.sqlite_pool, &[]).await.unwrap_or_else(|e| {
+        error!("Failed to list tags: {e}");
+        Vec::new()
+    });
+
+    if params.group.unwrap_or(false) {
+        let mut groups: BTreeMap<String, Vec<TagCount>> = BTreeMap::new();
+        for tag in get_all {
+            groups
+                .entry(tag.tag_type.clone().unwrap_or_default())
+                .or_default()
+                .push(tag);
+        }
+        web::Json(TagListResponse::Group(groups))
+    } else {
+        web::Json(TagListResponse::List(get_all))
+    }
 }
 
 #[get("detail/{tag}")]
@@ -53,14 +117,95 @@ async fn get(db_pool: Data<DBPool>, tag: web::Path<String>) -> impl Responder {
 }
 
 #[post("update")]
-async fn update(db_pool: Data<DBPool>, data: web::Json<Tag>) -> impl Responder {
-    if let Err(e) = db::tag::update_tag(&db_pool.sqlite_pool, &data.into_inner()).await {
-        return web::Json(CommonResponse {
+async fn update(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<Tag>,
+    broadcaster: Data<Broadcaster>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    match db::tag::update_tag(&db_pool.sqlite_pool, &data.into_inner()).await {
+        Ok(Some(tag_id)) => {
+            let user = auth_user(&auth);
+            rt::spawn(async move {
+                apply_implications(&config, &db_pool, &broadcaster, &user, Some(tag_id)).await;
+            });
+            web::Json(CommonResponse::from_msg(
+                "Applying implications to existing items in background",
+            ))
+        }
+        Ok(None) => web::Json(CommonResponse::default()),
+        Err(e) => web::Json(CommonResponse {
             err: Some(format!("Failed to update tag: {e}")),
             ..Default::default()
-        });
+        }),
+    }
+}
+
+#[get("implication_preview")]
+async fn implication_preview(db_pool: Data<DBPool>, params: Query<ImplicationPreviewQuery>) -> impl Responder {
+    let pool = &db_pool.sqlite_pool;
+    let mut res = ImplicationPreviewResponse::default();
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            res.err = Some(format!("{e}"));
+            return web::Json(res);
+        }
+    };
+    match db::tag::find_tag_id(&mut conn, &params.tag.to_lowercase()).await {
+        Ok(Some(tag_id)) => {
+            let deps = params
+                .deps
+                .to_lowercase()
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>();
+            match db::tag::preview_implications(pool, tag_id, &deps).await {
+                Ok(count) => res.affected_items = count,
+                Err(e) => res.err = Some(format!("{e}")),
+            }
+        }
+        Ok(None) => res.err = Some("Tag not found".to_string()),
+        Err(e) => res.err = Some(format!("{e}")),
+    }
+    web::Json(res)
+}
+
+/// Apply implications to existing items which have `tag`, or all items if `None`, as a job
+pub async fn apply_implications(
+    config: &ConfigData,
+    db_pool: &DBPool,
+    broadcaster: &Broadcaster,
+    user: &str,
+    tag: Option<i64>,
+) {
+    let id = add_job(&db_pool.sqlite_pool, "Apply tag implications", "").await;
+    match db::tag::apply_implications(&db_pool.sqlite_pool, user, tag).await {
+        Ok((count, items)) => {
+            sidecar::write_all_if_enabled(&*config.config.read().await, &db_pool.sqlite_pool, &items).await;
+            if let Ok(id) = id {
+                let _ = update_job(
+                    &db_pool.sqlite_pool,
+                    id,
+                    &format!("Added {count} tags"),
+                    JobState::Succeed,
+                )
+                .await;
+            }
+            broadcaster
+                .info(&format!("Applied tag implications. Added {count} tags"))
+                .await;
+        }
+        Err(e) => {
+            if let Ok(id) = id {
+                let _ = update_job(&db_pool.sqlite_pool, id, &format!("{e}"), JobState::Failed).await;
+            }
+            broadcaster
+                .error(&format!("Failed to apply tag implications: {e}"))
+                .await;
+        }
     }
-    web::Json(CommonResponse::default())
 }
 
 #[get("delete")]
@@ -78,3 +223,96 @@ async fn delete(db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> impl Res
         ..Default::default()
     })
 }
+
+#[post("merge")]
+async fn merge(
+    config: Data<ConfigData>,
+    db_pool: Data<DBPool>,
+    data: web::Json<TagMerge>,
+    auth: Option<BasicAuth>,
+) -> impl Responder {
+    let pool = &db_pool.sqlite_pool;
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => return web::Json(CommonResponse::from_err(&format!("{e}"))),
+    };
+    let (from, into) = match (
+        db::tag::find_tag_id(&mut conn, &data.from.to_lowercase()).await,
+        db::tag::find_tag_id(&mut conn, &data.into.to_lowercase()).await,
+    ) {
+        (Ok(Some(from)), Ok(Some(into))) => (from, into),
+        (Err(e), _) | (_, Err(e)) => return web::Json(CommonResponse::from_err(&format!("{e}"))),
+        _ => return web::Json(CommonResponse::from_err("Tag not found")),
+    };
+
+    match db::tag::merge_tag(pool, &auth_user(&auth), from, into).await {
+        Ok(items) => sidecar::write_all_if_enabled(&*config.config.read().await, pool, &items).await,
+        Err(e) => return web::Json(CommonResponse::from_err(&format!("Failed to merge tag: {e}"))),
+    }
+    web::Json(CommonResponse::from_msg(&format!(
+        "Merged {} into {}",
+        data.from, data.into
+    )))
+}
+
+#[get("type")]
+async fn get_types(db_pool: Data<DBPool>) -> impl Responder {
+    let res = match db::tag::list_types(&db_pool.sqlite_pool).await {
+        Ok(types) => TagTypeResponse { types, err: None },
+        Err(e) => TagTypeResponse {
+            err: Some(format!("Failed to list tag types: {e}")),
+            ..Default::default()
+        },
+    };
+    web::Json(res)
+}
+
+#[post("type/add")]
+async fn add_type(db_pool: Data<DBPool>, data: web::Json<TagTypeAdd>) -> impl Responder {
+    let Some(name) = type_name(&data.name) else {
+        return web::Json(CommonResponse::from_err(INVALID_TYPE_NAME));
+    };
+    match db::tag::add_type(&db_pool.sqlite_pool, &name).await {
+        Ok(id) => web::Json(CommonResponse::from_msg(&id.to_string())),
+        Err(e) => web::Json(CommonResponse::from_err(&format!("Failed to add tag type: {e}"))),
+    }
+}
+
+#[post("type/update")]
+async fn update_type(db_pool: Data<DBPool>, data: web::Json<TagType>) -> impl Responder {
+    let Some(name) = type_name(&data.name) else {
+        return web::Json(CommonResponse::from_err(INVALID_TYPE_NAME));
+    };
+    let tag_type = TagType { id: data.id, name };
+    if let Err(e) = db::tag::update_type(&db_pool.sqlite_pool, &tag_type).await {
+        return web::Json(CommonResponse::from_err(&format!("Failed to update tag type: {e}")));
+    }
+    web::Json(CommonResponse::default())
+}
+
+const INVALID_TYPE_NAME: &str = "Tag type must not be empty or contain ':' or space";
+
+/// Tag type name in lowercase. `None` if it is empty or has ':' or space, since it is used as namespace in searches.
+fn type_name(name: &str) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return None;
+    }
+    Some(name)
+}
+
+#[get("type/delete")]
+async fn delete_type(db_pool: Data<DBPool>, params: Query<DeleteRequest>) -> impl Responder {
+    let mut err_str = String::new();
+    for id in params.ids.iter() {
+        if let Err(e) = db::tag::delete_type(&db_pool.sqlite_pool, *id).await {
+            err_str.push_str(&format!("{e}\n"));
+        }
+    }
+
+    let err = if err_str.is_empty() { None } else { Some(err_str) };
+    web::Json(CommonResponse {
+        err,
+        ..Default::default()
+    })
+}
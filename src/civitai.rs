@@ -1,12 +1,15 @@
 use crate::api::TRASH_DIR;
 use crate::config::Config;
+use crate::disk::SpaceLimit;
+use crate::{a1111, importer};
 use actix_web_lab::__reexports::futures_util::StreamExt;
 use jwalk::{Parallelism, WalkDir};
-use reqwest::Client;
+use reqwest::{Client, StatusCode, Url};
 use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use serde::Deserialize;
 use serde_json::{Value, to_string_pretty};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
@@ -18,6 +21,30 @@ use tokio::sync::Semaphore;
 use tracing::{error, info};
 
 pub const PREVIEW_EXT: &str = "jpeg";
+/// Extensions of preview images next to models, in order of preference. Previews imported from other tools keep the
+/// type of their image.
+pub const PREVIEW_IMAGE_EXTS: [&str; 5] = [PREVIEW_EXT, "jpg", "png", "webp", "gif"];
+/// Directory in base path where earlier versions of `.json` and `.model.json` are kept when sync overwrites them
+pub const ARCHIVE_DIR: &str = ".civitai_archive";
+
+/// Error of sync when a model which was on Civitai is not found anymore. The info files are kept as they are.
+#[derive(Debug)]
+pub struct RemovedUpstream;
+
+impl fmt::Display for RemovedUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Model was removed from Civitai")
+    }
+}
+
+impl std::error::Error for RemovedUpstream {}
+
+/// Models whose info was downloaded from Civitai by [`update_model_info`]
+#[derive(Default)]
+pub struct SyncReport {
+    pub found: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
 
 #[derive(PartialEq)]
 pub enum FileType {
@@ -47,7 +74,7 @@ pub struct CivitaiEnums {
     pub _base_model_type: Vec<String>,
 }
 
-pub async fn update_model_info(config: &Config) -> anyhow::Result<()> {
+pub async fn update_model_info(config: &Config) -> anyhow::Result<SyncReport> {
     let valid_ext = config.extensions.iter().collect::<HashSet<_>>();
     let client = Client::new();
     let mut headers = HeaderMap::new();
@@ -78,11 +105,12 @@ pub async fn update_model_info(config: &Config) -> anyhow::Result<()> {
 
                     let handle = tokio::spawn(async move {
                         info!("Update model info: {}", entry.path().display());
-                        if let Ok(_permit) = semaphore.acquire().await {
-                            if let Err(e) = get_item_info(&path, &client, &headers, None, &config).await {
-                                error!("Failed to get model info {}: {}", &path.display(), e);
-                            }
+                        let _permit = semaphore.acquire().await?;
+                        let res = get_item_info(&path, &client, &headers, None, &config).await;
+                        if let Err(e) = &res {
+                            error!("Failed to get model info {}: {}", &path.display(), e);
                         }
+                        anyhow::Ok((path, res))
                     });
                     handles.push(handle);
                 }
@@ -90,50 +118,74 @@ pub async fn update_model_info(config: &Config) -> anyhow::Result<()> {
         }
     }
 
+    let mut report = SyncReport::default();
     for handle in handles {
-        if let Err(e) = handle.await {
-            error!("Failed to sync Civitai: {}", e);
+        match handle.await {
+            Ok(Ok((path, Ok(true)))) => report.found.push(path),
+            Ok(Ok((path, Err(e)))) if e.is::<RemovedUpstream>() => report.removed.push(path),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Failed to sync Civitai: {}", e),
+            Err(e) => error!("Failed to sync Civitai: {}", e),
         }
     }
 
     info!("Finished sync Civitai");
 
-    Ok(())
+    Ok(report)
 }
 
+/// Download Civitai info of model, its `.model.json` and preview.
+/// Return true if the info was downloaded, false if the `.json` was kept.
 pub async fn get_item_info(
     path: &Path,
     client: &Client,
     headers: &HeaderMap,
     blake3: Option<String>,
     config: &Config,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<bool> {
     let info: Value;
     let mut json_path = PathBuf::from(path);
     json_path.set_extension("json");
 
-    if !json_path.exists() || config.civitai.overwrite_json {
+    if let Err(e) = importer::import(path).await {
+        error!("Failed to import model info of {}: {}", path.display(), e);
+    }
+    // `.json` may only have A1111 user metadata
+    let current: Value = File::open(&json_path)
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default();
+
+    let is_fetched = !importer::is_civitai_info(&current) || config.civitai.overwrite_json;
+    if is_fetched {
         let hash = blake3.unwrap_or(calculate_blake3(path)?);
         let url = format!("https://civitai.com/api/v1/model-versions/by-hash/{hash}");
-        info = client.get(url).headers(headers.clone()).send().await?.json().await?;
-        if let Some(err) = info["error"].as_str() {
-            if !err.is_empty() {
-                return Err(anyhow::anyhow!(err.to_string()));
-            }
+        let response = client.get(url).headers(headers.clone()).send().await?;
+        if response.status() == StatusCode::NOT_FOUND && importer::is_civitai_info(&current) {
+            return Err(RemovedUpstream.into());
         }
-        save_info(&json_path, &info).await?;
+        let mut fetched: Value = response.json().await?;
+        if let Some(err) = fetched["error"].as_str()
+            && !err.is_empty()
+        {
+            return Err(anyhow::anyhow!(err.to_string()));
+        }
+        a1111::keep_metadata(&current, &mut fetched);
+        archive_info(&json_path, &fetched, &config.model_paths).await?;
+        save_info(&json_path, &fetched).await?;
+        info = fetched;
     } else {
         info!("File already exists: {}", json_path.display());
-        info = serde_json::from_reader(File::open(&json_path)?)?;
+        info = current;
     }
 
     if let Some(model_id) = info["modelId"].as_i64() {
-        get_model_info(path, client, headers, model_id, config.civitai.overwrite_json).await?;
+        get_model_info(path, client, headers, model_id, config).await?;
     }
 
     download_preview(client, headers, config, &info, path).await?;
 
-    Ok(())
+    Ok(is_fetched)
 }
 
 async fn get_model_info(
@@ -141,25 +193,46 @@ async fn get_model_info(
     client: &Client,
     headers: &HeaderMap,
     model_id: i64,
-    overwrite: bool,
+    config: &Config,
 ) -> anyhow::Result<()> {
-    let info: Value;
     let mut json_path = PathBuf::from(path);
     json_path.set_extension("model.json");
-    if !json_path.exists() || overwrite {
+    if !json_path.exists() || config.civitai.overwrite_json {
         let url = format!("https://civitai.com/api/v1/models/{model_id}");
-        // TODO: clean code
-        info = client.get(url).headers(headers.clone()).send().await?.json().await?;
-        if let Some(err) = info["error"].as_str() {
-            if !err.is_empty() {
-                return Err(anyhow::anyhow!(err.to_string()));
-            }
+        let response = client.get(url).headers(headers.clone()).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(RemovedUpstream.into());
+        }
+        let info: Value = response.json().await?;
+        if let Some(err) = info["error"].as_str()
+            && !err.is_empty()
+        {
+            return Err(anyhow::anyhow!(err.to_string()));
         }
+        archive_info(&json_path, &info, &config.model_paths).await?;
         save_info(&json_path, &info).await?;
     }
     Ok(())
 }
 
+/// Newest models of Civitai user, newest first
+pub async fn get_creator_models(client: &Client, headers: &HeaderMap, username: &str) -> anyhow::Result<Vec<Value>> {
+    let url = Url::parse_with_params(
+        "https://civitai.com/api/v1/models",
+        &[("username", username), ("sort", "Newest"), ("limit", "20")],
+    )?;
+    let info: Value = client.get(url).headers(headers.clone()).send().await?.json().await?;
+    if let Some(err) = info["error"].as_str()
+        && !err.is_empty()
+    {
+        return Err(anyhow::anyhow!(err.to_string()));
+    }
+    Ok(info["items"].as_array().cloned().unwrap_or_default())
+}
+
+/// Download `url` to `path`. If `limit` is set, the download is refused before anything is written when its
+/// Content-Length does not fit. An existing file with another hash is moved to trash once the download has started.
+#[allow(clippy::too_many_arguments)]
 pub async fn download_file(
     url: &str,
     path: &Path,
@@ -168,38 +241,24 @@ pub async fn download_file(
     base_paths: &HashMap<String, String>,
     blake3: &str,
     max_retry: usize,
+    limit: Option<&SpaceLimit>,
 ) -> anyhow::Result<()> {
+    let mut is_replaced = false;
     if path.exists() {
         if let Ok(file_hash) = calculate_blake3(path)
             && blake3 == file_hash
         {
             info!("File already exists with same hash: {}", path.display());
             return Ok(());
-        } else {
-            let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
-            let mut trash_path = PathBuf::from(path.parent().unwrap_or(Path::new("."))).join(TRASH_DIR);
-            for (_, base_path) in base_paths.iter() {
-                if path.starts_with(base_path) {
-                    trash_path = PathBuf::from(base_path).join(TRASH_DIR);
-                }
-            }
-            if fs::create_dir_all(&trash_path).await.is_ok() {
-                let mut new_name = PathBuf::from(path);
-                new_name.set_extension(format!(
-                    "{}.bakup.{}",
-                    path.extension().unwrap_or_default().to_str().unwrap_or_default(),
-                    timestamp
-                ));
-                trash_path = trash_path.join(new_name.file_name().unwrap_or_default());
-                fs::rename(path, trash_path).await?;
-            }
         }
+        is_replaced = true;
     }
 
     let mut hasher = blake3::Hasher::new();
     let mut downloaded_bytes = 0;
     let mut retried = 0;
-    let mut file = File::create(path)?;
+    let mut file: Option<File> = None;
+    let mut reservation = None;
     let mut range_headers = headers.clone();
     let mut err_msg = String::new();
     loop {
@@ -209,12 +268,40 @@ pub async fn download_file(
                     err_msg = response.text().await.unwrap_or_default();
                     error!("Request failed: {}", &err_msg);
                 } else {
+                    // Remaining bytes when the download is resumed, so written bytes are already used space
+                    if let Some(limit) = limit
+                        && let Some(len) = response.content_length()
+                    {
+                        // Bytes of the previous response are written or will not be
+                        drop(reservation.take());
+                        match limit.reserve(len) {
+                            Ok(reserved) => reservation = Some(reserved),
+                            Err(e) => {
+                                if downloaded_bytes == 0 && file.take().is_some() {
+                                    let _ = std::fs::remove_file(path);
+                                }
+                                return Err(e);
+                            }
+                        }
+                    }
+                    let file = match file.as_mut() {
+                        Some(file) => file,
+                        None => {
+                            if is_replaced {
+                                move_to_trash(path, base_paths).await?;
+                            }
+                            file.insert(File::create(path)?)
+                        }
+                    };
                     let mut stream = response.bytes_stream();
                     while let Some(chunk_result) = stream.next().await {
-                        if let Ok(chunk) = chunk_result {
-                            if file.write_all(&chunk).is_ok() {
-                                hasher.update(&chunk);
-                                downloaded_bytes += chunk.len();
+                        if let Ok(chunk) = chunk_result
+                            && file.write_all(&chunk).is_ok()
+                        {
+                            hasher.update(&chunk);
+                            downloaded_bytes += chunk.len();
+                            if let Some(reservation) = reservation.as_mut() {
+                                reservation.written(chunk.len() as u64);
                             }
                         }
                     }
@@ -237,11 +324,45 @@ pub async fn download_file(
         retried += 1;
         range_headers.insert("Range", HeaderValue::from_str(&format!("bytes={}-", downloaded_bytes))?);
     }
+    // Nothing is received, existing file is kept
+    let Some(mut file) = file else {
+        return Err(anyhow::anyhow!(err_msg));
+    };
     file.flush()?;
     info!("Finish downloading: {}", path.display());
     Ok(())
 }
 
+/// Move file to the trash directory of its base path, with a timestamp in its name
+async fn move_to_trash(path: &Path, base_paths: &HashMap<String, String>) -> anyhow::Result<()> {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+    let mut trash_path = PathBuf::from(path.parent().unwrap_or(Path::new("."))).join(TRASH_DIR);
+    for (_, base_path) in base_paths.iter() {
+        if path.starts_with(base_path) {
+            trash_path = PathBuf::from(base_path).join(TRASH_DIR);
+        }
+    }
+    if fs::create_dir_all(&trash_path).await.is_ok() {
+        let mut new_name = PathBuf::from(path);
+        new_name.set_extension(format!(
+            "{}.bakup.{}",
+            path.extension().unwrap_or_default().to_str().unwrap_or_default(),
+            timestamp
+        ));
+        trash_path = trash_path.join(new_name.file_name().unwrap_or_default());
+        fs::rename(path, trash_path).await?;
+    }
+    Ok(())
+}
+
+/// Preview image of the model, `None` if it has none
+pub fn find_preview(model_path: &Path) -> Option<PathBuf> {
+    PREVIEW_IMAGE_EXTS
+        .iter()
+        .map(|ext| model_path.with_extension(ext))
+        .find(|preview| preview.exists())
+}
+
 async fn download_preview(
     client: &Client,
     headers: &HeaderMap,
@@ -268,6 +389,7 @@ async fn download_preview(
                         &config.model_paths,
                         "",
                         config.civitai.max_retries,
+                        None,
                     )
                     .await?;
                 }
@@ -291,6 +413,45 @@ async fn download_preview(
     Ok(())
 }
 
+/// Copy `info_file` to [`ARCHIVE_DIR`] of its base path before it is overwritten by `info`, as
+/// `name.json.<timestamp>`. Nothing is copied if only the download and rating stats changed.
+async fn archive_info(info_file: &Path, info: &Value, base_paths: &HashMap<String, String>) -> anyhow::Result<()> {
+    let Ok(old) = fs::read_to_string(info_file).await else {
+        return Ok(());
+    };
+    if let Ok(old) = serde_json::from_str::<Value>(&old)
+        && without_stats(&old) == without_stats(info)
+    {
+        return Ok(());
+    }
+
+    let mut archive_dir = PathBuf::from(info_file.parent().unwrap_or(Path::new("."))).join(ARCHIVE_DIR);
+    for (_, base_path) in base_paths.iter() {
+        if info_file.starts_with(base_path) {
+            archive_dir = PathBuf::from(base_path).join(ARCHIVE_DIR);
+        }
+    }
+    fs::create_dir_all(&archive_dir).await?;
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+    let file_name = info_file.file_name().unwrap_or_default().to_string_lossy();
+    fs::write(archive_dir.join(format!("{file_name}.{timestamp}")), old).await?;
+    Ok(())
+}
+
+/// Civitai info without `stats` of model and its versions, which change on every sync
+fn without_stats(info: &Value) -> Value {
+    let mut info = info.clone();
+    if let Some(info) = info.as_object_mut() {
+        info.remove("stats");
+    }
+    for version in info["modelVersions"].as_array_mut().into_iter().flatten() {
+        if let Some(version) = version.as_object_mut() {
+            version.remove("stats");
+        }
+    }
+    info
+}
+
 async fn save_info(info_file: &Path, info: &Value) -> anyhow::Result<()> {
     if !info_file.extension().unwrap_or_default().eq("json") {
         return Err(anyhow::anyhow!("Invalid json extension. Do you save to wrong file?"));
@@ -362,6 +523,33 @@ pub async fn file_type(path: &Path) -> FileType {
     FileType::NA
 }
 
+/// Remove HTML tags from Civitai description and decode common entities
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            }
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
 pub fn get_extension_from_url(url: &str) -> Option<String> {
     url.split('/')
         .next_back()
@@ -13,6 +13,7 @@ const DEFAULT_SQLITE_PATH: &str = "sdmm.sqlite";
 
 const DEFAULT_API_PER_PAGE: u32 = 20;
 const DEFAULT_PARALLEL: usize = 8;
+const DEFAULT_DISK_RESERVE_MB: u64 = 1024;
 
 #[derive(Deserialize, Debug, Serialize, Clone)]
 pub struct SQLiteConfig {
@@ -92,6 +93,9 @@ pub struct CivitaiConfig {
     pub base_models: Vec<String>,
     #[serde(default)]
     pub types: Vec<String>,
+    /// Hours between checks of new models from followed creators. 0 to disable.
+    #[serde(default)]
+    pub check_followed_hours: u64,
 }
 
 impl Default for CivitaiConfig {
@@ -105,6 +109,51 @@ impl Default for CivitaiConfig {
             search: CivitaiSearch::default(),
             base_models: Vec::new(),
             types: Vec::new(),
+            check_followed_hours: 24,
+        }
+    }
+}
+
+/// What to do when `.sdmm.json` sidecar and database are different
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+pub enum SidecarPolicy {
+    /// Add tags from sidecar. Note and rating are only used if they are empty in database.
+    #[default]
+    Merge,
+    /// Only use sidecar for items without any tag, note or rating in database
+    PreferDatabase,
+    /// Replace tags, note and rating in database by sidecar
+    PreferSidecar,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SidecarConfig {
+    /// Write `.sdmm.json` next to model when its tags, note or rating is changed
+    #[serde(default)]
+    pub write: bool,
+    #[serde(default)]
+    pub policy: SidecarPolicy,
+    /// Write A1111/Forge user metadata into `.json` of LoRAs and embeddings after syncing Civitai, and when trigger words
+    /// or preferred weight is changed
+    #[serde(default)]
+    pub a1111: bool,
+}
+
+/// Free space and quotas which are checked before a model is downloaded
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DiskConfig {
+    /// MB which must stay free on the file system of a download
+    pub reserve_mb: u64,
+    /// Max MB of model files in each collection, by label of `model_paths`. Collections without quota are unlimited.
+    pub quota_mb: HashMap<String, u64>,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        Self {
+            reserve_mb: DEFAULT_DISK_RESERVE_MB,
+            quota_mb: HashMap::new(),
         }
     }
 }
@@ -125,6 +174,10 @@ pub struct Config {
     pub parallel: usize,
     #[serde(default)]
     pub extensions: HashSet<String>,
+    #[serde(default)]
+    pub sidecar: SidecarConfig,
+    #[serde(default)]
+    pub disk: DiskConfig,
 }
 
 impl Default for Config {
@@ -144,6 +197,8 @@ impl Default for Config {
             db: DBConfig::default(),
             api: APIConfig::default(),
             civitai: CivitaiConfig::default(),
+            sidecar: SidecarConfig::default(),
+            disk: DiskConfig::default(),
         }
     }
 }
@@ -3,6 +3,15 @@
 pub mod item;
 pub mod tag;
 pub mod job;
+pub mod query;
+pub mod saved_search;
+pub mod collection;
+pub mod history;
+pub mod catalog;
+pub mod model_group;
+pub mod creator;
+pub mod item_link;
+pub mod stats;
 
 use crate::config::DBConfig;
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
@@ -25,3 +34,50 @@ impl DBPool {
         Ok(Self { sqlite_pool })
     }
 }
+
+/// Migrated in-memory database. One connection, as every connection would get its own database.
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    let opts = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .foreign_keys(true);
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(opts)
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}
+
+/// Empty temporary directory of a test, removed when dropped
+#[cfg(test)]
+pub struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("sdmm-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,657 @@
+//! Export and import of user-curated data: tags with description, implications and aliases,
+//! and tags, note, favorite and rating of items.
+//!
+//! Items are keyed by BLAKE3 hash, so catalog can be imported into another instance whose collection labels and
+//! folder layout are different. Items without a matching hash are matched by collection label and relative path.
+
+use crate::db::history::{item_state, record_item_changes};
+use crate::db::tag::{USER_TAG_TYPE, add_tag_item, find_tag_id, get_or_create_tag, implies, split_namespace};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+pub const CATALOG_VERSION: i64 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Catalog {
+    pub version: i64,
+    pub tags: Vec<CatalogTag>,
+    pub items: Vec<CatalogItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CatalogTag {
+    pub name: String,
+    #[serde(default)]
+    pub tag_type: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    /// Implied tags
+    #[serde(default)]
+    pub deps: Vec<String>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CatalogItem {
+    pub blake3: String,
+    /// Path relative to collection
+    pub path: String,
+    #[serde(default)]
+    pub base_label: String,
+    /// Tags in form `namespace:name`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub note: String,
+    #[serde(default)]
+    pub favorite: bool,
+    #[serde(default)]
+    pub rating: Option<i64>,
+}
+
+/// One line of NDJSON catalog
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CatalogRecord {
+    Tag(CatalogTag),
+    Item(CatalogItem),
+}
+
+#[derive(Serialize)]
+pub struct Conflict {
+    /// What is conflicted, e.g. `note`, `rating`, `description`, `tag_type`, `alias`, `implication`, `collection`
+    pub field: String,
+    /// Item path or tag name
+    pub key: String,
+    pub local: String,
+    pub imported: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub tags_created: i64,
+    pub items_matched: i64,
+    /// Path of items which are not found in this database
+    pub items_missing: Vec<String>,
+    /// Local value is kept for conflicts
+    pub conflicts: Vec<Conflict>,
+    /// Items whose tags, note, rating or favorite are changed
+    #[serde(skip)]
+    pub updated_items: Vec<i64>,
+}
+
+impl Catalog {
+    /// Parse JSON document or NDJSON records
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        if let Ok(catalog) = serde_json::from_str::<Catalog>(content) {
+            return Ok(catalog);
+        }
+
+        let mut catalog = Catalog {
+            version: CATALOG_VERSION,
+            tags: Vec::new(),
+            items: Vec::new(),
+        };
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<CatalogRecord>(line) {
+                Ok(CatalogRecord::Tag(tag)) => catalog.tags.push(tag),
+                Ok(CatalogRecord::Item(item)) => catalog.items.push(item),
+                Err(e) => return Err(anyhow::anyhow!("Invalid catalog at line {}: {}", i + 1, e)),
+            }
+        }
+        Ok(catalog)
+    }
+
+    pub fn to_ndjson(&self) -> anyhow::Result<String> {
+        let mut ret = String::new();
+        for tag in self.tags.iter() {
+            ret.push_str(&serde_json::to_string(&RecordRef::Tag(tag))?);
+            ret.push('\n');
+        }
+        for item in self.items.iter() {
+            ret.push_str(&serde_json::to_string(&RecordRef::Item(item))?);
+            ret.push('\n');
+        }
+        Ok(ret)
+    }
+}
+
+/// Borrowed `CatalogRecord` for serializing
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RecordRef<'a> {
+    Tag(&'a CatalogTag),
+    Item(&'a CatalogItem),
+}
+
+pub async fn export(pool: &SqlitePool) -> Result<Catalog, sqlx::Error> {
+    let tags = sqlx::query!(
+        r#"SELECT tag.name as name, tag_type.type as "tag_type?", tag.description as description,
+            (SELECT GROUP_CONCAT(dep.name, ' ') FROM tag_tag JOIN tag AS dep ON dep.id = tag_tag.dep
+                WHERE tag_tag.tag = tag.id) as "deps?: String",
+            (SELECT GROUP_CONCAT(alias, ' ') FROM tag_alias WHERE tag_alias.tag = tag.id) as "aliases?: String"
+        FROM tag
+        LEFT JOIN tag_type ON tag_type.id = tag.type
+        ORDER BY tag.name"#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CatalogTag {
+        name: row.name,
+        tag_type: row.tag_type,
+        description: row.description,
+        deps: split(row.deps),
+        aliases: split(row.aliases),
+    })
+    .collect();
+
+    let items = sqlx::query!(
+        r#"SELECT item.blake3 as blake3, item.path as path, item.base_label as base_label, item.note as note,
+            item.favorite as "favorite: bool", item.rating as rating,
+            (SELECT GROUP_CONCAT(IFNULL(tag_type.type || ':', '') || tag.name, ' ')
+                FROM tag_item
+                JOIN tag ON tag.id = tag_item.tag
+                LEFT JOIN tag_type ON tag_type.id = tag.type
+                WHERE tag_item.item = item.id) as "tags?: String"
+        FROM item
+        WHERE item.is_checked = true
+        ORDER BY item.base_label, item.path"#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CatalogItem {
+        blake3: row.blake3,
+        path: row.path,
+        base_label: row.base_label,
+        tags: split(row.tags),
+        note: row.note,
+        favorite: row.favorite,
+        rating: row.rating,
+    })
+    .collect();
+
+    Ok(Catalog {
+        version: CATALOG_VERSION,
+        tags,
+        items,
+    })
+}
+
+fn split(s: Option<String>) -> Vec<String> {
+    s.unwrap_or_default()
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Merge catalog into database. Nothing is written if `dry_run` is set.
+///
+/// Tags and implications are added, missing tag types and empty descriptions, notes and ratings are filled.
+/// Different non-empty values are reported as conflicts and local values are kept.
+/// Changed tags and notes are recorded in history by `user`.
+pub async fn import(pool: &SqlitePool, user: &str, catalog: &Catalog, dry_run: bool) -> anyhow::Result<ImportReport> {
+    let mut report = ImportReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut tx = pool.begin().await?;
+
+    for tag in catalog.tags.iter() {
+        import_tag(&mut tx, tag, &mut report).await?;
+    }
+    for item in catalog.items.iter() {
+        import_item(&mut tx, user, item, &mut report).await?;
+    }
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(report)
+}
+
+async fn import_tag(conn: &mut SqliteConnection, tag: &CatalogTag, report: &mut ImportReport) -> anyhow::Result<()> {
+    let name = tag.name.to_lowercase();
+    let full_name = match tag.tag_type.as_ref() {
+        Some(tag_type) => format!("{tag_type}:{name}"),
+        None => name.clone(),
+    };
+    if let Some(tag_type) = tag.tag_type.as_ref() {
+        sqlx::query!("INSERT OR IGNORE INTO tag_type (type) VALUES (?)", tag_type)
+            .execute(&mut *conn)
+            .await?;
+    }
+    let tag_id = match find_tag_id(conn, &name).await? {
+        Some(tag_id) => {
+            import_tag_type(conn, tag_id, &name, tag.tag_type.as_deref(), report).await?;
+            tag_id
+        }
+        None => {
+            report.tags_created += 1;
+            get_or_create_tag(conn, &full_name, None).await?
+        }
+    };
+
+    let description = sqlx::query_scalar!("SELECT description FROM tag WHERE id = ?", tag_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if description.is_empty() {
+        sqlx::query!("UPDATE tag SET description = ? WHERE id = ?", tag.description, tag_id)
+            .execute(&mut *conn)
+            .await?;
+    } else if !tag.description.is_empty() && description != tag.description {
+        report.conflicts.push(Conflict {
+            field: "description".to_string(),
+            key: name.clone(),
+            local: description,
+            imported: tag.description.clone(),
+        });
+    }
+
+    for alias in tag.aliases.iter() {
+        let alias = alias.to_lowercase();
+        match find_tag_id(conn, &alias).await? {
+            None => {
+                sqlx::query!("INSERT INTO tag_alias (alias, tag) VALUES (?, ?)", alias, tag_id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            Some(id) if id == tag_id => {}
+            Some(_) => report.conflicts.push(Conflict {
+                field: "alias".to_string(),
+                key: name.clone(),
+                local: format!("{alias} is another tag"),
+                imported: alias,
+            }),
+        }
+    }
+
+    for dep in tag.deps.iter() {
+        let dep_id = get_or_create_tag(conn, &dep.to_lowercase(), None).await?;
+        if dep_id == tag_id {
+            continue;
+        }
+        if implies(conn, dep_id, tag_id).await? {
+            report.conflicts.push(Conflict {
+                field: "implication".to_string(),
+                key: name.clone(),
+                local: format!("{dep} implies {name}"),
+                imported: format!("{name} implies {dep}"),
+            });
+            continue;
+        }
+        sqlx::query!("INSERT OR IGNORE INTO tag_tag (tag, dep) VALUES (?, ?)", tag_id, dep_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Set type of existing tag if it has none. A different local type is reported as a conflict and kept.
+async fn import_tag_type(
+    conn: &mut SqliteConnection,
+    tag_id: i64,
+    name: &str,
+    tag_type: Option<&str>,
+    report: &mut ImportReport,
+) -> Result<(), sqlx::Error> {
+    let Some(tag_type) = tag_type else {
+        return Ok(());
+    };
+    let local = sqlx::query_scalar!(
+        r#"SELECT tag_type.type as "tag_type?" FROM tag LEFT JOIN tag_type ON tag_type.id = tag.type WHERE tag.id = ?"#,
+        tag_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    match local {
+        None => {
+            sqlx::query!(
+                "UPDATE tag SET type = (SELECT id FROM tag_type WHERE type = ?) WHERE id = ?",
+                tag_type,
+                tag_id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        Some(local) if local != tag_type => {
+            // Same tag may be on many imported items
+            if !report.conflicts.iter().any(|c| c.field == "tag_type" && c.key == name) {
+                report.conflicts.push(Conflict {
+                    field: "tag_type".to_string(),
+                    key: name.to_string(),
+                    local,
+                    imported: tag_type.to_string(),
+                });
+            }
+        }
+        Some(_) => {}
+    }
+    Ok(())
+}
+
+/// Tag of imported item to add. Namespace of an existing tag is removed, so its local type is kept.
+async fn import_item_tag(
+    conn: &mut SqliteConnection,
+    tag: &str,
+    report: &mut ImportReport,
+) -> Result<String, sqlx::Error> {
+    let tag = tag.to_lowercase();
+    let (name, type_id) = split_namespace(conn, &tag).await?;
+    if type_id.is_none() {
+        return Ok(tag);
+    }
+    let Some(tag_id) = find_tag_id(conn, &name).await? else {
+        return Ok(tag);
+    };
+    let tag_type = tag.split_once(':').map(|(namespace, _)| namespace);
+    import_tag_type(conn, tag_id, &name, tag_type, report).await?;
+    Ok(name)
+}
+
+async fn import_item(
+    conn: &mut SqliteConnection,
+    user: &str,
+    item: &CatalogItem,
+    report: &mut ImportReport,
+) -> anyhow::Result<()> {
+    let Some(local) = find_item(conn, item).await? else {
+        // Same path in another collection may be a different model
+        match other_collection(conn, item).await? {
+            Some(label) => report.conflicts.push(Conflict {
+                field: "collection".to_string(),
+                key: item.path.clone(),
+                local: label,
+                imported: item.base_label.clone(),
+            }),
+            None => report.items_missing.push(item.path.clone()),
+        }
+        return Ok(());
+    };
+    report.items_matched += 1;
+
+    let before = item_state(conn, local.id).await?;
+    let mut is_updated = false;
+    let mut tags = Vec::new();
+    for tag in item.tags.iter() {
+        tags.push(import_item_tag(conn, tag, report).await?);
+    }
+    add_tag_item(conn, local.id, &tags, Some(USER_TAG_TYPE)).await?;
+
+    if local.note.is_empty() {
+        sqlx::query!("UPDATE item SET note = ? WHERE id = ?", item.note, local.id)
+            .execute(&mut *conn)
+            .await?;
+    } else if !item.note.is_empty() && local.note != item.note {
+        report.conflicts.push(Conflict {
+            field: "note".to_string(),
+            key: item.path.clone(),
+            local: local.note,
+            imported: item.note.clone(),
+        });
+    }
+
+    match (local.rating, item.rating) {
+        (None, Some(rating)) => {
+            sqlx::query!("UPDATE item SET rating = ? WHERE id = ?", rating, local.id)
+                .execute(&mut *conn)
+                .await?;
+            is_updated = true;
+        }
+        (Some(local_rating), Some(rating)) if local_rating != rating => report.conflicts.push(Conflict {
+            field: "rating".to_string(),
+            key: item.path.clone(),
+            local: local_rating.to_string(),
+            imported: rating.to_string(),
+        }),
+        _ => {}
+    }
+
+    if item.favorite {
+        is_updated |= sqlx::query!(
+            "UPDATE item SET favorite = true WHERE id = ? AND favorite = false",
+            local.id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+            > 0;
+    }
+
+    let after = item_state(conn, local.id).await?;
+    if is_updated || after.tags != before.tags || after.note != before.note {
+        report.updated_items.push(local.id);
+    }
+    record_item_changes(conn, user, local.id, &before).await?;
+    Ok(())
+}
+
+struct LocalItem {
+    id: i64,
+    note: String,
+    rating: Option<i64>,
+}
+
+/// Find item by hash, or by collection label and relative path if hash is not found
+async fn find_item(conn: &mut SqliteConnection, item: &CatalogItem) -> Result<Option<LocalItem>, sqlx::Error> {
+    let blake3 = item.blake3.to_lowercase();
+    if !blake3.is_empty()
+        && let Some(local) = sqlx::query_as!(
+            LocalItem,
+            "SELECT id, note, rating FROM item WHERE is_checked = true AND blake3 = ? ORDER BY id LIMIT 1",
+            blake3
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+    {
+        return Ok(Some(local));
+    }
+
+    sqlx::query_as!(
+        LocalItem,
+        r#"SELECT id, note, rating FROM item
+        WHERE is_checked = true AND path = ? AND base_label = ?
+        ORDER BY id
+        LIMIT 1"#,
+        item.path,
+        item.base_label
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+/// Label of another collection which has an item at the relative path of `item`
+async fn other_collection(conn: &mut SqliteConnection, item: &CatalogItem) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT base_label FROM item WHERE is_checked = true AND path = ? AND base_label != ? ORDER BY id LIMIT 1",
+        item.path,
+        item.base_label
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    fn catalog() -> Catalog {
+        Catalog {
+            version: CATALOG_VERSION,
+            tags: vec![CatalogTag {
+                name: "Anime".to_string(),
+                tag_type: Some("style".to_string()),
+                description: "Drawn".to_string(),
+                deps: vec!["2d".to_string()],
+                aliases: vec!["animestyle".to_string(), "local".to_string()],
+            }],
+            items: vec![
+                CatalogItem {
+                    blake3: "ABC".to_string(),
+                    path: "other/a.safetensors".to_string(),
+                    base_label: "other".to_string(),
+                    tags: vec!["style:anime".to_string(), "mine".to_string()],
+                    note: "imported".to_string(),
+                    favorite: true,
+                    rating: Some(4),
+                },
+                CatalogItem {
+                    blake3: String::new(),
+                    path: "b.safetensors".to_string(),
+                    base_label: "c".to_string(),
+                    tags: Vec::new(),
+                    note: "imported".to_string(),
+                    favorite: false,
+                    rating: Some(2),
+                },
+                CatalogItem {
+                    blake3: "def".to_string(),
+                    path: "c.safetensors".to_string(),
+                    base_label: "other".to_string(),
+                    tags: Vec::new(),
+                    note: String::new(),
+                    favorite: false,
+                    rating: None,
+                },
+                CatalogItem {
+                    blake3: "123".to_string(),
+                    path: "missing.safetensors".to_string(),
+                    base_label: "c".to_string(),
+                    tags: Vec::new(),
+                    note: String::new(),
+                    favorite: false,
+                    rating: None,
+                },
+            ],
+        }
+    }
+
+    /// Item 1 matches by hash, item 2 by path with a note and rating, item 3 is at a path of another collection
+    async fn local_items(pool: &SqlitePool) {
+        sqlx::query(
+            r#"INSERT INTO item (id, path, base_label, blake3, note, rating) VALUES
+                (1, 'a.safetensors', 'c', 'abc', '', NULL),
+                (2, 'b.safetensors', 'c', '', 'local', 3),
+                (3, 'c.safetensors', 'c', '', '', NULL)"#,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        get_or_create_tag(&mut pool.acquire().await.unwrap(), "local", None)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_json_and_ndjson() {
+        let json = serde_json::to_string(&catalog()).unwrap();
+        let parsed = Catalog::parse(&json).unwrap();
+        assert_eq!((parsed.tags.len(), parsed.items.len()), (1, 4));
+
+        let ndjson = format!("\n{}\n", catalog().to_ndjson().unwrap());
+        let parsed = Catalog::parse(&ndjson).unwrap();
+        assert_eq!(parsed.tags[0].aliases, vec!["animestyle", "local"]);
+        assert_eq!(parsed.items[0].tags, vec!["style:anime", "mine"]);
+        assert_eq!(parsed.items[3].path, "missing.safetensors");
+    }
+
+    #[test]
+    fn parse_reports_invalid_line() {
+        let ndjson = "{\"kind\":\"tag\",\"name\":\"a\"}\n{\"kind\":\"item\"}\n";
+        let err = Catalog::parse(ndjson).err().unwrap().to_string();
+        assert!(err.starts_with("Invalid catalog at line 2"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn import_fills_empty_values_and_reports_conflicts() {
+        let pool = test_pool().await;
+        local_items(&pool).await;
+        let report = import(&pool, "bob", &catalog(), false).await.unwrap();
+
+        assert_eq!(report.tags_created, 1);
+        assert_eq!(report.items_matched, 2);
+        assert_eq!(report.items_missing, vec!["missing.safetensors"]);
+        assert_eq!(report.updated_items, vec![1]);
+        let conflicts = report
+            .conflicts
+            .iter()
+            .map(|c| (c.field.as_str(), c.key.as_str(), c.local.as_str(), c.imported.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            conflicts,
+            vec![
+                ("alias", "anime", "local is another tag", "local"),
+                ("note", "b.safetensors", "local", "imported"),
+                ("rating", "b.safetensors", "3", "2"),
+                ("collection", "c.safetensors", "c", "other"),
+            ]
+        );
+
+        let mut conn = pool.acquire().await.unwrap();
+        let anime = find_tag_id(&mut conn, "anime").await.unwrap().unwrap();
+        assert_eq!(find_tag_id(&mut conn, "animestyle").await.unwrap(), Some(anime));
+        let two_d = find_tag_id(&mut conn, "2d").await.unwrap().unwrap();
+        assert!(implies(&mut conn, anime, two_d).await.unwrap());
+
+        let state = item_state(&mut conn, 1).await.unwrap();
+        assert_eq!(
+            (state.tags.as_str(), state.note.as_str()),
+            ("2d style:anime user:mine", "imported")
+        );
+        let (rating, favorite): (Option<i64>, bool) = sqlx::query_as("SELECT rating, favorite FROM item WHERE id = 1")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!((rating, favorite), (Some(4), true));
+        let history: Vec<(i64, String)> =
+            sqlx::query_as("SELECT item, action FROM history WHERE user = 'bob' ORDER BY id")
+                .fetch_all(&mut *conn)
+                .await
+                .unwrap();
+        assert_eq!(history, vec![(1, "tags".to_string()), (1, "note".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_writes_nothing() {
+        let pool = test_pool().await;
+        local_items(&pool).await;
+        let report = import(&pool, "bob", &catalog(), true).await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.tags_created, 1);
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(find_tag_id(&mut conn, "anime").await.unwrap(), None);
+        let state = item_state(&mut conn, 1).await.unwrap();
+        assert_eq!((state.tags.as_str(), state.note.as_str()), ("", ""));
+    }
+
+    #[tokio::test]
+    async fn import_keeps_local_tag_type() {
+        let pool = test_pool().await;
+        local_items(&pool).await;
+        get_or_create_tag(&mut pool.acquire().await.unwrap(), "character:anime", None)
+            .await
+            .unwrap();
+        let report = import(&pool, "bob", &catalog(), false).await.unwrap();
+
+        let conflicts = report
+            .conflicts
+            .iter()
+            .filter(|c| c.field == "tag_type")
+            .map(|c| (c.key.as_str(), c.local.as_str(), c.imported.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(conflicts, vec![("anime", "character", "style")]);
+        let state = item_state(&mut pool.acquire().await.unwrap(), 1).await.unwrap();
+        assert_eq!(state.tags, "2d character:anime user:mine");
+    }
+}
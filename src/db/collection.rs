@@ -0,0 +1,142 @@
+use crate::db::item::Item;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+/// Manually curated, ordered list of items
+#[derive(Serialize, Deserialize)]
+pub struct Collection {
+    #[serde(default)]
+    pub id: i64,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub count: i64,
+}
+
+pub async fn list(pool: &SqlitePool) -> Result<Vec<Collection>, sqlx::Error> {
+    sqlx::query_as!(
+        Collection,
+        r#"SELECT collection.id as id, title, description, COUNT(collection_item.item) as "count!: i64"
+        FROM collection
+        LEFT JOIN collection_item ON collection_item.collection = collection.id
+        GROUP BY collection.id
+        ORDER BY title"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn add(pool: &SqlitePool, collection: &Collection) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        "INSERT INTO collection (title, description) VALUES (?, ?)",
+        collection.title,
+        collection.description
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn update(pool: &SqlitePool, collection: &Collection) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE collection SET title = ?, description = ? WHERE id = ?",
+        collection.title,
+        collection.description,
+        collection.id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM collection WHERE id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Replace items of collection, keeping the given order
+pub async fn set_items(pool: &SqlitePool, id: i64, items: &[i64]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM collection_item WHERE collection = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    for (position, item) in items.iter().enumerate() {
+        let position = position as i64;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO collection_item (collection, item, position) VALUES (?, ?, ?)",
+            id,
+            item,
+            position
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// Append items to the end of collection. Items already in collection are kept at their position.
+pub async fn add_items(pool: &SqlitePool, id: i64, items: &[i64]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for item in items {
+        sqlx::query!(
+            r#"INSERT OR IGNORE INTO collection_item (collection, item, position)
+            SELECT ?, ?, IFNULL(MAX(position), -1) + 1 FROM collection_item WHERE collection = ?"#,
+            id,
+            item,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+pub async fn remove_items(pool: &SqlitePool, id: i64, items: &[i64]) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM collection_item WHERE collection = ? AND item IN (SELECT value FROM json_each(?))",
+        id,
+        serde_json::json!(items)
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Return items of collection in their order and the total number of items
+pub async fn get_items(pool: &SqlitePool, id: i64, limit: i64, offset: i64) -> Result<(Vec<Item>, i64), sqlx::Error> {
+    let items = sqlx::query_as!(
+        Item,
+        r#"SELECT item.id as id, name, path, base_label, note, NULL as "snippet?: String",
+            favorite as "favorite: bool", rating, last_used_at, use_count, trained_words, preferred_weight, file_size,
+            model_type, base_model, civitai_model_id, civitai_version_id, creator, precision, format, size_type,
+            nsfw as "nsfw: bool", nsfw_level, poi as "poi: bool", allow_no_credit as "allow_no_credit: bool",
+            allow_commercial_use, allow_derivatives as "allow_derivatives: bool",
+            allow_different_license as "allow_different_license: bool", license_changed_at, previous_license,
+            removed_upstream_at, corrupt_at
+        FROM collection_item
+        JOIN item ON item.id = collection_item.item
+        WHERE collection_item.collection = ? AND item.is_checked = true
+        ORDER BY collection_item.position
+        LIMIT ? OFFSET ?"#,
+        id,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!: i64" FROM collection_item
+        JOIN item ON item.id = collection_item.item
+        WHERE collection_item.collection = ? AND item.is_checked = true"#,
+        id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((items, total))
+}
@@ -0,0 +1,103 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+#[derive(Serialize)]
+pub struct CreatorCount {
+    pub creator: String,
+    pub count: i64,
+    pub followed: bool,
+}
+
+#[derive(Serialize)]
+pub struct FollowedCreator {
+    pub username: String,
+    /// Newest Civitai model which is already announced. `None` if creator is not checked yet.
+    pub newest_model_id: Option<i64>,
+    pub checked_at: Option<i64>,
+}
+
+/// Creators of local items with their number of items, most first
+pub async fn list(pool: &SqlitePool) -> Result<Vec<CreatorCount>, sqlx::Error> {
+    sqlx::query_as!(
+        CreatorCount,
+        r#"SELECT creator, COUNT(*) as count,
+            EXISTS (SELECT 1 FROM followed_creator WHERE username = creator COLLATE NOCASE) as "followed: bool"
+        FROM item
+        WHERE is_checked = true AND creator != ''
+        GROUP BY creator
+        ORDER BY count DESC, creator COLLATE NOCASE"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn list_followed(pool: &SqlitePool) -> Result<Vec<FollowedCreator>, sqlx::Error> {
+    sqlx::query_as!(
+        FollowedCreator,
+        "SELECT username, newest_model_id, checked_at FROM followed_creator ORDER BY username COLLATE NOCASE"
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn follow(pool: &SqlitePool, username: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("INSERT OR IGNORE INTO followed_creator (username) VALUES (?)", username)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn unfollow(pool: &SqlitePool, username: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM followed_creator WHERE username = ?", username)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Record that creator is checked and models up to `newest_model_id` are announced
+pub async fn set_checked(pool: &SqlitePool, username: &str, newest_model_id: Option<i64>) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE followed_creator SET newest_model_id = IFNULL(?, newest_model_id),
+            checked_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+        WHERE username = ?"#,
+        newest_model_id,
+        username
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn list_creators_with_followed() {
+        let pool = test_pool().await;
+        sqlx::query(
+            r#"INSERT INTO item (path, base_label, creator) VALUES
+                ('1', 'c', 'alice'), ('2', 'c', 'bob'), ('3', 'c', 'bob'), ('4', 'c', '')"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        follow(&pool, "Alice").await.unwrap();
+
+        let creators = list(&pool).await.unwrap();
+        assert_eq!(
+            creators
+                .iter()
+                .map(|c| (c.creator.as_str(), c.count, c.followed))
+                .collect::<Vec<_>>(),
+            vec![("bob", 2, false), ("alice", 1, true)]
+        );
+
+        set_checked(&pool, "Alice", Some(5)).await.unwrap();
+        set_checked(&pool, "Alice", None).await.unwrap();
+        let followed = list_followed(&pool).await.unwrap();
+        assert_eq!(followed[0].newest_model_id, Some(5));
+        assert!(followed[0].checked_at.is_some());
+    }
+}
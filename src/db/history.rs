@@ -0,0 +1,168 @@
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+
+pub enum Action {
+    Tags,
+    Note,
+    Move,
+    Delete,
+    Config,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Tags => "tags",
+            Action::Note => "note",
+            Action::Move => "move",
+            Action::Delete => "delete",
+            Action::Config => "config",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct History {
+    pub id: i64,
+    /// Basic auth user. Empty if basic auth is disabled.
+    pub user: String,
+    pub created_at: i64,
+    pub action: String,
+    pub item: Option<i64>,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+/// Tags and note of item, which can be reverted
+pub struct ItemState {
+    /// Tags in form `namespace:name`, sorted and separated by space
+    pub tags: String,
+    pub note: String,
+}
+
+pub async fn add(
+    conn: &mut SqliteConnection,
+    user: &str,
+    action: Action,
+    item: Option<i64>,
+    old_value: &str,
+    new_value: &str,
+) -> Result<(), sqlx::Error> {
+    let action = action.as_str();
+    sqlx::query!(
+        r#"INSERT INTO history (user, created_at, action, item, old_value, new_value)
+        VALUES (?, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER), ?, ?, ?, ?)"#,
+        user,
+        action,
+        item,
+        old_value,
+        new_value
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// List history of item, or of everything if `item` is `None`. Newest first.
+pub async fn list(
+    pool: &SqlitePool,
+    item: Option<i64>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<History>, i64), sqlx::Error> {
+    let history = sqlx::query_as!(
+        History,
+        r#"SELECT id, user, created_at, action, item, old_value, new_value FROM history
+        WHERE ? IS NULL OR item = ?
+        ORDER BY id DESC
+        LIMIT ? OFFSET ?"#,
+        item,
+        item,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!: i64" FROM history WHERE ? IS NULL OR item = ?"#,
+        item,
+        item
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((history, total))
+}
+
+pub async fn item_state(conn: &mut SqliteConnection, item: i64) -> Result<ItemState, sqlx::Error> {
+    let tags = sqlx::query_scalar!(
+        r#"SELECT IFNULL(GROUP_CONCAT(name, ' '), '') as "tags!: String" FROM (
+            SELECT IFNULL(tag_type.type || ':', '') || tag.name as name
+            FROM tag_item
+            JOIN tag ON tag.id = tag_item.tag
+            LEFT JOIN tag_type ON tag_type.id = tag.type
+            WHERE tag_item.item = ?
+            ORDER BY name)"#,
+        item
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let note = sqlx::query_scalar!("SELECT note FROM item WHERE id = ?", item)
+        .fetch_one(conn)
+        .await?;
+    Ok(ItemState { tags, note })
+}
+
+/// Compare current tags and note of item with `before`, and record what is changed
+pub async fn record_item_changes(
+    conn: &mut SqliteConnection,
+    user: &str,
+    item: i64,
+    before: &ItemState,
+) -> Result<(), sqlx::Error> {
+    let after = item_state(conn, item).await?;
+    if after.tags != before.tags {
+        add(conn, user, Action::Tags, Some(item), &before.tags, &after.tags).await?;
+    }
+    if after.note != before.note {
+        add(conn, user, Action::Note, Some(item), &before.note, &after.note).await?;
+    }
+    Ok(())
+}
+
+/// Return tags and note of item right after history entry `history_id`.
+/// Field is `None` if it has never been changed.
+pub async fn item_state_at(
+    pool: &SqlitePool,
+    item: i64,
+    history_id: i64,
+) -> Result<(Option<String>, Option<String>), sqlx::Error> {
+    let mut state = Vec::new();
+    for action in [Action::Tags, Action::Note] {
+        let action = action.as_str();
+        // Value after the last change up to `history_id`, or value before the first change after it
+        let value = sqlx::query_scalar!(
+            r#"SELECT value as "value!: String" FROM (
+                SELECT new_value as value, 0 as ord, -id as seq FROM history
+                WHERE item = ? AND action = ? AND id <= ?
+                UNION ALL
+                SELECT old_value as value, 1 as ord, id as seq FROM history
+                WHERE item = ? AND action = ? AND id > ?)
+            ORDER BY ord, seq
+            LIMIT 1"#,
+            item,
+            action,
+            history_id,
+            item,
+            action,
+            history_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        state.push(value);
+    }
+    let note = state.pop().flatten();
+    let tags = state.pop().flatten();
+    Ok((tags, note))
+}
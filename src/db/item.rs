@@ -1,26 +1,199 @@
-use indexmap::IndexSet;
-use sqlx::SqlitePool;
+use crate::civitai::CivitaiFileMetadata;
+use crate::db::query::{
+    DuplicateBy, GroupBy, SearchFilter, SortBy, SortOrder, SortValue, SqlArg, decode_cursor, encode_cursor,
+};
+use crate::db::tag::{CORRUPT_TAG, add_tag_item, list_types, remove_tag_item};
 use sqlx::sqlite::SqliteQueryResult;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{AssertSqlSafe, FromRow, Row, SqliteConnection, SqlitePool};
 
-#[derive(sqlx::FromRow, Eq, PartialEq, Hash)]
+#[derive(sqlx::FromRow, PartialEq)]
 pub struct Item {
     pub id: i64,
     pub name: Option<String>,
     pub path: String,
     pub base_label: String,
     pub note: String,
+    /// Highlighted part of the matched text. Only set by full-text search.
+    pub snippet: Option<String>,
+    pub favorite: bool,
+    /// User rating from 1 to 5
+    pub rating: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub use_count: i64,
+    /// Trigger words, one per line
+    pub trained_words: String,
+    /// Weight of LoRA in prompt
+    pub preferred_weight: Option<f64>,
+    /// In bytes
+    pub file_size: i64,
+    pub model_type: String,
+    pub base_model: String,
+    pub civitai_model_id: Option<i64>,
+    pub civitai_version_id: Option<i64>,
+    pub creator: String,
+    pub precision: String,
+    pub format: String,
+    pub size_type: String,
+    pub nsfw: bool,
+    pub nsfw_level: i64,
+    pub poi: bool,
+    pub allow_no_credit: Option<bool>,
+    /// Comma separated, e.g. `Image,RentCivit`
+    pub allow_commercial_use: String,
+    pub allow_derivatives: Option<bool>,
+    pub allow_different_license: Option<bool>,
+    /// Set when license changed between syncs, until it is acknowledged
+    pub license_changed_at: Option<i64>,
+    /// License before the change, see [`license_text`]
+    pub previous_license: String,
+    /// When sync found that the model was removed from Civitai
+    pub removed_upstream_at: Option<i64>,
+    /// When verification found that the file does not match the hash recorded by Civitai or cannot be read
+    pub corrupt_at: Option<i64>,
 }
 
-pub async fn mark_obsolete_all(pool: &SqlitePool) -> Result<SqliteQueryResult, sqlx::Error> {
-    sqlx::query!(r#"UPDATE item SET is_checked = false WHERE is_checked = true AND path != ''"#)
-        .execute(pool)
-        .await
+/// License of Civitai model as text, e.g. `commercial:Image,RentCivit derivatives:yes no_credit:no`.
+/// Unknown permissions are left out, so the text is empty if the license is unknown.
+pub fn license_text(
+    allow_no_credit: Option<bool>,
+    allow_commercial_use: &str,
+    allow_derivatives: Option<bool>,
+    allow_different_license: Option<bool>,
+) -> String {
+    let yes_no = |allowed: bool| if allowed { "yes" } else { "no" };
+    let mut parts = Vec::new();
+    if !allow_commercial_use.is_empty() {
+        parts.push(format!("commercial:{allow_commercial_use}"));
+    }
+    for (name, allowed) in [
+        ("derivatives", allow_derivatives),
+        ("different_license", allow_different_license),
+        ("no_credit", allow_no_credit),
+    ] {
+        if let Some(allowed) = allowed {
+            parts.push(format!("{name}:{}", yes_no(allowed)));
+        }
+    }
+    parts.join(" ")
+}
+
+/// Model info from Civitai `.json` and `.model.json`, stored in their own columns
+#[derive(Default, Debug, PartialEq)]
+pub struct ModelFields {
+    pub model_type: String,
+    pub base_model: String,
+    pub civitai_model_id: Option<i64>,
+    pub civitai_version_id: Option<i64>,
+    pub creator: String,
+    pub precision: String,
+    pub format: String,
+    pub size_type: String,
+    pub nsfw: bool,
+    pub nsfw_level: i64,
+    pub poi: bool,
+    pub allow_no_credit: Option<bool>,
+    pub allow_commercial_use: String,
+    pub allow_derivatives: Option<bool>,
+    pub allow_different_license: Option<bool>,
+}
+
+impl ModelFields {
+    /// `item_info` is the `.json` of model version, `model_info` is the `.model.json`.
+    /// `file_metadata` is the metadata of the file of this item in `item_info`.
+    pub fn from_info(item_info: &Value, model_info: &Value, file_metadata: &CivitaiFileMetadata) -> Self {
+        // Civitai returns a list, older responses and other tools a single value which includes the lower ones
+        let allow_commercial_use = match &model_info["allowCommercialUse"] {
+            Value::Array(values) => values
+                .iter()
+                .filter_map(|v| v.as_str())
+                .collect::<Vec<&str>>()
+                .join(","),
+            Value::String(value) => match value.as_str() {
+                "Sell" => "Image,RentCivit,Rent,Sell".to_string(),
+                "Rent" => "Image,RentCivit,Rent".to_string(),
+                "RentCivit" => "Image,RentCivit".to_string(),
+                _ => value.clone(),
+            },
+            _ => String::new(),
+        };
+
+        Self {
+            model_type: model_info["type"].as_str().unwrap_or_default().to_string(),
+            base_model: item_info["baseModel"].as_str().unwrap_or_default().to_string(),
+            civitai_model_id: item_info["modelId"].as_i64(),
+            civitai_version_id: item_info["id"].as_i64(),
+            creator: model_info["creator"]["username"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            precision: file_metadata.fp.clone().unwrap_or_default(),
+            format: file_metadata.format.clone(),
+            size_type: file_metadata.size.clone().unwrap_or_default(),
+            nsfw: model_info["nsfw"].as_bool().unwrap_or(false),
+            nsfw_level: item_info["nsfwLevel"]
+                .as_i64()
+                .or(model_info["nsfwLevel"].as_i64())
+                .unwrap_or_default(),
+            poi: model_info["poi"].as_bool().unwrap_or(false),
+            allow_no_credit: model_info["allowNoCredit"].as_bool(),
+            allow_commercial_use,
+            allow_derivatives: model_info["allowDerivatives"].as_bool(),
+            allow_different_license: model_info["allowDifferentLicense"].as_bool(),
+        }
+    }
+
+    /// Tags without namespace which older versions added from these fields, e.g. `lora`, `sdxl_1.0` or `fp16`
+    pub fn legacy_tags(&self) -> Vec<String> {
+        let mut tags = [
+            &self.model_type,
+            &self.base_model,
+            &self.format,
+            &self.precision,
+            &self.size_type,
+        ]
+        .into_iter()
+        .filter(|value| !value.is_empty())
+        .map(|value| value.replace(" ", "_").to_lowercase())
+        .collect::<Vec<String>>();
+        if self.nsfw {
+            tags.push("nsfw".to_string());
+        }
+        if self.poi {
+            tags.push("poi".to_string());
+        }
+        tags
+    }
+
+    /// See [`license_text`]
+    pub fn license(&self) -> String {
+        license_text(
+            self.allow_no_credit,
+            &self.allow_commercial_use,
+            self.allow_derivatives,
+            self.allow_different_license,
+        )
+    }
+}
+
+/// Mark items which are not found by the scan started at `scan_started_ms`
+pub async fn mark_obsolete_unscanned(
+    pool: &SqlitePool,
+    scan_started_ms: i64,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET is_checked = false WHERE is_checked = true AND path != '' AND scanned_at < ?"#,
+        scan_started_ms
+    )
+    .execute(pool)
+    .await
 }
 
 /// Return (path, label)
-pub async fn mark_obsolete(pool: &SqlitePool, id: i64) -> Result<(String, String), sqlx::Error> {
+pub async fn mark_obsolete(conn: &mut SqliteConnection, id: i64) -> Result<(String, String), sqlx::Error> {
     sqlx::query!(r#"UPDATE item SET is_checked = false WHERE id = ?"#, id)
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
 
     struct Temp {
@@ -28,14 +201,32 @@ pub async fn mark_obsolete(pool: &SqlitePool, id: i64) -> Result<(String, String
         base_label: String,
     }
     let ret = sqlx::query_as!(Temp, r#"SELECT path, base_label FROM item WHERE id = ?"#, id)
-        .fetch_one(pool)
+        .fetch_one(&mut *conn)
         .await?;
 
     Ok((ret.path, ret.base_label))
 }
 
+/// Update location of item after its files are moved
+pub async fn update_path(
+    conn: &mut SqliteConnection,
+    id: i64,
+    base_label: &str,
+    path: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE item SET path = ?, base_label = ? WHERE id = ?",
+        path,
+        base_label,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
 pub async fn insert_or_update(
-    pool: &SqlitePool,
+    conn: &mut SqliteConnection,
     name: Option<&str>,
     path: &str,
     base_label: &str,
@@ -44,9 +235,11 @@ pub async fn insert_or_update(
 ) -> Result<i64, sqlx::Error> {
     let ret_id = sqlx::query!(
         r#"
-        INSERT INTO item (name, path, base_label, blake3, updated_at) VALUES (?, ?, ?, ?, ?)
+        INSERT INTO item (name, path, base_label, blake3, updated_at, created_at, scanned_at)
+        VALUES (?, ?, ?, ?, ?, strftime('%s', 'now') * 1000, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
         ON CONFLICT (path, base_label) DO UPDATE SET
             is_checked=true,
+            scanned_at=excluded.scanned_at,
             blake3=excluded.blake3,
             base_label=excluded.base_label,
             name=excluded.name,
@@ -58,13 +251,327 @@ pub async fn insert_or_update(
         blake3,
         updated_at_ms,
     )
-    .fetch_one(pool)
+    .fetch_one(&mut *conn)
     .await?
     .id;
 
     Ok(ret_id)
 }
 
+pub async fn update_file_info(
+    conn: &mut SqliteConnection,
+    id: i64,
+    file_size: i64,
+    has_preview: bool,
+    sha256: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET file_size = ?, has_preview = ?, sha256 = ? WHERE id = ?"#,
+        file_size,
+        has_preview,
+        sha256,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn update_civitai_stats(
+    conn: &mut SqliteConnection,
+    id: i64,
+    downloads: i64,
+    rating: f64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET civitai_downloads = ?, civitai_rating = ? WHERE id = ?"#,
+        downloads,
+        rating,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Update the Civitai texts which are indexed by full-text search.
+/// `trained_words` are one per line, they are not updated if user has edited them.
+pub async fn update_search_text(
+    conn: &mut SqliteConnection,
+    id: i64,
+    model_name: &str,
+    version_name: &str,
+    description: &str,
+    trained_words: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET model_name = ?, version_name = ?, description = ?,
+            trained_words = CASE WHEN trigger_words_edited THEN trained_words ELSE ? END
+        WHERE id = ?"#,
+        model_name,
+        version_name,
+        description,
+        trained_words,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Update model fields of item. If the license is not known now, e.g. `.model.json` failed to download,
+/// the stored license is kept. If a known license differs from the stored one, the item is flagged.
+pub async fn update_model_fields(
+    conn: &mut SqliteConnection,
+    id: i64,
+    fields: &ModelFields,
+) -> Result<(), sqlx::Error> {
+    let old = sqlx::query!(
+        r#"SELECT allow_no_credit as "allow_no_credit: bool", allow_commercial_use,
+            allow_derivatives as "allow_derivatives: bool", allow_different_license as "allow_different_license: bool"
+        FROM item WHERE id = ?"#,
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let old_license = old
+        .as_ref()
+        .map(|old| {
+            license_text(
+                old.allow_no_credit,
+                &old.allow_commercial_use,
+                old.allow_derivatives,
+                old.allow_different_license,
+            )
+        })
+        .unwrap_or_default();
+    let new_license = fields.license();
+    let license_changed = !old_license.is_empty() && !new_license.is_empty() && old_license != new_license;
+    let (allow_no_credit, allow_commercial_use, allow_derivatives, allow_different_license) =
+        match old.filter(|_| new_license.is_empty()) {
+            Some(old) => (
+                old.allow_no_credit,
+                old.allow_commercial_use,
+                old.allow_derivatives,
+                old.allow_different_license,
+            ),
+            None => (
+                fields.allow_no_credit,
+                fields.allow_commercial_use.clone(),
+                fields.allow_derivatives,
+                fields.allow_different_license,
+            ),
+        };
+
+    sqlx::query!(
+        r#"UPDATE item SET model_type = ?, base_model = ?, civitai_model_id = ?, civitai_version_id = ?, creator = ?,
+            precision = ?, format = ?, size_type = ?, nsfw = ?, nsfw_level = ?, poi = ?, allow_no_credit = ?,
+            allow_commercial_use = ?, allow_derivatives = ?, allow_different_license = ?,
+            license_changed_at = CASE WHEN ? THEN strftime('%s', 'now') * 1000 ELSE license_changed_at END,
+            previous_license = CASE WHEN ? THEN ? ELSE previous_license END
+        WHERE id = ?"#,
+        fields.model_type,
+        fields.base_model,
+        fields.civitai_model_id,
+        fields.civitai_version_id,
+        fields.creator,
+        fields.precision,
+        fields.format,
+        fields.size_type,
+        fields.nsfw,
+        fields.nsfw_level,
+        fields.poi,
+        allow_no_credit,
+        allow_commercial_use,
+        allow_derivatives,
+        allow_different_license,
+        license_changed,
+        license_changed,
+        old_license,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Mark item as removed from Civitai, keeping the time it was first found removed, or clear the mark
+pub async fn set_removed_upstream(conn: &mut SqliteConnection, id: i64, removed: bool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET removed_upstream_at = CASE WHEN ? THEN IFNULL(removed_upstream_at, strftime('%s', 'now') * 1000)
+            ELSE NULL END
+        WHERE id = ?"#,
+        removed,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+pub struct ItemPath {
+    pub id: i64,
+    pub base_label: String,
+    pub path: String,
+}
+
+/// Path of every item which is not obsolete
+pub async fn list_paths(pool: &SqlitePool) -> Result<Vec<ItemPath>, sqlx::Error> {
+    sqlx::query_as!(
+        ItemPath,
+        "SELECT id, base_label, path FROM item WHERE is_checked = true ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Mark item as corrupt with tag [`CORRUPT_TAG`], keeping the time it was first found corrupt, or clear the mark.
+/// Either way the item is recorded as verified now.
+pub async fn set_corrupt(conn: &mut SqliteConnection, id: i64, corrupt: bool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE item SET corrupt_at = CASE WHEN ? THEN IFNULL(corrupt_at, strftime('%s', 'now') * 1000) ELSE NULL END,
+            verified_at = strftime('%s', 'now') * 1000
+        WHERE id = ?"#,
+        corrupt,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+    if corrupt {
+        add_tag_item(conn, id, &vec![CORRUPT_TAG.to_string()], None).await
+    } else {
+        remove_tag_item(conn, id, &[CORRUPT_TAG.to_string()]).await
+    }
+}
+
+pub async fn get_id_by_path(pool: &SqlitePool, base_label: &str, path: &str) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT id FROM item WHERE is_checked = true AND base_label = ? AND path = ?",
+        base_label,
+        path
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Id of item and modified time of its file when `tensor_hash` was calculated
+pub async fn get_tensor_hashed_at(
+    conn: &mut SqliteConnection,
+    base_label: &str,
+    path: &str,
+) -> Result<Option<(i64, Option<i64>)>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, tensor_hashed_at FROM item WHERE is_checked = true AND base_label = ? AND path = ?",
+        base_label,
+        path
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(row.map(|row| (row.id, row.tensor_hashed_at)))
+}
+
+/// `hashed_at` is the modified time of file when `tensor_hash` was calculated
+pub async fn update_tensor_hash(
+    conn: &mut SqliteConnection,
+    id: i64,
+    tensor_hash: &str,
+    hashed_at: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE item SET tensor_hash = ?, tensor_hashed_at = ? WHERE id = ?",
+        tensor_hash,
+        hashed_at,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Clear the license change flag of items
+pub async fn acknowledge_license_change(pool: &SqlitePool, ids: &[i64]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for id in ids {
+        sqlx::query!("UPDATE item SET license_changed_at = NULL WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+pub async fn set_favorite(pool: &SqlitePool, id: i64, favorite: bool) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE item SET favorite = ? WHERE id = ?", favorite, id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Set rating from 1 to 5, or `None` to remove it
+pub async fn set_rating(pool: &SqlitePool, id: i64, rating: Option<i64>) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE item SET rating = ? WHERE id = ?", rating, id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Set trigger words edited by user. `None` to use the words from Civitai or model metadata again on next scan.
+pub async fn set_trigger_words(pool: &SqlitePool, id: i64, words: Option<&[String]>) -> Result<(), sqlx::Error> {
+    match words {
+        Some(words) => {
+            let words = words
+                .iter()
+                .map(|w| w.trim())
+                .filter(|w| !w.is_empty())
+                .collect::<Vec<&str>>()
+                .join("\n");
+            sqlx::query!(
+                "UPDATE item SET trained_words = ?, trigger_words_edited = true WHERE id = ?",
+                words,
+                id
+            )
+            .execute(pool)
+            .await?;
+        }
+        None => {
+            sqlx::query!("UPDATE item SET trigger_words_edited = false WHERE id = ?", id)
+                .execute(pool)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Weight of LoRA in prompt, or `None` to remove it
+pub async fn set_preferred_weight(pool: &SqlitePool, id: i64, weight: Option<f64>) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE item SET preferred_weight = ? WHERE id = ?", weight, id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Record that model with this hash is used. `hash` is BLAKE3, SHA256 or AutoV2 (first 10 characters of SHA256).
+/// Return number of matched items.
+pub async fn mark_used(pool: &SqlitePool, hash: &str) -> Result<u64, sqlx::Error> {
+    let hash = hash.to_lowercase();
+    let autov2 = if hash.len() == 10 { format!("{hash}%") } else { String::new() };
+    let count = sqlx::query!(
+        r#"UPDATE item SET
+            last_used_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER),
+            use_count = use_count + 1
+        WHERE is_checked = true AND ?1 != '' AND (blake3 = ?1 OR sha256 = ?1 OR (?2 != '' AND sha256 LIKE ?2))"#,
+        hash,
+        autov2
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(count)
+}
+
 pub async fn clean(pool: &SqlitePool) -> Result<u64, sqlx::Error> {
     let count = sqlx::query!(r#"DELETE FROM item WHERE is_checked = false"#)
         .execute(pool)
@@ -76,7 +583,14 @@ pub async fn clean(pool: &SqlitePool) -> Result<u64, sqlx::Error> {
 pub async fn get_by_id(pool: &SqlitePool, id: i64) -> Result<Item, sqlx::Error> {
     let item = sqlx::query_as!(
         Item,
-        "SELECT id, name, path, base_label, note FROM item WHERE id = ?",
+        r#"SELECT id, name, path, base_label, note, NULL as "snippet?: String",
+            favorite as "favorite: bool", rating, last_used_at, use_count, trained_words, preferred_weight, file_size,
+            model_type, base_model, civitai_model_id, civitai_version_id, creator, precision, format, size_type,
+            nsfw as "nsfw: bool", nsfw_level, poi as "poi: bool", allow_no_credit as "allow_no_credit: bool",
+            allow_commercial_use, allow_derivatives as "allow_derivatives: bool",
+            allow_different_license as "allow_different_license: bool", license_changed_at, previous_license,
+            removed_upstream_at, corrupt_at
+        FROM item WHERE id = ?"#,
         id
     )
     .fetch_one(pool)
@@ -85,142 +599,560 @@ pub async fn get_by_id(pool: &SqlitePool, id: i64) -> Result<Item, sqlx::Error>
     Ok(item)
 }
 
-pub async fn search(
-    pool: &SqlitePool,
-    search: &str,
-    limit: i64,
-    offset: i64,
-    tag_only: bool,
-    duplicate_only: bool,
-) -> Result<(Vec<Item>, i64), sqlx::Error> {
-    //TODO: Search in note too
-    let mut items = IndexSet::new();
-    let mut count = 0;
-    let limit_dup_count = if duplicate_only { 1 } else { 0 };
-
-    if !tag_only {
-        let items_by_name = sqlx::query_as!(
-            Item,
-            r#"SELECT id,name, path, base_label, note
-            FROM item
-            WHERE is_checked = true
-                AND (name COLLATE NOCASE LIKE '%' || ? || '%'
-                    OR model_name COLLATE NOCASE LIKE '%' || ? || '%')
-                AND blake3 IN (
-                    SELECT blake3 FROM item
-                    WHERE is_checked = true
-                    GROUP BY blake3
-                    HAVING COUNT(*) > ?)
-            ORDER BY updated_at DESC
-            LIMIT ? OFFSET ?"#,
-            search,
-            search,
-            limit_dup_count,
-            limit,
-            offset
-        )
-        .fetch_all(pool)
-        .await?;
+#[derive(Default)]
+pub struct SearchOptions<'a> {
+    pub search: &'a str,
+    /// Query terms which must all match, parsed apart from `search` so its `OR` or quotes cannot change them
+    pub filters: &'a str,
+    pub tag_only: bool,
+    pub duplicate_only: bool,
+    /// What is compared to find duplicates if `duplicate_only` is set
+    pub duplicate_by: DuplicateBy,
+    /// Only show the newest installed version of each Civitai model
+    pub collapse_versions: bool,
+    pub sort: SortBy,
+    pub order: Option<SortOrder>,
+    pub limit: i64,
+    /// Ignored if `cursor` is used
+    pub offset: i64,
+    /// Return items after this cursor. Ignored when sorting by relevance, whose rank is not stable between pages.
+    pub cursor: Option<&'a str>,
+    /// Count all matching items by this field
+    pub group_by: Option<GroupBy>,
+}
 
-        let count_by_name: i64 = sqlx::query_scalar!(
-            r#"SELECT count(id)
-            FROM item
-            WHERE is_checked = true
-                AND (name COLLATE NOCASE LIKE '%' || ? || '%'
-                    OR model_name COLLATE NOCASE LIKE '%' || ? || '%')
-                AND blake3 IN (
-                    SELECT blake3 FROM item
-                    WHERE is_checked = true
-                    GROUP BY blake3
-                    HAVING COUNT(*) > ?)"#,
-            search,
-            search,
-            limit_dup_count,
-        )
-        .fetch_one(pool)
-        .await?;
+pub struct SearchResult {
+    pub items: Vec<Item>,
+    pub total: i64,
+    /// Cursor to get next page. `None` if this is the last page.
+    pub next_cursor: Option<String>,
+    /// Number of matching items by value of `group_by`, most first
+    pub groups: Vec<GroupCount>,
+}
 
-        items.extend(items_by_name);
-        count += count_by_name;
-    }
-
-    let tags: Vec<String> = search
-        .split_whitespace()
-        .map(|s| s.to_string().to_lowercase())
-        .collect();
-
-    // WORKAROUND: Do not exclude name match if tag_only
-    let search = if tag_only { "ikjsdfh3280urkjhfskjaeoiosd92304q31#!@&$^%@#&$*6" } else { search };
-
-    if !tags.is_empty() {
-        let search_by_tags = sqlx::query_as!(
-            Item,
-            r#"
-            SELECT item.id as id, item.name as name, item.note as note, item.path as path, item.base_label as base_label
-            FROM item
-            LEFT JOIN tag_item ON item.id = tag_item.item
-            LEFT JOIN tag ON tag.id = tag_item.tag
-            WHERE item.is_checked = true
-                AND tag.name IN (SELECT value FROM json_each(?))
-                AND NOT(item.name COLLATE NOCASE LIKE '%' || ? || '%'
-                        OR item.model_name COLLATE NOCASE LIKE '%' || ? || '%')
-                AND blake3 IN (
-                    SELECT blake3 FROM item
-                    WHERE is_checked = true
-                    GROUP BY blake3
-                    HAVING COUNT(*) > ?)
-            GROUP BY item.id
-            HAVING COUNT(DISTINCT tag.id) = ?
-            ORDER BY item.updated_at DESC LIMIT ? OFFSET ?
-            "#,
-            serde_json::json!(tags),
-            search,
-            search,
-            limit_dup_count,
-            tags.len() as i64,
-            limit,
-            offset
-        )
-        .fetch_all(pool)
-        .await?;
+#[derive(Serialize, FromRow)]
+pub struct GroupCount {
+    pub value: String,
+    pub count: i64,
+}
 
-        let tags_count: i64 = sqlx::query_scalar!(
-            r#"SELECT COUNT(*) FROM (SELECT item.id FROM item
-                LEFT JOIN tag_item ON item.id = tag_item.item
-                LEFT JOIN tag ON tag.id = tag_item.tag
-                WHERE item.is_checked = true
-                    AND tag.name IN (SELECT value FROM json_each(?))
-                    AND NOT(item.name COLLATE NOCASE LIKE '%' || ? || '%'
-                            OR item.model_name COLLATE NOCASE LIKE '%' || ? || '%')
-                    AND blake3 IN (
-                        SELECT blake3 FROM item
-                        WHERE is_checked = true
-                        GROUP BY blake3
-                        HAVING COUNT(*) > ?)
-                GROUP BY item.id
-                HAVING COUNT(DISTINCT tag.id) = ?)"#,
-            serde_json::json!(tags),
-            search,
-            search,
-            limit_dup_count,
-            tags.len() as i64,
-        )
-        .fetch_one(pool)
-        .await?;
+/// Escape FTS snippet as HTML, with matched words between `<mark>` tags.
+/// FTS marks them with control characters, which are not in indexed text, so note and description cannot inject HTML.
+fn highlight_snippet(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '\u{2}' => html.push_str("<mark>"),
+            '\u{3}' => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
+        }
+    }
+    html
+}
+
+pub async fn search(pool: &SqlitePool, opts: &SearchOptions<'_>) -> anyhow::Result<SearchResult> {
+    let mut search = if opts.duplicate_only {
+        format!("{} {}", opts.duplicate_by.term(), opts.search)
+    } else {
+        opts.search.to_string()
+    };
+    if opts.collapse_versions {
+        search = format!("newest:true {search}");
+    }
+    let namespaces = list_types(pool)
+        .await?
+        .into_iter()
+        .map(|tag_type| tag_type.name)
+        .collect::<Vec<String>>();
+    let mut filter = SearchFilter::parse(&search, opts.tag_only, &namespaces)?;
+    if !opts.filters.trim().is_empty() {
+        let filters = SearchFilter::parse(opts.filters, false, &namespaces)?;
+        filter.condition = format!("{} AND {}", filter.condition, filters.condition);
+        filter.args.extend(filters.args);
+    }
+
+    let mut args = Vec::new();
+    let (fts_join, snippet) = match filter.fts_match.as_ref() {
+        Some(fts_match) => {
+            args.push(SqlArg::Text(fts_match.clone()));
+            (
+                r#"LEFT JOIN (
+                    SELECT rowid,
+                        bm25(item_fts, 10.0, 5.0, 3.0, 2.0, 1.0, 3.0) as rank,
+                        snippet(item_fts, -1, char(2), char(3), '...', 16) as snippet
+                    FROM item_fts WHERE item_fts MATCH ?) AS fts ON fts.rowid = item.id"#,
+                "fts.snippet",
+            )
+        }
+        None => ("", "NULL"),
+    };
+    args.extend(filter.args.iter().cloned());
+
+    let sort = if opts.sort == SortBy::Relevance && filter.fts_match.is_none() {
+        SortBy::Updated
+    } else {
+        opts.sort
+    };
+    let order = opts.order.unwrap_or(sort.default_order());
+    let sort_column = sort.column();
+    let direction = order.as_sql();
+    // Rank changes when items are updated during a scan, so relevance pages by offset
+    let cursor = opts.cursor.filter(|_| sort != SortBy::Relevance);
+
+    let mut page_condition = String::new();
+    if let Some(cursor) = cursor {
+        let (value, id) = decode_cursor(cursor)?;
+        let cmp = if order == SortOrder::Asc { ">" } else { "<" };
+        page_condition = format!("AND ({sort_column} {cmp} ? OR ({sort_column} = ? AND item.id {cmp} ?))");
+        args.push(value.clone().into());
+        args.push(value.into());
+        args.push(SqlArg::Int(id));
+    }
+    // Get one more item to know if there is next page
+    args.push(SqlArg::Int(opts.limit + 1));
+    args.push(SqlArg::Int(if cursor.is_some() { 0 } else { opts.offset }));
+
+    let sql = format!(
+        r#"SELECT item.id as id, item.name as name, item.path as path, item.base_label as base_label,
+            item.note as note, {snippet} as snippet, item.favorite as favorite, item.rating as rating,
+            item.last_used_at as last_used_at, item.use_count as use_count,
+            item.trained_words as trained_words, item.preferred_weight as preferred_weight,
+            item.file_size as file_size, item.model_type as model_type, item.base_model as base_model, item.civitai_model_id as civitai_model_id,
+            item.civitai_version_id as civitai_version_id, item.creator as creator, item.precision as precision,
+            item.format as format, item.size_type as size_type, item.nsfw as nsfw, item.nsfw_level as nsfw_level,
+            item.poi as poi, item.allow_no_credit as allow_no_credit, item.allow_commercial_use as allow_commercial_use,
+            item.allow_derivatives as allow_derivatives, item.allow_different_license as allow_different_license,
+            item.license_changed_at as license_changed_at, item.previous_license as previous_license,
+            item.removed_upstream_at as removed_upstream_at, item.corrupt_at as corrupt_at,
+            {sort_column} as sort_value
+        FROM item
+        {fts_join}
+        WHERE item.is_checked = true AND {} {page_condition}
+        ORDER BY {sort_column} {direction}, item.id {direction}
+        LIMIT ? OFFSET ?"#,
+        filter.condition
+    );
+    let mut query = sqlx::query(AssertSqlSafe(sql));
+    for arg in args {
+        query = match arg {
+            SqlArg::Text(s) => query.bind(s),
+            SqlArg::Int(i) => query.bind(i),
+            SqlArg::Float(f) => query.bind(f),
+        };
+    }
+    let rows = query.fetch_all(pool).await?;
+
+    let has_next_page = rows.len() as i64 > opts.limit;
+    let rows = &rows[..rows.len().min(opts.limit.max(0) as usize)];
+    let mut items = rows.iter().map(Item::from_row).collect::<Result<Vec<Item>, _>>()?;
+    for item in items.iter_mut() {
+        item.snippet = item.snippet.as_deref().map(highlight_snippet);
+    }
+
+    let mut next_cursor = None;
+    if has_next_page
+        && sort != SortBy::Relevance
+        && let Some(last) = rows.last()
+    {
+        let value = match sort {
+            SortBy::Rating => SortValue::Float(last.try_get("sort_value")?),
+            _ if sort.is_text() => SortValue::Text(last.try_get("sort_value")?),
+            _ => SortValue::Int(last.try_get("sort_value")?),
+        };
+        next_cursor = Some(encode_cursor(&value, last.try_get("id")?));
+    }
+
+    let sql = format!(
+        "SELECT COUNT(*) FROM item WHERE item.is_checked = true AND {}",
+        filter.condition
+    );
+    let mut query = sqlx::query_scalar::<_, i64>(AssertSqlSafe(sql));
+    for arg in filter.args.iter().cloned() {
+        query = match arg {
+            SqlArg::Text(s) => query.bind(s),
+            SqlArg::Int(i) => query.bind(i),
+            SqlArg::Float(f) => query.bind(f),
+        };
+    }
+    let total = query.fetch_one(pool).await?;
 
-        count += tags_count;
-        items.extend(search_by_tags);
+    let mut groups = Vec::new();
+    if let Some(group_by) = opts.group_by {
+        let column = group_by.column();
+        let sql = format!(
+            r#"SELECT CAST({column} AS TEXT) as value, COUNT(*) as count FROM item
+            WHERE item.is_checked = true AND {}
+            GROUP BY {column}
+            ORDER BY count DESC, value"#,
+            filter.condition
+        );
+        let mut query = sqlx::query_as::<_, GroupCount>(AssertSqlSafe(sql));
+        for arg in filter.args {
+            query = match arg {
+                SqlArg::Text(s) => query.bind(s),
+                SqlArg::Int(i) => query.bind(i),
+                SqlArg::Float(f) => query.bind(f),
+            };
+        }
+        groups = query.fetch_all(pool).await?;
     }
 
-    Ok((items.into_iter().collect(), count))
+    Ok(SearchResult {
+        items,
+        total,
+        next_cursor,
+        groups,
+    })
 }
 
 pub async fn get_by_hash(pool: &SqlitePool, blake3: &str) -> Result<Item, sqlx::Error> {
     sqlx::query_as!(
         Item,
-        "SELECT id, name, path, base_label, note FROM item WHERE is_checked = true AND blake3 = ?",
+        r#"SELECT id, name, path, base_label, note, NULL as "snippet?: String",
+            favorite as "favorite: bool", rating, last_used_at, use_count, trained_words, preferred_weight, file_size,
+            model_type, base_model, civitai_model_id, civitai_version_id, creator, precision, format, size_type,
+            nsfw as "nsfw: bool", nsfw_level, poi as "poi: bool", allow_no_credit as "allow_no_credit: bool",
+            allow_commercial_use, allow_derivatives as "allow_derivatives: bool",
+            allow_different_license as "allow_different_license: bool", license_changed_at, previous_license,
+            removed_upstream_at, corrupt_at
+        FROM item WHERE is_checked = true AND blake3 = ?"#,
         blake3
     )
     .fetch_one(pool)
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    /// Names and sizes have ties, so items with the same sort value are split between pages
+    async fn items(pool: &SqlitePool) {
+        sqlx::query(
+            r#"INSERT INTO item (id, path, base_label, name, file_size) VALUES
+                (1, '1', 'c', 'b', 3), (2, '2', 'c', 'a', 0), (3, '3', 'c', 'B', 5),
+                (4, '4', 'c', 'c', 3), (5, '5', 'c', 'A', 0)"#,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    /// Ids of all pages of `limit` items, following cursors
+    async fn page_ids(pool: &SqlitePool, sort: SortBy, order: Option<SortOrder>, limit: i64) -> Vec<Vec<i64>> {
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let opts = SearchOptions {
+                sort,
+                order,
+                limit,
+                cursor: cursor.as_deref(),
+                ..Default::default()
+            };
+            let res = search(pool, &opts).await.unwrap();
+            assert_eq!(res.total, 5);
+            pages.push(res.items.iter().map(|item| item.id).collect());
+            match res.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return pages,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_through_ties() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        assert_eq!(
+            page_ids(&pool, SortBy::Name, None, 2).await,
+            vec![vec![2, 5], vec![1, 3], vec![4]]
+        );
+        assert_eq!(
+            page_ids(&pool, SortBy::Name, Some(SortOrder::Desc), 2).await,
+            vec![vec![4, 3], vec![1, 5], vec![2]]
+        );
+        assert_eq!(
+            page_ids(&pool, SortBy::Size, None, 2).await,
+            vec![vec![3, 4], vec![1, 5], vec![2]]
+        );
+    }
+
+    #[tokio::test]
+    async fn last_full_page_has_no_cursor() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        assert_eq!(page_ids(&pool, SortBy::Name, None, 5).await, vec![vec![2, 5, 1, 3, 4]]);
+    }
+
+    #[test]
+    fn model_fields_from_civitai_info() {
+        let item_info = serde_json::json!({"id": 2, "modelId": 1, "baseModel": "SDXL 1.0", "nsfwLevel": 4});
+        let model_info = serde_json::json!({
+            "type": "LORA",
+            "nsfw": true,
+            "creator": {"username": "someone"},
+            "allowNoCredit": true,
+            "allowCommercialUse": ["Image", "RentCivit"],
+            "allowDerivatives": false,
+        });
+        let file_metadata = CivitaiFileMetadata {
+            format: "SafeTensor".to_string(),
+            fp: Some("fp16".to_string()),
+            size: None,
+        };
+        assert_eq!(
+            ModelFields::from_info(&item_info, &model_info, &file_metadata),
+            ModelFields {
+                model_type: "LORA".to_string(),
+                base_model: "SDXL 1.0".to_string(),
+                civitai_model_id: Some(1),
+                civitai_version_id: Some(2),
+                creator: "someone".to_string(),
+                precision: "fp16".to_string(),
+                format: "SafeTensor".to_string(),
+                nsfw: true,
+                nsfw_level: 4,
+                allow_no_credit: Some(true),
+                allow_commercial_use: "Image,RentCivit".to_string(),
+                allow_derivatives: Some(false),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_and_group_by_model_fields() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        sqlx::query(
+            r#"UPDATE item SET model_type = CASE WHEN id < 4 THEN 'LORA' ELSE 'Checkpoint' END,
+                base_model = CASE WHEN id = 1 THEN 'SD 1.5' ELSE 'SDXL 1.0' END"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let opts = SearchOptions {
+            search: "type:lora base:sdxl",
+            sort: SortBy::BaseModel,
+            limit: 10,
+            group_by: Some(GroupBy::ModelType),
+            ..Default::default()
+        };
+        let res = search(&pool, &opts).await.unwrap();
+        assert_eq!(res.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![2, 3]);
+
+        let opts = SearchOptions { search: "", ..opts };
+        let res = search(&pool, &opts).await.unwrap();
+        assert_eq!(res.items.first().map(|item| item.id), Some(1));
+        let groups = res
+            .groups
+            .iter()
+            .map(|group| (group.value.as_str(), group.count))
+            .collect::<Vec<_>>();
+        assert_eq!(groups, vec![("LORA", 3), ("Checkpoint", 2)]);
+    }
+
+    #[tokio::test]
+    async fn filters_are_not_or_ed_with_search() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        sqlx::query("UPDATE item SET model_type = CASE WHEN id < 4 THEN 'LORA' ELSE 'Checkpoint' END")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let opts = SearchOptions {
+            search: "size>0 OR",
+            filters: r#"type:"checkpoint""#,
+            limit: 10,
+            ..Default::default()
+        };
+        let res = search(&pool, &opts).await.unwrap();
+        assert_eq!(res.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[tokio::test]
+    async fn license_change_is_flagged() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        let mut fields = ModelFields {
+            allow_commercial_use: "Image,RentCivit,Rent,Sell".to_string(),
+            allow_derivatives: Some(true),
+            ..Default::default()
+        };
+        update_model_fields(&mut pool.acquire().await.unwrap(), 1, &fields)
+            .await
+            .unwrap();
+        update_model_fields(&mut pool.acquire().await.unwrap(), 1, &fields)
+            .await
+            .unwrap();
+        assert_eq!(get_by_id(&pool, 1).await.unwrap().license_changed_at, None);
+
+        // Unknown license keeps the stored one
+        update_model_fields(&mut pool.acquire().await.unwrap(), 1, &ModelFields::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            get_by_id(&pool, 1).await.unwrap().allow_commercial_use,
+            fields.allow_commercial_use
+        );
+
+        fields.allow_commercial_use = "Image".to_string();
+        update_model_fields(&mut pool.acquire().await.unwrap(), 1, &fields)
+            .await
+            .unwrap();
+        let item = get_by_id(&pool, 1).await.unwrap();
+        assert!(item.license_changed_at.is_some());
+        assert_eq!(
+            item.previous_license,
+            "commercial:Image,RentCivit,Rent,Sell derivatives:yes"
+        );
+
+        let opts = SearchOptions {
+            search: "has:license_change -allow:sell allow:image",
+            limit: 10,
+            ..Default::default()
+        };
+        let res = search(&pool, &opts).await.unwrap();
+        assert_eq!(res.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![1]);
+
+        acknowledge_license_change(&pool, &[1]).await.unwrap();
+        assert_eq!(get_by_id(&pool, 1).await.unwrap().license_changed_at, None);
+    }
+
+    #[tokio::test]
+    async fn removed_upstream_keeps_first_time() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        sqlx::query("UPDATE item SET removed_upstream_at = 1 WHERE id = 2")
+            .execute(&pool)
+            .await
+            .unwrap();
+        set_removed_upstream(&mut pool.acquire().await.unwrap(), 2, true)
+            .await
+            .unwrap();
+        set_removed_upstream(&mut pool.acquire().await.unwrap(), 3, true)
+            .await
+            .unwrap();
+        assert_eq!(get_by_id(&pool, 2).await.unwrap().removed_upstream_at, Some(1));
+
+        let opts = SearchOptions {
+            search: "status:removed_upstream",
+            limit: 10,
+            ..Default::default()
+        };
+        let res = search(&pool, &opts).await.unwrap();
+        assert_eq!(res.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![3, 2]);
+
+        set_removed_upstream(&mut pool.acquire().await.unwrap(), 2, false)
+            .await
+            .unwrap();
+        assert_eq!(get_by_id(&pool, 2).await.unwrap().removed_upstream_at, None);
+    }
+
+    #[tokio::test]
+    async fn corrupt_mark_sets_tag() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        set_corrupt(&mut pool.acquire().await.unwrap(), 2, true).await.unwrap();
+        set_corrupt(&mut pool.acquire().await.unwrap(), 3, false).await.unwrap();
+        assert!(get_by_id(&pool, 2).await.unwrap().corrupt_at.is_some());
+
+        for search_text in ["status:corrupt", "tag:corrupt"] {
+            let opts = SearchOptions {
+                search: search_text,
+                limit: 10,
+                ..Default::default()
+            };
+            let res = search(&pool, &opts).await.unwrap();
+            assert_eq!(res.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![2]);
+        }
+
+        set_corrupt(&mut pool.acquire().await.unwrap(), 2, false).await.unwrap();
+        let opts = SearchOptions {
+            search: "tag:corrupt",
+            limit: 10,
+            ..Default::default()
+        };
+        assert!(search(&pool, &opts).await.unwrap().items.is_empty());
+        assert_eq!(get_by_id(&pool, 2).await.unwrap().corrupt_at, None);
+    }
+
+    #[tokio::test]
+    async fn duplicates_by_weights_ignore_metadata() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        sqlx::query("UPDATE item SET blake3 = 'file' || id, tensor_hash = CASE WHEN id IN (1, 4) THEN 'w' ELSE '' END")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let opts = SearchOptions {
+            duplicate_only: true,
+            sort: SortBy::Name,
+            limit: 10,
+            ..Default::default()
+        };
+        assert!(search(&pool, &opts).await.unwrap().items.is_empty());
+        let opts = SearchOptions {
+            duplicate_by: DuplicateBy::Weights,
+            ..opts
+        };
+        let res = search(&pool, &opts).await.unwrap();
+        assert_eq!(res.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![1, 4]);
+    }
+
+    #[tokio::test]
+    async fn offset_is_ignored_with_cursor() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        let opts = SearchOptions {
+            sort: SortBy::Name,
+            limit: 2,
+            offset: 2,
+            ..Default::default()
+        };
+        let page = search(&pool, &opts).await.unwrap();
+        assert_eq!(page.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![1, 3]);
+
+        let opts = SearchOptions {
+            cursor: page.next_cursor.as_deref(),
+            ..opts
+        };
+        let page = search(&pool, &opts).await.unwrap();
+        assert_eq!(page.items.iter().map(|item| item.id).collect::<Vec<_>>(), vec![4]);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn relevance_pages_by_offset() {
+        let pool = test_pool().await;
+        items(&pool).await;
+        let opts = SearchOptions {
+            search: "a",
+            sort: SortBy::Relevance,
+            limit: 1,
+            ..Default::default()
+        };
+        let first = search(&pool, &opts).await.unwrap();
+        assert_eq!(first.total, 2);
+        assert!(first.next_cursor.is_none());
+
+        let cursor = encode_cursor(&SortValue::Float(0.0), 0);
+        let opts = SearchOptions {
+            offset: 1,
+            cursor: Some(&cursor),
+            ..opts
+        };
+        let second = search(&pool, &opts).await.unwrap();
+        assert_eq!(second.items.len(), 1);
+        assert_ne!(second.items[0].id, first.items[0].id);
+    }
+}
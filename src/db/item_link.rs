@@ -0,0 +1,157 @@
+//! Items whose file is replaced by a link to the file of another item with the same BLAKE3, to save disk space
+
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+
+/// Items with the same file
+#[derive(Serialize)]
+pub struct DuplicateGroup {
+    pub blake3: String,
+    pub item_count: i64,
+    /// Size of one file
+    pub file_size: i64,
+    /// Number of items which are already links
+    pub linked_count: i64,
+}
+
+#[derive(Serialize)]
+pub struct GroupItem {
+    pub id: i64,
+    pub base_label: String,
+    pub path: String,
+    pub file_size: i64,
+}
+
+#[derive(Serialize)]
+pub struct ItemLink {
+    pub item: i64,
+    pub canonical: i64,
+    /// `hardlink` or `symlink`
+    pub link_type: String,
+    pub reclaimed_bytes: i64,
+    pub linked_at: i64,
+}
+
+/// Groups of items with the same BLAKE3, largest files first
+pub async fn list_groups(pool: &SqlitePool) -> Result<Vec<DuplicateGroup>, sqlx::Error> {
+    sqlx::query_as!(
+        DuplicateGroup,
+        r#"SELECT blake3 as "blake3!", COUNT(*) as item_count, MAX(file_size) as "file_size!: i64",
+            COUNT(item_link.item) as linked_count
+        FROM item
+        LEFT JOIN item_link ON item_link.item = item.id
+        WHERE is_checked = true AND blake3 != ''
+        GROUP BY blake3
+        HAVING COUNT(*) > 1
+        ORDER BY file_size DESC, blake3"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Items with BLAKE3 `blake3`, oldest first
+pub async fn group_items(pool: &SqlitePool, blake3: &str) -> Result<Vec<GroupItem>, sqlx::Error> {
+    sqlx::query_as!(
+        GroupItem,
+        "SELECT id, base_label, path, file_size FROM item WHERE is_checked = true AND blake3 = ? ORDER BY id",
+        blake3
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn list(pool: &SqlitePool) -> Result<Vec<ItemLink>, sqlx::Error> {
+    sqlx::query_as!(
+        ItemLink,
+        "SELECT item, canonical, link_type, reclaimed_bytes, linked_at FROM item_link ORDER BY linked_at DESC"
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Record that file of `item` is replaced by a link to the file of `canonical`
+pub async fn add(
+    conn: &mut SqliteConnection,
+    item: i64,
+    canonical: i64,
+    link_type: &str,
+    reclaimed_bytes: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT OR REPLACE INTO item_link (item, canonical, link_type, reclaimed_bytes, linked_at)
+        VALUES (?, ?, ?, ?, strftime('%s', 'now') * 1000)"#,
+        item,
+        canonical,
+        link_type,
+        reclaimed_bytes
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Number of items which are symlinks to the file of `canonical`, and break if it is removed
+pub async fn count_symlinks_to(conn: &mut SqliteConnection, canonical: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!: i64" FROM item_link
+        JOIN item ON item.id = item_link.item
+        WHERE item_link.canonical = ? AND item_link.link_type = 'symlink' AND item.is_checked = true"#,
+        canonical
+    )
+    .fetch_one(&mut *conn)
+    .await
+}
+
+/// True if the file of `item` is a symlink to the file of another item, which breaks if it is moved
+pub async fn is_symlink(conn: &mut SqliteConnection, item: i64) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT COUNT(*) > 0 as "is_symlink!: bool" FROM item_link WHERE item = ? AND link_type = 'symlink'"#,
+        item
+    )
+    .fetch_one(&mut *conn)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn groups_count_linked_items() {
+        let pool = test_pool().await;
+        sqlx::query(
+            r#"INSERT INTO item (id, path, base_label, blake3, file_size) VALUES
+                (1, '1', 'c', 'a', 10), (2, '2', 'c', 'a', 10), (3, '3', 'd', 'a', 10),
+                (4, '4', 'c', 'b', 99), (5, '5', 'c', '', 5), (6, '6', 'c', '', 5)"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        add(&mut conn, 2, 1, "hardlink", 10).await.unwrap();
+        add(&mut conn, 3, 1, "symlink", 10).await.unwrap();
+        assert_eq!(count_symlinks_to(&mut conn, 1).await.unwrap(), 1);
+        assert!(is_symlink(&mut conn, 3).await.unwrap());
+        assert!(!is_symlink(&mut conn, 2).await.unwrap());
+        drop(conn);
+
+        let groups = list_groups(&pool).await.unwrap();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| (group.blake3.as_str(), group.item_count, group.linked_count))
+                .collect::<Vec<_>>(),
+            vec![("a", 3, 2)]
+        );
+        assert_eq!(
+            group_items(&pool, "a")
+                .await
+                .unwrap()
+                .iter()
+                .map(|item| item.id)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+    }
+}
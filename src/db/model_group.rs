@@ -0,0 +1,95 @@
+//! Local items grouped by Civitai model, so all installed versions of a model are shown together
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+#[derive(Serialize)]
+pub struct ModelGroup {
+    pub civitai_model_id: i64,
+    pub model_name: String,
+    pub model_type: String,
+    pub creator: String,
+    /// Number of installed versions
+    pub item_count: i64,
+    /// Item of the newest installed version
+    pub newest_item: i64,
+}
+
+/// Installed version of a Civitai model
+#[derive(Serialize)]
+pub struct LocalVersion {
+    pub item_id: i64,
+    pub civitai_version_id: Option<i64>,
+    pub version_name: String,
+    pub name: String,
+    pub path: String,
+    pub base_label: String,
+    pub base_model: String,
+}
+
+/// List Civitai models of local items which have at least `min_items` installed versions, most versions first
+pub async fn list(pool: &SqlitePool, min_items: i64) -> Result<Vec<ModelGroup>, sqlx::Error> {
+    sqlx::query_as!(
+        ModelGroup,
+        r#"SELECT civitai_model_id as "civitai_model_id!", MAX(model_name) as "model_name!: String",
+            MAX(model_type) as "model_type!: String", MAX(creator) as "creator!: String", COUNT(*) as item_count,
+            (SELECT newest.id FROM item AS newest
+                WHERE newest.is_checked = true AND newest.civitai_model_id = item.civitai_model_id
+                ORDER BY IFNULL(newest.civitai_version_id, 0) DESC, newest.id DESC LIMIT 1) as "newest_item!: i64"
+        FROM item
+        WHERE is_checked = true AND civitai_model_id IS NOT NULL
+        GROUP BY civitai_model_id
+        HAVING COUNT(*) >= ?
+        ORDER BY item_count DESC, model_name COLLATE NOCASE"#,
+        min_items
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Installed versions of Civitai model, newest first.
+/// Civitai version ids only increase, so the version with the highest id is the newest.
+pub async fn local_versions(pool: &SqlitePool, civitai_model_id: i64) -> Result<Vec<LocalVersion>, sqlx::Error> {
+    sqlx::query_as!(
+        LocalVersion,
+        r#"SELECT id as item_id, civitai_version_id, version_name, name, path, base_label, base_model
+        FROM item
+        WHERE is_checked = true AND civitai_model_id = ?
+        ORDER BY IFNULL(civitai_version_id, 0) DESC, id DESC"#,
+        civitai_model_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn newest_version_is_highest_version_id() {
+        let pool = test_pool().await;
+        sqlx::query(
+            r#"INSERT INTO item (id, path, base_label, civitai_model_id, civitai_version_id, model_name) VALUES
+                (1, '1', 'c', 10, 200, 'a'), (2, '2', 'c', 10, 300, 'a'), (3, '3', 'c', 10, 100, 'a'),
+                (4, '4', 'c', 20, 400, 'b'), (5, '5', 'c', NULL, NULL, '')"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let groups = list(&pool, 1).await.unwrap();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| (group.civitai_model_id, group.item_count, group.newest_item))
+                .collect::<Vec<_>>(),
+            vec![(10, 3, 2), (20, 1, 4)]
+        );
+        assert_eq!(list(&pool, 2).await.unwrap().len(), 1);
+
+        let versions = local_versions(&pool, 10).await.unwrap();
+        assert_eq!(versions.iter().map(|v| v.item_id).collect::<Vec<_>>(), vec![2, 1, 3]);
+    }
+}
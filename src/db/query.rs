@@ -0,0 +1,837 @@
+//! Search query language used by `/api/item?search=`.
+//!
+//! Words next to each other are AND-ed, `OR` has higher priority than AND:
+//! * `word`, `"quoted phrase"`: match name, note, description, trained words or a tag with same name
+//! * `tag:x`, `type:lora`, `base:sdxl`, `collection:label`, `creator:name`, `model:<Civitai model id>`
+//! * `format:safetensors`, `format:fp16`, `format:pruned`: file format, precision or size type
+//! * `trigger:word`, `trigger:"some phrase"`: has this trigger word
+//! * `namespace:x`: tag `x` of tag type `namespace`, e.g. `style:anime`
+//! * `size>2GB`, `size<=500MB`, `added<30d`, `added>1y`
+//! * `has:preview`, `has:note`, `has:civitai`, `has:trigger`, `has:description`, `has:tag`, `has:favorite`,
+//!   `has:rating`, `has:nsfw`, `has:poi`, `has:license`, `has:license_change`
+//! * `allow:sell`, `allow:rent`, `allow:rentcivit`, `allow:image`: commercial use allowed by license of Civitai model,
+//!   `allow:derivatives`, `allow:different_license`, `allow:no_credit`
+//! * `rating>=4`, `uses>10`, `used<30d`, `used>6m` (never used items count as used long ago)
+//! * `dup:true`, `dup:false`, `dup:weights`: same tensor data of `.safetensors` even if `__metadata__` differs
+//! * `status:removed_upstream`: model was removed from Civitai, `status:corrupt`: file does not match its Civitai hash
+//! * `newest:true`: only the newest installed version of each Civitai model, `newest:false`: only older versions
+//! * `-` before any term to exclude it
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    /// Best full-text match first. Same as `Updated` if there is no word to match.
+    #[default]
+    Relevance,
+    Updated,
+    Added,
+    Name,
+    Size,
+    Downloads,
+    Rating,
+    /// Rating given by user
+    UserRating,
+    LastUsed,
+    UseCount,
+    ModelType,
+    BaseModel,
+    Creator,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortBy {
+    /// SQL expression to sort by. `fts` is the joined full-text search result.
+    pub fn column(&self) -> &'static str {
+        match self {
+            SortBy::Relevance => "IFNULL(fts.rank, 0.0)",
+            SortBy::Updated => "IFNULL(item.updated_at, 0)",
+            SortBy::Added => "IFNULL(item.created_at, 0)",
+            SortBy::Name => "item.name COLLATE NOCASE",
+            SortBy::Size => "item.file_size",
+            SortBy::Downloads => "item.civitai_downloads",
+            SortBy::Rating => "item.civitai_rating",
+            SortBy::UserRating => "IFNULL(item.rating, 0)",
+            SortBy::LastUsed => "IFNULL(item.last_used_at, 0)",
+            SortBy::UseCount => "item.use_count",
+            SortBy::ModelType => "item.model_type COLLATE NOCASE",
+            SortBy::BaseModel => "item.base_model COLLATE NOCASE",
+            SortBy::Creator => "item.creator COLLATE NOCASE",
+        }
+    }
+
+    /// Whether the sort column is text
+    pub fn is_text(&self) -> bool {
+        matches!(
+            self,
+            SortBy::Name | SortBy::ModelType | SortBy::BaseModel | SortBy::Creator
+        )
+    }
+
+    pub fn default_order(&self) -> SortOrder {
+        match self {
+            SortBy::Relevance | SortBy::Name | SortBy::ModelType | SortBy::BaseModel | SortBy::Creator => {
+                SortOrder::Asc
+            }
+            _ => SortOrder::Desc,
+        }
+    }
+}
+
+/// What is compared to find duplicated items
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateBy {
+    /// Whole file
+    #[default]
+    File,
+    /// Tensor data of `.safetensors`, so files which only differ in `__metadata__` are duplicates
+    Weights,
+}
+
+impl DuplicateBy {
+    /// Search term which matches duplicated items
+    pub fn term(&self) -> &'static str {
+        match self {
+            DuplicateBy::File => "dup:true",
+            DuplicateBy::Weights => "dup:weights",
+        }
+    }
+}
+
+/// Field of item to count matching items by
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    ModelType,
+    BaseModel,
+    CivitaiModel,
+    Creator,
+    Precision,
+    Format,
+}
+
+impl GroupBy {
+    pub fn column(&self) -> &'static str {
+        match self {
+            GroupBy::ModelType => "item.model_type",
+            GroupBy::BaseModel => "item.base_model",
+            GroupBy::CivitaiModel => "IFNULL(item.civitai_model_id, '')",
+            GroupBy::Creator => "item.creator",
+            GroupBy::Precision => "item.precision",
+            GroupBy::Format => "item.format",
+        }
+    }
+}
+
+impl SortOrder {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Value of sort column of the last item in page
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SortValue {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl From<SortValue> for SqlArg {
+    fn from(value: SortValue) -> Self {
+        match value {
+            SortValue::Int(i) => SqlArg::Int(i),
+            SortValue::Float(f) => SqlArg::Float(f),
+            SortValue::Text(s) => SqlArg::Text(s),
+        }
+    }
+}
+
+/// Cursor is the hex encoded JSON of `[sort value, item id]`
+pub fn encode_cursor(value: &SortValue, id: i64) -> String {
+    let json = serde_json::json!([value, id]).to_string();
+    json.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn decode_cursor(cursor: &str) -> anyhow::Result<(SortValue, i64)> {
+    let invalid = || anyhow!("Invalid cursor {}", cursor);
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid())?;
+
+    serde_json::from_slice(&bytes).map_err(|_| invalid())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cmp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cmp {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Cmp::Eq => "=",
+            Cmp::Lt => "<",
+            Cmp::Le => "<=",
+            Cmp::Gt => ">",
+            Cmp::Ge => ">=",
+        }
+    }
+
+    fn reverse(&self) -> Self {
+        match self {
+            Cmp::Eq => Cmp::Eq,
+            Cmp::Lt => Cmp::Gt,
+            Cmp::Le => Cmp::Ge,
+            Cmp::Gt => Cmp::Lt,
+            Cmp::Ge => Cmp::Le,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Term {
+    Text(String),
+    Tag(String),
+    Base(String),
+    Type(String),
+    /// (tag type, tag name)
+    Namespace(String, String),
+    Collection(String),
+    Creator(String),
+    /// Civitai model id
+    Model(i64),
+    /// File format, precision or size type
+    Format(String),
+    Trigger(String),
+    Size(Cmp, i64),
+    /// Age in milliseconds
+    Added(Cmp, i64),
+    Has(String),
+    /// Permission of license
+    Allow(String),
+    Status(String),
+    Dup(bool),
+    DupWeights,
+    Newest(bool),
+    Rating(Cmp, i64),
+    /// Time since last used in milliseconds
+    Used(Cmp, i64),
+    Uses(Cmp, i64),
+}
+
+#[derive(Debug, PartialEq)]
+struct Clause {
+    negated: bool,
+    term: Term,
+}
+
+#[derive(Clone)]
+pub enum SqlArg {
+    Text(String),
+    Int(i64),
+    Float(f64),
+}
+
+/// Search query compiled to SQL.
+///
+/// `condition` is a boolean expression over table `item`, its parameters are `args`.
+/// `fts_match` is the FTS5 MATCH expression of all positive words, used for ranking and snippet.
+pub struct SearchFilter {
+    pub condition: String,
+    pub args: Vec<SqlArg>,
+    pub fts_match: Option<String>,
+}
+
+impl SearchFilter {
+    /// Parse `search` and compile it.
+    /// If `tag_only` is set, plain words only match tag name.
+    /// `namespaces` is the list of tag types which can be used as `namespace:tag`.
+    pub fn parse(search: &str, tag_only: bool, namespaces: &[String]) -> anyhow::Result<Self> {
+        let groups = parse_groups(search, namespaces)?;
+        let mut filter = SearchFilter {
+            condition: String::new(),
+            args: Vec::new(),
+            fts_match: None,
+        };
+        let mut fts_terms = Vec::new();
+
+        let mut and_parts = Vec::new();
+        for group in groups.iter() {
+            let mut or_parts = Vec::new();
+            for clause in group {
+                let cond = filter.compile_term(&clause.term, tag_only)?;
+                if clause.negated {
+                    // NOT of a nullable column is NULL, e.g. `-added<30d` must match items without creation time
+                    or_parts.push(format!("({cond}) IS NOT TRUE"));
+                } else {
+                    or_parts.push(cond);
+                    if !tag_only
+                        && let Term::Text(text) = &clause.term
+                        && let Some(m) = fts_match_expr(text)
+                    {
+                        fts_terms.push(m);
+                    }
+                }
+            }
+            and_parts.push(format!("({})", or_parts.join(" OR ")));
+        }
+
+        filter.condition = if and_parts.is_empty() { "1".to_string() } else { and_parts.join(" AND ") };
+        if !fts_terms.is_empty() {
+            filter.fts_match = Some(fts_terms.join(" OR "));
+        }
+
+        Ok(filter)
+    }
+
+    fn compile_term(&mut self, term: &Term, tag_only: bool) -> anyhow::Result<String> {
+        let cond = match term {
+            Term::Text(text) => match fts_match_expr(text) {
+                Some(m) if !tag_only => {
+                    self.args.push(SqlArg::Text(m));
+                    let tag_cond = self.tag_cond(TAG_NAME_FILTER, name_args(text));
+                    format!("(item.id IN (SELECT rowid FROM item_fts WHERE item_fts MATCH ?) OR {tag_cond})")
+                }
+                _ => self.tag_cond(TAG_NAME_FILTER, name_args(text)),
+            },
+            Term::Tag(tag) => self.tag_cond(TAG_NAME_FILTER, name_args(tag)),
+            // Same form as tag name, e.g. `type:lora`, `base:sdxl_1.0`
+            Term::Type(model_type) => {
+                self.args.push(SqlArg::Text(tag_name(model_type)));
+                "REPLACE(LOWER(item.model_type), ' ', '_') = ?".to_string()
+            }
+            Term::Base(base) => {
+                self.args
+                    .push(SqlArg::Text(format!("{}%", escape_like(&tag_name(base)))));
+                r"REPLACE(LOWER(item.base_model), ' ', '_') LIKE ? ESCAPE '\'".to_string()
+            }
+            Term::Namespace(namespace, tag) => self.tag_cond(
+                &format!("{TAG_NAME_FILTER} AND tag.type = (SELECT id FROM tag_type WHERE type = ?)"),
+                [name_args(tag), vec![SqlArg::Text(namespace.clone())]].concat(),
+            ),
+            Term::Collection(label) => {
+                self.args.push(SqlArg::Text(label.clone()));
+                "item.base_label = ?".to_string()
+            }
+            Term::Creator(creator) => {
+                self.args.push(SqlArg::Text(creator.clone()));
+                "item.creator = ? COLLATE NOCASE".to_string()
+            }
+            Term::Model(model_id) => {
+                self.args.push(SqlArg::Int(*model_id));
+                "item.civitai_model_id = ?".to_string()
+            }
+            Term::Format(format) => {
+                self.args.push(SqlArg::Text(format.clone()));
+                "? COLLATE NOCASE IN (item.format, item.precision, item.size_type)".to_string()
+            }
+            Term::Trigger(word) => {
+                // Trigger words are one per line
+                self.args
+                    .push(SqlArg::Text(format!("%\n{}\n%", escape_like(word.trim()))));
+                r"(char(10) || item.trained_words || char(10)) LIKE ? ESCAPE '\'".to_string()
+            }
+            Term::Size(cmp, size) => {
+                self.args.push(SqlArg::Int(*size));
+                format!("item.file_size {} ?", cmp.as_sql())
+            }
+            Term::Added(cmp, age) => {
+                self.args.push(SqlArg::Int(time_ago(*age)?));
+                // Less than 30 days ago means added after (now - 30 days)
+                format!("item.created_at {} ?", cmp.reverse().as_sql())
+            }
+            Term::Has(field) => match field.as_str() {
+                "preview" => "item.has_preview = true".to_string(),
+                "note" => "item.note != ''".to_string(),
+                "civitai" => "item.version_name != ''".to_string(),
+                "trigger" => "item.trained_words != ''".to_string(),
+                "description" => "item.description != ''".to_string(),
+                "tag" => "EXISTS (SELECT 1 FROM tag_item WHERE tag_item.item = item.id)".to_string(),
+                "favorite" => "item.favorite = true".to_string(),
+                "rating" => "item.rating IS NOT NULL".to_string(),
+                "nsfw" => "item.nsfw = true".to_string(),
+                "poi" => "item.poi = true".to_string(),
+                "license" => "(item.allow_commercial_use != '' OR item.allow_derivatives IS NOT NULL)".to_string(),
+                "license_change" => "item.license_changed_at IS NOT NULL".to_string(),
+                _ => return Err(anyhow!("Unknown field has:{}", field)),
+            },
+            Term::Allow(permission) => match permission.as_str() {
+                "image" | "rentcivit" | "rent" | "sell" => {
+                    self.args.push(SqlArg::Text(format!("%,{permission},%")));
+                    "(',' || item.allow_commercial_use || ',') LIKE ?".to_string()
+                }
+                "derivatives" => "item.allow_derivatives = true".to_string(),
+                "different_license" => "item.allow_different_license = true".to_string(),
+                "no_credit" => "item.allow_no_credit = true".to_string(),
+                _ => return Err(anyhow!("Unknown permission allow:{}", permission)),
+            },
+            Term::Status(status) => match status.as_str() {
+                "removed_upstream" => "item.removed_upstream_at IS NOT NULL".to_string(),
+                "corrupt" => "item.corrupt_at IS NOT NULL".to_string(),
+                _ => return Err(anyhow!("Unknown status status:{}", status)),
+            },
+            Term::Dup(is_dup) => {
+                let cond = r#"item.blake3 IN (
+                    SELECT blake3 FROM item
+                    WHERE is_checked = true AND blake3 != ''
+                    GROUP BY blake3
+                    HAVING COUNT(*) > 1)"#;
+                if *is_dup { cond.to_string() } else { format!("NOT {cond}") }
+            }
+            Term::DupWeights => r#"item.tensor_hash IN (
+                    SELECT tensor_hash FROM item
+                    WHERE is_checked = true AND tensor_hash != ''
+                    GROUP BY tensor_hash
+                    HAVING COUNT(*) > 1)"#
+                .to_string(),
+            Term::Newest(is_newest) => {
+                // Civitai version ids only increase
+                let cond = r#"(item.civitai_model_id IS NULL OR NOT EXISTS (
+                    SELECT 1 FROM item AS newer
+                    WHERE newer.is_checked = true AND newer.civitai_model_id = item.civitai_model_id
+                        AND (IFNULL(newer.civitai_version_id, 0), newer.id)
+                            > (IFNULL(item.civitai_version_id, 0), item.id)))"#;
+                if *is_newest { cond.to_string() } else { format!("NOT {cond}") }
+            }
+            Term::Rating(cmp, rating) => {
+                self.args.push(SqlArg::Int(*rating));
+                format!("item.rating {} ?", cmp.as_sql())
+            }
+            Term::Used(cmp, age) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+                self.args.push(SqlArg::Int(now - age));
+                match cmp {
+                    Cmp::Gt | Cmp::Ge => format!("IFNULL(item.last_used_at, 0) {} ?", cmp.reverse().as_sql()),
+                    _ => format!("item.last_used_at {} ?", cmp.reverse().as_sql()),
+                }
+            }
+            Term::Uses(cmp, count) => {
+                self.args.push(SqlArg::Int(*count));
+                format!("item.use_count {} ?", cmp.as_sql())
+            }
+        };
+
+        Ok(cond)
+    }
+
+    fn tag_cond(&mut self, tag_filter: &str, args: Vec<SqlArg>) -> String {
+        self.args.extend(args);
+        format!("item.id IN (SELECT tag_item.item FROM tag_item JOIN tag ON tag.id = tag_item.tag WHERE {tag_filter})")
+    }
+}
+
+/// Split query into groups of OR-ed clauses. Groups are AND-ed together.
+fn parse_groups(search: &str, namespaces: &[String]) -> anyhow::Result<Vec<Vec<Clause>>> {
+    let mut groups: Vec<Vec<Clause>> = Vec::new();
+    let mut pending_or = false;
+
+    for token in tokenize(search) {
+        if token == "OR" {
+            pending_or = !groups.is_empty();
+            continue;
+        }
+
+        let clause = parse_clause(&token, namespaces)?;
+        match groups.last_mut() {
+            Some(group) if pending_or => group.push(clause),
+            _ => groups.push(vec![clause]),
+        }
+        pending_or = false;
+    }
+
+    Ok(groups)
+}
+
+/// Split by whitespace, except inside double quotes. Quotes are kept.
+fn tokenize(search: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quote = false;
+
+    for c in search.chars() {
+        if c == '"' {
+            in_quote = !in_quote;
+            current.push(c);
+        } else if c.is_whitespace() && !in_quote {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+fn parse_clause(token: &str, namespaces: &[String]) -> anyhow::Result<Clause> {
+    let (negated, token) = match token.strip_prefix('-') {
+        Some(rest) if !rest.is_empty() => (true, rest),
+        _ => (false, token),
+    };
+
+    // Quoted token is always plain text
+    if token.starts_with('"') {
+        return Ok(Clause {
+            negated,
+            term: Term::Text(unquote(token)),
+        });
+    }
+
+    let field_len = token
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(token.len());
+    let (field, rest) = token.split_at(field_len);
+    let (cmp, value) = if let Some(v) = rest.strip_prefix(">=") {
+        (Cmp::Ge, v)
+    } else if let Some(v) = rest.strip_prefix("<=") {
+        (Cmp::Le, v)
+    } else if let Some(v) = rest.strip_prefix('>') {
+        (Cmp::Gt, v)
+    } else if let Some(v) = rest.strip_prefix('<') {
+        (Cmp::Lt, v)
+    } else if let Some(v) = rest.strip_prefix('=').or(rest.strip_prefix(':')) {
+        (Cmp::Eq, v)
+    } else {
+        return Ok(Clause {
+            negated,
+            term: Term::Text(unquote(token)),
+        });
+    };
+    let value = unquote(value);
+    let is_colon = rest.starts_with(':');
+
+    let field = field.to_lowercase();
+    let term = match field.as_str() {
+        "tag" if is_colon => match value.split_once(':') {
+            Some((namespace, tag)) if namespaces.iter().any(|n| n == namespace) => {
+                Term::Namespace(namespace.to_string(), tag.to_string())
+            }
+            _ => Term::Tag(value),
+        },
+        "base" if is_colon => Term::Base(value),
+        "type" if is_colon => Term::Type(value),
+        "collection" if is_colon => Term::Collection(value),
+        "creator" if is_colon => Term::Creator(value),
+        "model" if is_colon => Term::Model(value.parse().map_err(|_| anyhow!("Invalid model id {}", value))?),
+        "format" if is_colon => Term::Format(value),
+        "trigger" if is_colon => Term::Trigger(value),
+        "has" if is_colon => Term::Has(value.to_lowercase()),
+        "allow" if is_colon => Term::Allow(value.to_lowercase()),
+        "status" if is_colon => Term::Status(value.to_lowercase()),
+        "dup" if is_colon => match value.to_lowercase().as_str() {
+            "true" | "yes" | "1" => Term::Dup(true),
+            "false" | "no" | "0" => Term::Dup(false),
+            "weights" => Term::DupWeights,
+            _ => return Err(anyhow!("Invalid value dup:{}", value)),
+        },
+        "newest" if is_colon => match value.to_lowercase().as_str() {
+            "true" | "yes" | "1" => Term::Newest(true),
+            "false" | "no" | "0" => Term::Newest(false),
+            _ => return Err(anyhow!("Invalid value newest:{}", value)),
+        },
+        "size" => Term::Size(cmp, parse_size(&value)?),
+        "added" => {
+            if cmp == Cmp::Eq {
+                return Err(anyhow!("added only supports <, <=, > and >="));
+            }
+            Term::Added(cmp, parse_age(&value)?)
+        }
+        "used" => {
+            if cmp == Cmp::Eq {
+                return Err(anyhow!("used only supports <, <=, > and >="));
+            }
+            Term::Used(cmp, parse_age(&value)?)
+        }
+        "rating" => Term::Rating(cmp, value.parse().map_err(|_| anyhow!("Invalid rating {}", value))?),
+        "uses" => Term::Uses(cmp, value.parse().map_err(|_| anyhow!("Invalid number {}", value))?),
+        _ if is_colon && namespaces.contains(&field) => Term::Namespace(field, value),
+        _ => Term::Text(unquote(token)),
+    };
+
+    Ok(Clause { negated, term })
+}
+
+fn unquote(s: &str) -> String {
+    s.replace('"', "")
+}
+
+/// Parse size like `2GB`, `500mb` or `1024` to bytes
+fn parse_size(value: &str) -> anyhow::Result<i64> {
+    let value = value.to_uppercase();
+    let unit_pos = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_pos);
+    let number: f64 = number.parse().map_err(|_| anyhow!("Invalid size {}", value))?;
+    let multiplier: i64 = match unit {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(anyhow!("Invalid size unit {}", unit)),
+    };
+
+    Ok((number * multiplier as f64) as i64)
+}
+
+/// Parse age like `12h`, `30d`, `2w` or `1y` to milliseconds
+fn parse_age(value: &str) -> anyhow::Result<i64> {
+    let value = value.to_lowercase();
+    let unit_pos = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_pos);
+    let number: i64 = number.parse().map_err(|_| anyhow!("Invalid duration {}", value))?;
+    let hour: i64 = 60 * 60 * 1000;
+    let multiplier = match unit {
+        "h" => hour,
+        "" | "d" => 24 * hour,
+        "w" => 7 * 24 * hour,
+        "m" => 30 * 24 * hour,
+        "y" => 365 * 24 * hour,
+        _ => return Err(anyhow!("Invalid duration unit {}", unit)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Duration {} is too long", value))
+}
+
+/// Unix time in milliseconds `age` milliseconds ago
+fn time_ago(age: i64) -> anyhow::Result<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    now.checked_sub(age).ok_or_else(|| anyhow!("Duration is too long"))
+}
+
+/// Tag names are stored in lowercase with underscore instead of space
+fn tag_name(s: &str) -> String {
+    s.replace(' ', "_").to_lowercase()
+}
+
+/// Match tag by name or alias. Parameters are from `name_args()`.
+const TAG_NAME_FILTER: &str = "(tag.name = ? OR tag.id IN (SELECT tag FROM tag_alias WHERE alias = ?))";
+
+fn name_args(name: &str) -> Vec<SqlArg> {
+    let name = tag_name(name);
+    vec![SqlArg::Text(name.clone()), SqlArg::Text(name)]
+}
+
+fn escape_like(s: &str) -> String {
+    s.replace('\\', r"\\").replace('%', r"\%").replace('_', r"\_")
+}
+
+/// Convert a word or phrase into a FTS5 MATCH expression.
+///
+/// Text is quoted so that FTS5 operators typed by user are matched literally. A single word is used as a prefix.
+/// Return `None` if there is nothing to search.
+fn fts_match_expr(text: &str) -> Option<String> {
+    if !text.chars().any(char::is_alphanumeric) {
+        return None;
+    }
+
+    let quoted = format!("\"{}\"", text.replace('"', "\"\""));
+    if text.contains(char::is_whitespace) { Some(quoted) } else { Some(format!("{quoted}*")) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::AssertSqlSafe;
+
+    const HOUR: i64 = 60 * 60 * 1000;
+
+    fn namespaces() -> Vec<String> {
+        vec!["style".to_string()]
+    }
+
+    fn clause(token: &str) -> Clause {
+        parse_clause(token, &namespaces()).unwrap()
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_phrases() {
+        assert_eq!(
+            tokenize(r#"  anime  trigger:"blue hair" -tag:x OR "a  b" "#),
+            vec!["anime", r#"trigger:"blue hair""#, "-tag:x", "OR", r#""a  b""#]
+        );
+        assert!(tokenize("   ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_unclosed_quote_takes_the_rest() {
+        assert_eq!(tokenize(r#"a "b c"#), vec!["a", r#""b c"#]);
+    }
+
+    #[test]
+    fn parse_clause_text() {
+        assert_eq!(
+            clause("anime"),
+            Clause {
+                negated: false,
+                term: Term::Text("anime".to_string())
+            }
+        );
+        assert_eq!(clause(r#"-"size>1""#).term, Term::Text("size>1".to_string()));
+        assert!(clause(r#"-"size>1""#).negated);
+        assert_eq!(clause("-").term, Term::Text("-".to_string()));
+        assert_eq!(clause("unknown:x").term, Term::Text("unknown:x".to_string()));
+    }
+
+    #[test]
+    fn parse_clause_fields() {
+        assert_eq!(clause("tag:x").term, Term::Tag("x".to_string()));
+        assert_eq!(clause(r#"base:"SD 1.5""#).term, Term::Base("SD 1.5".to_string()));
+        assert_eq!(
+            clause("tag:style:anime").term,
+            Term::Namespace("style".to_string(), "anime".to_string())
+        );
+        assert_eq!(
+            clause("style:anime").term,
+            Term::Namespace("style".to_string(), "anime".to_string())
+        );
+        assert_eq!(clause("creator:someone").term, Term::Creator("someone".to_string()));
+        assert_eq!(clause("model:123").term, Term::Model(123));
+        assert_eq!(clause("format:fp16").term, Term::Format("fp16".to_string()));
+        assert_eq!(clause("HAS:Preview").term, Term::Has("preview".to_string()));
+        assert_eq!(clause("allow:Sell").term, Term::Allow("sell".to_string()));
+        assert_eq!(
+            clause("status:removed_upstream").term,
+            Term::Status("removed_upstream".to_string())
+        );
+        assert_eq!(clause("status:Corrupt").term, Term::Status("corrupt".to_string()));
+        assert_eq!(clause("dup:yes").term, Term::Dup(true));
+        assert_eq!(clause("dup:no").term, Term::Dup(false));
+        assert_eq!(clause("dup:weights").term, Term::DupWeights);
+        assert_eq!(clause("newest:true").term, Term::Newest(true));
+        assert_eq!(clause("size>2GB").term, Term::Size(Cmp::Gt, 2 << 30));
+        assert_eq!(clause("added<30d").term, Term::Added(Cmp::Lt, 30 * 24 * HOUR));
+        assert!(clause("-size>=4GB").negated);
+    }
+
+    #[test]
+    fn parse_clause_errors() {
+        let namespaces = namespaces();
+        assert!(parse_clause("added:30d", &namespaces).is_err());
+        assert!(parse_clause("added=1d", &namespaces).is_err());
+        assert!(parse_clause("dup:maybe", &namespaces).is_err());
+        assert!(parse_clause("model:abc", &namespaces).is_err());
+        assert!(parse_clause("size>big", &namespaces).is_err());
+        assert!(parse_clause("added<999999999999y", &namespaces).is_err());
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("1b").unwrap(), 1);
+        assert_eq!(parse_size("2K").unwrap(), 2048);
+        assert_eq!(parse_size("500mb").unwrap(), 500 << 20);
+        assert_eq!(parse_size("1.5GB").unwrap(), 3 << 29);
+        assert_eq!(parse_size("1tb").unwrap(), 1 << 40);
+        assert!(parse_size("1PB").is_err());
+        assert!(parse_size("GB").is_err());
+    }
+
+    #[test]
+    fn parse_age_units() {
+        assert_eq!(parse_age("12h").unwrap(), 12 * HOUR);
+        assert_eq!(parse_age("3").unwrap(), 3 * 24 * HOUR);
+        assert_eq!(parse_age("2W").unwrap(), 14 * 24 * HOUR);
+        assert_eq!(parse_age("6m").unwrap(), 180 * 24 * HOUR);
+        assert_eq!(parse_age("1y").unwrap(), 365 * 24 * HOUR);
+        assert!(parse_age("1s").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("-1d").is_err());
+    }
+
+    #[test]
+    fn parse_age_overflow() {
+        assert!(parse_age("999999999999y").is_err());
+        assert!(parse_age("99999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = encode_cursor(&SortValue::Text("Ünïcode \"name\"".to_string()), 42);
+        assert!(cursor.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert!(matches!(
+            decode_cursor(&cursor).unwrap(),
+            (SortValue::Text(s), 42) if s == "Ünïcode \"name\""
+        ));
+        let cursor = encode_cursor(&SortValue::Int(-7), 1);
+        assert!(matches!(decode_cursor(&cursor).unwrap(), (SortValue::Int(-7), 1)));
+        let cursor = encode_cursor(&SortValue::Float(4.5), 2);
+        assert!(matches!(decode_cursor(&cursor).unwrap(), (SortValue::Float(f), 2) if f == 4.5));
+    }
+
+    #[test]
+    fn invalid_cursor() {
+        let valid = encode_cursor(&SortValue::Int(1), 1);
+        assert!(decode_cursor(&valid[1..]).is_err());
+        assert!(decode_cursor("zz").is_err());
+        assert!(decode_cursor("éé").is_err());
+        // Hex of `[1]`
+        assert!(decode_cursor("5b315d").is_err());
+        assert!(decode_cursor("").is_err());
+    }
+
+    /// Ids of the items matching `search`, from rows with NULL creation time
+    async fn matching_ids(search: &str) -> Vec<i64> {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("CREATE TABLE item (id INTEGER PRIMARY KEY, created_at INTEGER)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let now = time_ago(0).unwrap();
+        sqlx::query("INSERT INTO item VALUES (1, ?), (2, ?), (3, NULL)")
+            .bind(now)
+            .bind(now - 60 * 24 * HOUR)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let filter = SearchFilter::parse(search, false, &namespaces()).unwrap();
+        let sql = format!("SELECT id FROM item WHERE {} ORDER BY id", filter.condition);
+        let mut query = sqlx::query_scalar::<_, i64>(AssertSqlSafe(sql));
+        for arg in filter.args {
+            query = match arg {
+                SqlArg::Text(s) => query.bind(s),
+                SqlArg::Int(i) => query.bind(i),
+                SqlArg::Float(f) => query.bind(f),
+            };
+        }
+        query.fetch_all(&pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn negated_added_matches_unknown_creation_time() {
+        assert_eq!(matching_ids("added<30d").await, vec![1]);
+        assert_eq!(matching_ids("-added<30d").await, vec![2, 3]);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::db::item::{SearchOptions, search};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedSearch {
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub search: String,
+    #[serde(default)]
+    pub tag_only: bool,
+    #[serde(default)]
+    pub duplicate_only: bool,
+}
+
+pub async fn list(pool: &SqlitePool) -> Result<Vec<SavedSearch>, sqlx::Error> {
+    sqlx::query_as!(
+        SavedSearch,
+        r#"SELECT id, name, search, tag_only as "tag_only: bool", duplicate_only as "duplicate_only: bool"
+        FROM saved_search ORDER BY name"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get(pool: &SqlitePool, id: i64) -> Result<SavedSearch, sqlx::Error> {
+    sqlx::query_as!(
+        SavedSearch,
+        r#"SELECT id, name, search, tag_only as "tag_only: bool", duplicate_only as "duplicate_only: bool"
+        FROM saved_search WHERE id = ?"#,
+        id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn add(pool: &SqlitePool, saved_search: &SavedSearch) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        "INSERT INTO saved_search (name, search, tag_only, duplicate_only) VALUES (?, ?, ?, ?)",
+        saved_search.name,
+        saved_search.search,
+        saved_search.tag_only,
+        saved_search.duplicate_only
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn update(pool: &SqlitePool, saved_search: &SavedSearch) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE saved_search SET name = ?, search = ?, tag_only = ?, duplicate_only = ? WHERE id = ?",
+        saved_search.name,
+        saved_search.search,
+        saved_search.tag_only,
+        saved_search.duplicate_only,
+        saved_search.id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM saved_search WHERE id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Number of items currently matching saved search
+pub async fn count_items(pool: &SqlitePool, saved_search: &SavedSearch) -> anyhow::Result<i64> {
+    let opts = SearchOptions {
+        search: &saved_search.search,
+        tag_only: saved_search.tag_only,
+        duplicate_only: saved_search.duplicate_only,
+        limit: 1,
+        ..Default::default()
+    };
+    Ok(search(pool, &opts).await?.total)
+}
@@ -0,0 +1,321 @@
+//! Disk usage of items, as totals, breakdowns and daily snapshots. Only items which are not obsolete are counted.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Serialize, Default)]
+pub struct Totals {
+    pub item_count: i64,
+    /// Sum of file size of all items, including files which are links
+    pub total_bytes: i64,
+    /// Bytes used on disk, which is `total_bytes` minus bytes reclaimed by dedupe
+    pub disk_bytes: i64,
+}
+
+/// Disk usage of items with the same collection, base model, model type, tag or folder
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Usage {
+    pub key: String,
+    pub item_count: i64,
+    pub total_bytes: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct Duplicates {
+    /// Number of BLAKE3 with more than one item
+    pub group_count: i64,
+    /// Number of items which are extra copies, i.e. all items of groups except one per group
+    pub item_count: i64,
+    /// Size of the extra copies
+    pub total_bytes: i64,
+    /// Bytes already reclaimed by replacing copies with links
+    pub reclaimed_bytes: i64,
+}
+
+#[derive(Serialize)]
+pub struct LargestItem {
+    pub id: i64,
+    pub name: String,
+    pub base_label: String,
+    pub path: String,
+    pub file_size: i64,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    /// `YYYY-MM-DD` in UTC
+    pub day: String,
+    pub base_label: String,
+    pub item_count: i64,
+    pub total_bytes: i64,
+}
+
+pub async fn totals(pool: &SqlitePool) -> Result<Totals, sqlx::Error> {
+    sqlx::query_as!(
+        Totals,
+        r#"SELECT COUNT(*) as "item_count!: i64", IFNULL(SUM(file_size), 0) as "total_bytes!: i64",
+            IFNULL(SUM(file_size), 0) - IFNULL((SELECT SUM(reclaimed_bytes) FROM item_link
+                JOIN item ON item.id = item_link.item WHERE item.is_checked = true), 0) as "disk_bytes!: i64"
+        FROM item
+        WHERE is_checked = true"#
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn by_collection(pool: &SqlitePool) -> Result<Vec<Usage>, sqlx::Error> {
+    sqlx::query_as!(
+        Usage,
+        r#"SELECT base_label as key, COUNT(*) as "item_count!: i64", SUM(file_size) as "total_bytes!: i64"
+        FROM item
+        WHERE is_checked = true
+        GROUP BY base_label
+        ORDER BY SUM(file_size) DESC, key"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Sum of file size of items in collection `base_label`
+pub async fn collection_bytes(pool: &SqlitePool, base_label: &str) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT IFNULL(SUM(file_size), 0) as "total_bytes!: i64" FROM item WHERE is_checked = true AND base_label = ?"#,
+        base_label
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Usage by base model, empty key for items without base model
+pub async fn by_base_model(pool: &SqlitePool) -> Result<Vec<Usage>, sqlx::Error> {
+    sqlx::query_as!(
+        Usage,
+        r#"SELECT base_model as key, COUNT(*) as "item_count!: i64", SUM(file_size) as "total_bytes!: i64"
+        FROM item
+        WHERE is_checked = true
+        GROUP BY base_model
+        ORDER BY SUM(file_size) DESC, key"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Usage by model type, empty key for items without model type
+pub async fn by_model_type(pool: &SqlitePool) -> Result<Vec<Usage>, sqlx::Error> {
+    sqlx::query_as!(
+        Usage,
+        r#"SELECT model_type as key, COUNT(*) as "item_count!: i64", SUM(file_size) as "total_bytes!: i64"
+        FROM item
+        WHERE is_checked = true
+        GROUP BY model_type
+        ORDER BY SUM(file_size) DESC, key"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Usage by tag. An item with several tags is counted for each of them.
+pub async fn by_tag(pool: &SqlitePool) -> Result<Vec<Usage>, sqlx::Error> {
+    sqlx::query_as!(
+        Usage,
+        r#"SELECT tag.name as key, COUNT(*) as "item_count!: i64", SUM(item.file_size) as "total_bytes!: i64"
+        FROM tag_item
+        JOIN tag ON tag.id = tag_item.tag
+        JOIN item ON item.id = tag_item.item
+        WHERE item.is_checked = true
+        GROUP BY tag.id
+        ORDER BY SUM(file_size) DESC, key"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Usage by folder of model files, as `<collection>/<folder>`. Files in subfolders are only counted for their own folder.
+pub async fn by_folder(pool: &SqlitePool) -> Result<Vec<Usage>, sqlx::Error> {
+    let items = sqlx::query!("SELECT base_label, path, file_size FROM item WHERE is_checked = true")
+        .fetch_all(pool)
+        .await?;
+    Ok(group_by_folder(items.iter().map(|item| {
+        (item.base_label.as_str(), item.path.as_str(), item.file_size)
+    })))
+}
+
+fn group_by_folder<'a>(items: impl Iterator<Item = (&'a str, &'a str, i64)>) -> Vec<Usage> {
+    let mut folders = HashMap::<String, (i64, i64)>::new();
+    for (label, path, file_size) in items {
+        let folder = Path::new(path).parent().and_then(|p| p.to_str()).unwrap_or_default();
+        let key = if folder.is_empty() { label.to_string() } else { format!("{label}/{}", folder.replace('\\', "/")) };
+        let usage = folders.entry(key).or_default();
+        usage.0 += 1;
+        usage.1 += file_size;
+    }
+    let mut usages = folders
+        .into_iter()
+        .map(|(key, (item_count, total_bytes))| Usage {
+            key,
+            item_count,
+            total_bytes,
+        })
+        .collect::<Vec<_>>();
+    usages.sort_by(|a, b| b.total_bytes.cmp(&a.total_bytes).then_with(|| a.key.cmp(&b.key)));
+    usages
+}
+
+pub async fn duplicates(pool: &SqlitePool) -> Result<Duplicates, sqlx::Error> {
+    sqlx::query_as!(
+        Duplicates,
+        r#"SELECT COUNT(*) as "group_count!: i64", IFNULL(SUM(item_count - 1), 0) as "item_count!: i64",
+            IFNULL(SUM((item_count - 1) * file_size), 0) as "total_bytes!: i64",
+            IFNULL((SELECT SUM(reclaimed_bytes) FROM item_link
+                JOIN item ON item.id = item_link.item WHERE item.is_checked = true), 0) as "reclaimed_bytes!: i64"
+        FROM (SELECT COUNT(*) as item_count, MAX(file_size) as file_size
+            FROM item
+            WHERE is_checked = true AND blake3 != ''
+            GROUP BY blake3
+            HAVING COUNT(*) > 1)"#
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// `limit` largest items, largest first
+pub async fn largest(pool: &SqlitePool, limit: i64) -> Result<Vec<LargestItem>, sqlx::Error> {
+    sqlx::query_as!(
+        LargestItem,
+        r#"SELECT id, name, base_label, path, file_size
+        FROM item
+        WHERE is_checked = true
+        ORDER BY file_size DESC, id
+        LIMIT ?"#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Save usage of each collection as snapshot of today, replacing an earlier snapshot of today
+pub async fn save_snapshot(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT OR REPLACE INTO stats_snapshot (day, base_label, item_count, total_bytes)
+        SELECT date('now'), base_label, COUNT(*), SUM(file_size)
+        FROM item
+        WHERE is_checked = true
+        GROUP BY base_label"#
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Snapshots of the last `days` days, oldest first
+pub async fn list_snapshots(pool: &SqlitePool, days: i64) -> Result<Vec<Snapshot>, sqlx::Error> {
+    sqlx::query_as!(
+        Snapshot,
+        r#"SELECT day, base_label, item_count, total_bytes
+        FROM stats_snapshot
+        WHERE day > date('now', '-' || ? || ' days')
+        ORDER BY day, base_label"#,
+        days
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn usage_of_items() {
+        let pool = test_pool().await;
+        sqlx::query(
+            r#"INSERT INTO item (id, path, base_label, name, blake3, file_size, base_model, is_checked) VALUES
+                (1, 'a/1.safetensors', 'lora', '1', 'x', 10, 'SDXL', true),
+                (2, 'a/2.safetensors', 'lora', '2', 'x', 10, 'SDXL', true),
+                (3, '3.safetensors', 'checkpoint', '3', 'x', 10, 'SD 1.5', true),
+                (4, '4.safetensors', 'checkpoint', '4', 'y', 100, 'SDXL', true),
+                (5, '5.safetensors', 'checkpoint', '5', 'z', 1000, 'SDXL', false)"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO item_link (item, canonical, link_type, reclaimed_bytes, linked_at) VALUES (2, 1, 'hardlink', 10, 0)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let totals = totals(&pool).await.unwrap();
+        assert_eq!(
+            (totals.item_count, totals.total_bytes, totals.disk_bytes),
+            (4, 130, 120)
+        );
+        assert_eq!(
+            by_base_model(&pool).await.unwrap(),
+            vec![
+                Usage {
+                    key: "SDXL".to_string(),
+                    item_count: 3,
+                    total_bytes: 120
+                },
+                Usage {
+                    key: "SD 1.5".to_string(),
+                    item_count: 1,
+                    total_bytes: 10
+                },
+            ]
+        );
+        let duplicates = duplicates(&pool).await.unwrap();
+        assert_eq!(
+            (
+                duplicates.group_count,
+                duplicates.item_count,
+                duplicates.total_bytes,
+                duplicates.reclaimed_bytes
+            ),
+            (1, 2, 20, 10)
+        );
+        assert_eq!(
+            largest(&pool, 2)
+                .await
+                .unwrap()
+                .iter()
+                .map(|item| item.id)
+                .collect::<Vec<_>>(),
+            vec![4, 1]
+        );
+
+        save_snapshot(&pool).await.unwrap();
+        save_snapshot(&pool).await.unwrap();
+        let snapshots = list_snapshots(&pool, 30).await.unwrap();
+        assert_eq!(
+            snapshots
+                .iter()
+                .map(|s| (s.base_label.as_str(), s.item_count, s.total_bytes))
+                .collect::<Vec<_>>(),
+            vec![("checkpoint", 2, 110), ("lora", 2, 20)]
+        );
+    }
+
+    #[test]
+    fn folders_of_items() {
+        let usages = group_by_folder(
+            [
+                ("lora", "style/a.safetensors", 5),
+                ("lora", "style/b.safetensors", 5),
+                ("lora", "style/anime/c.safetensors", 20),
+                ("lora", "d.safetensors", 1),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(
+            usages
+                .iter()
+                .map(|u| (u.key.as_str(), u.item_count, u.total_bytes))
+                .collect::<Vec<_>>(),
+            vec![("lora/style/anime", 1, 20), ("lora/style", 2, 10), ("lora", 1, 1)]
+        );
+    }
+}
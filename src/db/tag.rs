@@ -1,13 +1,24 @@
-use crate::civitai::CivitaiFileMetadata;
+use crate::db::history::{item_state, record_item_changes};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
 use std::collections::HashSet;
 
+/// Tag type of tags which are created by user
+pub const USER_TAG_TYPE: &str = "user";
+/// Tag type of tags which are created from tags of Civitai model
+pub const CIVITAI_TAG_TYPE: &str = "style";
+/// Namespaces of tags which were added from model info before it had its own item fields. They are removed from items
+/// by migration, and ignored in sidecars written before.
+pub const AUTO_TAG_NAMESPACES: [&str; 3] = ["base", "type", "format"];
+/// Tag of items whose file does not match the hash recorded by Civitai or cannot be read
+pub const CORRUPT_TAG: &str = "corrupt";
+
 #[derive(Serialize, Deserialize, FromRow)]
 pub struct TagCount {
     pub tag: String,
     pub count: i64,
+    pub tag_type: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -16,14 +27,210 @@ pub struct Tag {
     pub name: String,
     pub description: String,
     pub deps: Option<String>,
+    /// Namespace of tag. Set to empty string to remove namespace.
+    #[serde(default)]
+    pub tag_type: Option<String>,
+    /// Other names which resolve to this tag, separated by space
+    #[serde(default)]
+    pub aliases: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct TagType {
+    pub id: i64,
+    pub name: String,
 }
 
 pub async fn get_tag_by_name(pool: &SqlitePool, name: &str) -> Result<Tag, sqlx::Error> {
-    sqlx::query_as!(Tag, r#"SELECT tmp.id as id, tmp.name as name , tmp.description as description, GROUP_CONCAT(tag.name, ' ') as "deps:_"
+    sqlx::query_as!(Tag, r#"SELECT tmp.id as id, tmp.name as name , tmp.description as description, GROUP_CONCAT(tag.name, ' ') as "deps:_",
+    tmp.tag_type as "tag_type?",
+    (SELECT GROUP_CONCAT(alias, ' ') FROM tag_alias WHERE tag_alias.tag = tmp.id) as "aliases:_"
 FROM
-    (SELECT tag.id as id, tag.name as name, tag.description as description, tag_tag.dep as dep FROM tag LEFT JOIN tag_tag ON tag.id = tag_tag.tag) as tmp
-LEFT JOIN tag ON tag.id = tmp.dep WHERE tmp.name = ? GROUP BY tmp.id
-"#, name).fetch_one(pool).await
+    (SELECT tag.id as id, tag.name as name, tag.description as description, tag_tag.dep as dep, tag_type.type as tag_type
+    FROM tag
+    LEFT JOIN tag_tag ON tag.id = tag_tag.tag
+    LEFT JOIN tag_type ON tag_type.id = tag.type) as tmp
+LEFT JOIN tag ON tag.id = tmp.dep
+WHERE tmp.name = ? OR tmp.id = (SELECT tag FROM tag_alias WHERE alias = ?)
+GROUP BY tmp.id
+"#, name, name).fetch_one(pool).await
+}
+
+/// Find tag by name or alias
+pub async fn find_tag_id(conn: &mut SqliteConnection, name: &str) -> Result<Option<i64>, sqlx::Error> {
+    if let Some(id) = sqlx::query_scalar!("SELECT id FROM tag WHERE name = ?", name)
+        .fetch_optional(&mut *conn)
+        .await?
+    {
+        return Ok(Some(id));
+    }
+    sqlx::query_scalar!("SELECT tag FROM tag_alias WHERE alias = ?", name)
+        .fetch_optional(conn)
+        .await
+}
+
+/// Move all items and dependencies of tag `from` to tag `into`, then delete `from` and keep its name as an alias.
+/// Changed tags of items are recorded in history by `user`. Return items which had `from`.
+pub async fn merge_tag(pool: &SqlitePool, user: &str, from: i64, into: i64) -> anyhow::Result<Vec<i64>> {
+    let mut tx = pool.begin().await?;
+    let items = sqlx::query_scalar!("SELECT item FROM tag_item WHERE tag = ?", from)
+        .fetch_all(&mut *tx)
+        .await?;
+    let mut states = Vec::with_capacity(items.len());
+    for item in items.iter() {
+        states.push(item_state(&mut tx, *item).await?);
+    }
+    merge_into(&mut tx, from, into).await?;
+    for (item, before) in items.iter().zip(states.iter()) {
+        record_item_changes(&mut tx, user, *item, before).await?;
+    }
+    tx.commit().await?;
+    Ok(items)
+}
+
+/// Merge tag `from` into `into` in the transaction of `conn`. Caller must roll back if it fails.
+async fn merge_into(conn: &mut SqliteConnection, from: i64, into: i64) -> anyhow::Result<()> {
+    if from == into {
+        return Err(anyhow::anyhow!("Cannot merge tag into itself"));
+    }
+
+    let from_name = sqlx::query_scalar!("SELECT name FROM tag WHERE id = ?", from)
+        .fetch_one(&mut *conn)
+        .await?;
+    sqlx::query!(
+        "INSERT OR IGNORE INTO tag_item (tag, item) SELECT ?, item FROM tag_item WHERE tag = ?",
+        into,
+        from
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "INSERT OR IGNORE INTO tag_tag (tag, dep) SELECT ?, dep FROM tag_tag WHERE tag = ? AND dep != ?",
+        into,
+        from,
+        into
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "INSERT OR IGNORE INTO tag_tag (tag, dep) SELECT tag, ? FROM tag_tag WHERE dep = ? AND tag != ?",
+        into,
+        from,
+        into
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!("UPDATE tag_alias SET tag = ? WHERE tag = ?", into, from)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!(
+        "UPDATE tag SET description = (SELECT description FROM tag WHERE id = ?) WHERE id = ? AND description = ''",
+        from,
+        into
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!("DELETE FROM tag WHERE id = ?", from)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!(
+        "INSERT OR REPLACE INTO tag_alias (alias, tag) VALUES (?, ?)",
+        from_name,
+        into
+    )
+    .execute(&mut *conn)
+    .await?;
+    if implies(conn, into, into).await? {
+        return Err(anyhow::anyhow!(
+            "Merging {} would create a cycle of implications",
+            from_name
+        ));
+    }
+    Ok(())
+}
+
+pub async fn list_types(pool: &SqlitePool) -> Result<Vec<TagType>, sqlx::Error> {
+    sqlx::query_as!(TagType, "SELECT id, type as name FROM tag_type ORDER BY type")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn add_type(pool: &SqlitePool, name: &str) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!("INSERT INTO tag_type (type) VALUES (?)", name)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn update_type(pool: &SqlitePool, tag_type: &TagType) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE tag_type SET type = ? WHERE id = ?", tag_type.name, tag_type.id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Delete tag type. Its tags are kept without namespace.
+pub async fn delete_type(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("UPDATE tag SET type = NULL WHERE type = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM tag_type WHERE id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+async fn get_type_id(conn: &mut SqliteConnection, name: &str) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar!("SELECT id FROM tag_type WHERE type = ?", name)
+        .fetch_optional(conn)
+        .await
+}
+
+/// Split `namespace:name` if namespace is a known tag type
+pub async fn split_namespace(conn: &mut SqliteConnection, tag: &str) -> Result<(String, Option<i64>), sqlx::Error> {
+    if let Some((namespace, name)) = tag.split_once(':')
+        && !name.is_empty()
+        && let Some(type_id) = get_type_id(conn, namespace).await?
+    {
+        return Ok((name.to_string(), Some(type_id)));
+    }
+    Ok((tag.to_string(), None))
+}
+
+/// Return id of tag, create it if not exist.
+///
+/// Namespace in `namespace:name` is always set to the tag. `default_type` is only used for new tag without namespace.
+pub async fn get_or_create_tag(
+    conn: &mut SqliteConnection,
+    tag: &str,
+    default_type: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    let (name, type_id) = split_namespace(conn, tag).await?;
+
+    match find_tag_id(conn, &name).await? {
+        Some(id) => {
+            if let Some(type_id) = type_id {
+                sqlx::query!("UPDATE tag SET type = ? WHERE id = ?", type_id, id)
+                    .execute(conn)
+                    .await?;
+            }
+            Ok(id)
+        }
+        None => {
+            let type_id = match (type_id, default_type) {
+                (Some(type_id), _) => Some(type_id),
+                (None, Some(default_type)) => get_type_id(conn, default_type).await?,
+                (None, None) => None,
+            };
+            let id = sqlx::query!("INSERT INTO tag (name, type) VALUES (?, ?)", name, type_id)
+                .execute(conn)
+                .await?
+                .last_insert_rowid();
+            Ok(id)
+        }
+    }
 }
 
 // pub async fn add_tag(pool: &SqlitePool, name: &str) -> anyhow::Result<()> {
@@ -38,46 +245,219 @@ pub async fn delete(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub async fn update_tag(pool: &SqlitePool, tag: &Tag) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        "UPDATE tag SET name = ?, description = ? WHERE id = ?",
-        tag.name,
-        tag.description,
-        tag.id
+/// Return true if `from` implies `to` through one or more dependencies
+pub async fn implies(conn: &mut SqliteConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        r#"WITH RECURSIVE closure(id) AS (
+            SELECT dep FROM tag_tag WHERE tag = ?
+            UNION
+            SELECT tag_tag.dep FROM tag_tag JOIN closure ON tag_tag.tag = closure.id)
+        SELECT COUNT(*) as "count!: i64" FROM closure WHERE id = ?"#,
+        from,
+        to
     )
-    .execute(pool)
+    .fetch_one(conn)
     .await?;
+    Ok(count > 0)
+}
 
-    for dep in tag
-        .deps
-        .clone()
-        .unwrap_or_default()
-        .to_lowercase()
-        .split_whitespace()
-        .collect::<Vec<&str>>()
-    {
-        let dep_id = match sqlx::query_scalar!("SELECT id FROM tag WHERE name = ?", dep)
-            .fetch_one(pool)
-            .await
+/// Add implied tags to all items which have `tag`, or all tags if `None`. Changes are recorded in history by `user`.
+/// Return number of added tag-item pairs and the items which got new tags.
+pub async fn apply_implications(
+    pool: &SqlitePool,
+    user: &str,
+    tag: Option<i64>,
+) -> Result<(u64, Vec<i64>), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let items = sqlx::query_scalar!(
+        r#"WITH RECURSIVE closure(tag, dep) AS (
+            SELECT tag, dep FROM tag_tag
+            UNION
+            SELECT closure.tag, tag_tag.dep FROM closure JOIN tag_tag ON tag_tag.tag = closure.dep)
+        SELECT DISTINCT tag_item.item FROM tag_item JOIN closure ON closure.tag = tag_item.tag
+        WHERE (? IS NULL OR tag_item.tag = ?)
+            AND NOT EXISTS (SELECT 1 FROM tag_item AS t WHERE t.item = tag_item.item AND t.tag = closure.dep)
+        ORDER BY tag_item.item"#,
+        tag,
+        tag
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut states = Vec::with_capacity(items.len());
+    for item in items.iter() {
+        states.push(item_state(&mut tx, *item).await?);
+    }
+    let count = sqlx::query!(
+        r#"WITH RECURSIVE closure(tag, dep) AS (
+            SELECT tag, dep FROM tag_tag
+            UNION
+            SELECT closure.tag, tag_tag.dep FROM closure JOIN tag_tag ON tag_tag.tag = closure.dep)
+        INSERT OR IGNORE INTO tag_item (tag, item)
+        SELECT closure.dep, tag_item.item FROM tag_item JOIN closure ON closure.tag = tag_item.tag
+        WHERE ? IS NULL OR tag_item.tag = ?"#,
+        tag,
+        tag
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    for (item, before) in items.iter().zip(states.iter()) {
+        record_item_changes(&mut tx, user, *item, before).await?;
+    }
+    tx.commit().await?;
+    Ok((count, items))
+}
+
+/// Count items which would get new tags if `tag` implies `deps`
+pub async fn preview_implications(pool: &SqlitePool, tag: i64, deps: &[String]) -> anyhow::Result<i64> {
+    let mut conn = pool.acquire().await?;
+    let mut dep_ids = Vec::new();
+    for dep in deps {
+        match find_tag_id(&mut conn, dep).await? {
+            Some(dep_id) => {
+                if dep_id != tag && implies(&mut conn, dep_id, tag).await? {
+                    return Err(anyhow::anyhow!("{} already implies this tag", dep));
+                }
+                dep_ids.push(dep_id);
+            }
+            // New tag has no items yet and 0 is not a tag id, so every item misses it
+            None => dep_ids.push(0),
+        }
+    }
+
+    let count = sqlx::query_scalar!(
+        r#"WITH RECURSIVE closure(dep) AS (
+            SELECT value FROM json_each(?)
+            UNION
+            SELECT tag_tag.dep FROM closure JOIN tag_tag ON tag_tag.tag = closure.dep)
+        SELECT COUNT(DISTINCT tag_item.item) as "count!: i64"
+        FROM tag_item JOIN closure
+        WHERE tag_item.tag = ?
+            AND NOT EXISTS (SELECT 1 FROM tag_item AS t WHERE t.item = tag_item.item AND t.tag = closure.dep)"#,
+        serde_json::json!(dep_ids),
+        tag
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(count)
+}
+
+/// Update tag. If it is renamed to an existing tag or alias, it is merged into that tag.
+/// Dependencies are checked for cycles first, then the whole update is done in one transaction.
+/// Return id of the tag if new dependencies are added or another tag is merged into it,
+/// so its items need implications applied.
+pub async fn update_tag(pool: &SqlitePool, tag: &Tag) -> anyhow::Result<Option<i64>> {
+    let name = tag.name.trim().to_lowercase();
+    let deps = tag.deps.clone().unwrap_or_default().to_lowercase();
+    let deps = deps.split_whitespace().collect::<Vec<&str>>();
+
+    let mut tx = pool.begin().await?;
+    let merge_into_id = find_tag_id(&mut tx, &name).await?.filter(|id| *id != tag.id);
+    let tag_id = merge_into_id.unwrap_or(tag.id);
+    // New dependency tags cannot imply anything yet
+    for dep in deps.iter() {
+        if let Some(dep_id) = find_tag_id(&mut tx, dep).await?
+            && dep_id != tag_id
+            && implies(&mut tx, dep_id, tag_id).await?
         {
-            Ok(id) => id,
-            Err(_) => sqlx::query!("INSERT INTO tag (name) VALUES (?)", dep)
-                .execute(pool)
-                .await?
-                .last_insert_rowid(),
-        };
+            return Err(anyhow::anyhow!(
+                "Cycle of implications: {} already implies {}",
+                dep,
+                name
+            ));
+        }
+    }
 
-        sqlx::query!("INSERT OR IGNORE INTO tag_tag (tag, dep) VALUES (?, ?)", tag.id, dep_id)
-            .execute(pool)
+    match merge_into_id {
+        Some(existing_id) => merge_into(&mut tx, tag.id, existing_id).await?,
+        None => {
+            sqlx::query!(
+                "UPDATE tag SET name = ?, description = ? WHERE id = ?",
+                name,
+                tag.description,
+                tag.id
+            )
+            .execute(&mut *tx)
             .await?;
+            update_tag_type(&mut tx, tag).await?;
+            update_aliases(&mut tx, tag).await?;
+        }
     }
 
+    let mut is_added = merge_into_id.is_some();
+    for dep in deps {
+        let dep_id = get_or_create_tag(&mut tx, dep, None).await?;
+        if dep_id == tag_id {
+            continue;
+        }
+        is_added |= sqlx::query!("INSERT OR IGNORE INTO tag_tag (tag, dep) VALUES (?, ?)", tag_id, dep_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+    }
+    // Merging may have brought in dependencies of the other tag
+    if implies(&mut tx, tag_id, tag_id).await? {
+        return Err(anyhow::anyhow!(
+            "Updating {} would create a cycle of implications",
+            name
+        ));
+    }
+    tx.commit().await?;
+
+    Ok(is_added.then_some(tag_id))
+}
+
+async fn update_tag_type(conn: &mut SqliteConnection, tag: &Tag) -> anyhow::Result<()> {
+    if let Some(tag_type) = tag.tag_type.as_ref() {
+        let type_id = if tag_type.is_empty() {
+            None
+        } else {
+            match get_type_id(conn, tag_type).await? {
+                Some(id) => Some(id),
+                None => return Err(anyhow::anyhow!("Unknown tag type {}", tag_type)),
+            }
+        };
+        sqlx::query!("UPDATE tag SET type = ? WHERE id = ?", type_id, tag.id)
+            .execute(conn)
+            .await?;
+    }
     Ok(())
 }
 
-pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> Result<(), sqlx::Error> {
+/// Replace aliases of tag. An alias cannot be the name or an alias of another tag; rename the tag to merge instead.
+async fn update_aliases(conn: &mut SqliteConnection, tag: &Tag) -> anyhow::Result<()> {
+    let Some(aliases) = tag.aliases.as_ref() else {
+        return Ok(());
+    };
+
+    sqlx::query!("DELETE FROM tag_alias WHERE tag = ?", tag.id)
+        .execute(&mut *conn)
+        .await?;
+    for alias in aliases.to_lowercase().split_whitespace() {
+        match find_tag_id(conn, alias).await? {
+            Some(other_id) if other_id == tag.id => {}
+            Some(_) => return Err(anyhow::anyhow!("Alias {} is another tag or its alias", alias)),
+            None => {
+                sqlx::query!("INSERT INTO tag_alias (alias, tag) VALUES (?, ?)", alias, tag.id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add tags to item. Tag can be in form `namespace:name`.
+/// New tags without namespace get `default_type`.
+pub async fn add_tag_item(
+    conn: &mut SqliteConnection,
+    item: i64,
+    tags: &Vec<String>,
+    default_type: Option<&str>,
+) -> Result<(), sqlx::Error> {
     let added_tags = sqlx::query_scalar!("SELECT tag FROM tag_item WHERE item = ?", item)
-        .fetch_all(pool)
+        .fetch_all(&mut *conn)
         .await?;
     let mut exist_tags = HashSet::new();
     exist_tags.extend(added_tags);
@@ -91,26 +471,17 @@ pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> R
 
         let tag = tag.to_lowercase().to_string();
 
-        let tag_id = match sqlx::query_scalar!("SELECT id FROM tag WHERE name = ?", tag)
-            .fetch_one(pool)
-            .await
-        {
-            Ok(id) => id,
-            Err(_) => sqlx::query!("INSERT INTO tag (name) VALUES (?)", tag)
-                .execute(pool)
-                .await?
-                .last_insert_rowid(),
-        };
+        let tag_id = get_or_create_tag(conn, &tag, default_type).await?;
 
         if !exist_tags.contains(&tag_id) {
             sqlx::query!("INSERT OR IGNORE INTO tag_item (item, tag) VALUES (?, ?)", item, tag_id)
-                .execute(pool)
+                .execute(&mut *conn)
                 .await?;
 
             exist_tags.insert(tag_id);
 
             let deps = sqlx::query_scalar!("SELECT dep FROM tag_tag WHERE tag = ?", tag_id)
-                .fetch_all(pool)
+                .fetch_all(&mut *conn)
                 .await?;
             depend_tags.extend(deps);
         }
@@ -121,12 +492,12 @@ pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> R
         for tag in depend_tags.iter() {
             if !exist_tags.contains(tag) {
                 sqlx::query!("INSERT OR IGNORE INTO tag_item (item, tag) VALUES (?, ?)", item, tag)
-                    .execute(pool)
+                    .execute(&mut *conn)
                     .await?;
                 exist_tags.insert(*tag);
 
                 let deps = sqlx::query_scalar!("SELECT dep FROM tag_tag WHERE tag = ?", tag)
-                    .fetch_all(pool)
+                    .fetch_all(&mut *conn)
                     .await?;
                 tmp_dep_tags.extend(deps);
             }
@@ -138,60 +509,69 @@ pub async fn add_tag_item(pool: &SqlitePool, item: i64, tags: &Vec<String>) -> R
     Ok(())
 }
 
+/// Add tags of Civitai model, new ones in the [`CIVITAI_TAG_TYPE`] namespace.
+/// Type, base model, format and NSFW are stored in their own item fields.
 pub async fn add_tag_from_model_info(
-    pool: &SqlitePool,
+    conn: &mut SqliteConnection,
     item: i64,
-    extra_tags: &Vec<String>,
     model_info: &Value,
-    file_metadata: &CivitaiFileMetadata,
 ) -> Result<(), sqlx::Error> {
     let mut tags = Vec::new();
-    for tag in extra_tags {
-        tags.push(tag.clone().replace(" ", "_").to_lowercase());
-    }
-
-    let nsfw = model_info["nsfw"].as_bool().unwrap_or(false);
-    let poi = model_info["poi"].as_bool().unwrap_or(false);
-    let model_type = model_info["type"].as_str().unwrap_or_default();
-    tags.push(model_type.replace(" ", "_").to_lowercase());
-    if nsfw {
-        tags.push(String::from("nsfw"));
-    }
-    if poi {
-        tags.push(String::from("poi"));
-    }
     if let Some(json_tags) = model_info["tags"].as_array() {
         for tag in json_tags {
             tags.push(tag.as_str().unwrap_or_default().replace(" ", "_").to_lowercase());
         }
     }
 
-    tags.push(file_metadata.format.clone().replace(" ", "_").to_lowercase());
-    if let Some(fp) = &file_metadata.fp {
-        tags.push(fp.to_string());
-    }
-    if let Some(size) = &file_metadata.size {
-        tags.push(size.to_string());
-    }
-    add_tag_item(pool, item, &tags).await
+    add_tag_item(conn, item, &tags, Some(CIVITAI_TAG_TYPE)).await
 }
 
-pub async fn update_tag_item(pool: &SqlitePool, item: i64, tag_str: &str) -> anyhow::Result<()> {
+pub async fn update_tag_item(conn: &mut SqliteConnection, item: i64, tag_str: &str) -> anyhow::Result<()> {
     sqlx::query!("DELETE FROM tag_item WHERE item = ?", item)
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
 
     let mut tags = Vec::new();
     for tag in tag_str.split_whitespace() {
         tags.push(tag.to_lowercase());
     }
-    add_tag_item(pool, item, &tags).await?;
+    add_tag_item(conn, item, &tags, Some(USER_TAG_TYPE)).await?;
+    Ok(())
+}
+
+/// Remove tags from item. Implied tags are kept.
+pub async fn remove_tag_item(conn: &mut SqliteConnection, item: i64, tags: &[String]) -> Result<(), sqlx::Error> {
+    for tag in tags {
+        if let Some(tag_id) = find_tag_id(conn, &tag.to_lowercase()).await? {
+            sqlx::query!("DELETE FROM tag_item WHERE item = ? AND tag = ?", item, tag_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
     Ok(())
 }
 
-pub async fn update_item_note(pool: &SqlitePool, item: i64, note: &str) -> Result<(), sqlx::Error> {
+/// Remove tags from item. Tags with the same name in a namespace are kept.
+pub async fn remove_untyped_tag_item(
+    conn: &mut SqliteConnection,
+    item: i64,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    for tag in tags {
+        sqlx::query!(
+            "DELETE FROM tag_item WHERE item = ? AND tag IN (SELECT id FROM tag WHERE name = ? AND type IS NULL)",
+            item,
+            tag
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn update_item_note(conn: &mut SqliteConnection, item: i64, note: &str) -> Result<(), sqlx::Error> {
     sqlx::query!("UPDATE item SET note = ? WHERE id = ?", note, item)
-        .execute(pool)
+        .execute(conn)
         .await?;
 
     Ok(())
@@ -201,19 +581,22 @@ pub async fn list_tags(pool: &SqlitePool, item_ids: &[i64]) -> Result<Vec<TagCou
     if item_ids.is_empty() {
         sqlx::query_as!(
             TagCount,
-            r#"SELECT tag.name as tag, COUNT(tag_item.tag) as count FROM tag
+            r#"SELECT tag.name as tag, COUNT(tag_item.tag) as count, tag_type.type as "tag_type?" FROM tag
                 LEFT JOIN tag_item ON tag.id = tag_item.tag
                 LEFT JOIN item ON item.id = tag_item.item
+                LEFT JOIN tag_type ON tag_type.id = tag.type
                 GROUP BY tag.name ORDER BY count DESC"#
         )
         .fetch_all(pool)
         .await
     } else {
         sqlx::query_as!(TagCount,
-            r#"SELECT name as "tag!", count as "count!"
-        FROM (SELECT tag_item.tag as tag_id, tag.name as name, COUNT(tag_item.tag) as count FROM tag
+            r#"SELECT name as "tag!", count as "count!", tag_type as "tag_type?"
+        FROM (SELECT tag_item.tag as tag_id, tag.name as name, COUNT(tag_item.tag) as count, tag_type.type as tag_type
+            FROM tag
             LEFT JOIN tag_item ON tag.id = tag_item.tag
             LEFT JOIN item ON item.id = tag_item.item
+            LEFT JOIN tag_type ON tag_type.id = tag.type
             WHERE item.is_checked = true
             GROUP BY tag.name)
         JOIN tag_item ON tag_item.tag = tag_id
@@ -225,3 +608,186 @@ pub async fn list_tags(pool: &SqlitePool, item_ids: &[i64]) -> Result<Vec<TagCou
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    fn tag(id: i64, name: &str, aliases: &str) -> Tag {
+        Tag {
+            id,
+            name: name.to_string(),
+            description: String::new(),
+            deps: None,
+            tag_type: None,
+            aliases: Some(aliases.to_string()),
+        }
+    }
+
+    /// Items 1 and 2 have tag `a`, item 3 has tag `b`, item 1 also has tag `x`
+    async fn tagged_items(pool: &SqlitePool) -> (i64, i64) {
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query("INSERT INTO item (id, path, base_label) VALUES (1, '1', ''), (2, '2', ''), (3, '3', '')")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let a = get_or_create_tag(&mut conn, "a", None).await.unwrap();
+        let b = get_or_create_tag(&mut conn, "b", None).await.unwrap();
+        let x = get_or_create_tag(&mut conn, "x", None).await.unwrap();
+        sqlx::query("INSERT INTO tag_item (tag, item) VALUES (?, 1), (?, 2), (?, 3), (?, 1)")
+            .bind(a)
+            .bind(a)
+            .bind(b)
+            .bind(x)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        (a, b)
+    }
+
+    async fn item_tags(pool: &SqlitePool, item: i64) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT tag.name FROM tag_item JOIN tag ON tag.id = tag_item.tag WHERE item = ? ORDER BY name",
+        )
+        .bind(item)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    /// Item, old and new tags of tag changes by `bob`
+    async fn tag_history(pool: &SqlitePool) -> Vec<(i64, String, String)> {
+        sqlx::query_as(
+            "SELECT item, old_value, new_value FROM history WHERE user = 'bob' AND action = 'tags' ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn preview_counts_items_missing_implied_tags() {
+        let pool = test_pool().await;
+        let (a, b) = tagged_items(&pool).await;
+        let deps = |deps: &str| deps.split_whitespace().map(String::from).collect::<Vec<_>>();
+
+        assert_eq!(preview_implications(&pool, a, &deps("x")).await.unwrap(), 1);
+        assert_eq!(preview_implications(&pool, a, &deps("new x")).await.unwrap(), 2);
+        assert_eq!(preview_implications(&pool, a, &deps("")).await.unwrap(), 0);
+
+        update_tag(
+            &pool,
+            &Tag {
+                deps: Some("a".to_string()),
+                aliases: None,
+                ..tag(b, "b", "")
+            },
+        )
+        .await
+        .unwrap();
+        // Dependencies after a new tag are still checked for cycles
+        assert!(preview_implications(&pool, a, &deps("new b")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn new_dependency_is_applied_to_items_of_the_tag() {
+        let pool = test_pool().await;
+        let (a, b) = tagged_items(&pool).await;
+        let c = get_or_create_tag(&mut pool.acquire().await.unwrap(), "c", None)
+            .await
+            .unwrap();
+        // Implication which was not applied yet
+        sqlx::query("INSERT INTO tag_tag (tag, dep) VALUES (?, ?)")
+            .bind(b)
+            .bind(c)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let updated = Tag {
+            deps: Some("c".to_string()),
+            aliases: None,
+            ..tag(a, "a", "")
+        };
+        assert_eq!(update_tag(&pool, &updated).await.unwrap(), Some(a));
+        assert_eq!(update_tag(&pool, &updated).await.unwrap(), None);
+        assert_eq!(
+            apply_implications(&pool, "bob", Some(a)).await.unwrap(),
+            (2, vec![1, 2])
+        );
+        assert_eq!(item_tags(&pool, 1).await, vec!["a", "c", "x"]);
+        assert_eq!(item_tags(&pool, 2).await, vec!["a", "c"]);
+        assert_eq!(item_tags(&pool, 3).await, vec!["b"]);
+        assert_eq!(
+            tag_history(&pool).await,
+            vec![
+                (1, "a x".to_string(), "a c x".to_string()),
+                (2, "a".to_string(), "a c".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn merge_records_history_of_items() {
+        let pool = test_pool().await;
+        let (a, b) = tagged_items(&pool).await;
+        assert_eq!(merge_tag(&pool, "bob", b, a).await.unwrap(), vec![3]);
+        assert_eq!(item_tags(&pool, 3).await, vec!["a"]);
+        assert_eq!(tag_history(&pool).await, vec![(3, "b".to_string(), "a".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn alias_of_another_tag_is_rejected() {
+        let pool = test_pool().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let anime = get_or_create_tag(&mut conn, "anime", None).await.unwrap();
+        let style = get_or_create_tag(&mut conn, "animestyle", None).await.unwrap();
+        drop(conn);
+        update_tag(&pool, &tag(anime, "anime", "anime_style")).await.unwrap();
+
+        // Name of another tag
+        assert!(update_tag(&pool, &tag(anime, "anime", "animestyle")).await.is_err());
+        // Alias of another tag
+        assert!(
+            update_tag(&pool, &tag(style, "animestyle", "anime_style"))
+                .await
+                .is_err()
+        );
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(find_tag_id(&mut conn, "anime_style").await.unwrap(), Some(anime));
+        assert_eq!(find_tag_id(&mut conn, "animestyle").await.unwrap(), Some(style));
+    }
+
+    #[tokio::test]
+    async fn own_aliases_are_replaced() {
+        let pool = test_pool().await;
+        let anime = get_or_create_tag(&mut pool.acquire().await.unwrap(), "anime", None)
+            .await
+            .unwrap();
+        update_tag(&pool, &tag(anime, "anime", "a b")).await.unwrap();
+        update_tag(&pool, &tag(anime, "anime", "b c c")).await.unwrap();
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(find_tag_id(&mut conn, "a").await.unwrap(), None);
+        assert_eq!(find_tag_id(&mut conn, "b").await.unwrap(), Some(anime));
+        assert_eq!(find_tag_id(&mut conn, "c").await.unwrap(), Some(anime));
+    }
+
+    #[tokio::test]
+    async fn tags_of_model_info_get_namespace() {
+        let pool = test_pool().await;
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query("INSERT INTO item (id, path, base_label) VALUES (1, 'a', 'c')")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let user_tags = vec!["user:anime".to_string()];
+        add_tag_item(&mut conn, 1, &user_tags, None).await.unwrap();
+        let info = serde_json::json!({"tags": ["anime", "Blue Hair"]});
+        add_tag_from_model_info(&mut conn, 1, &info).await.unwrap();
+        let tags = item_state(&mut conn, 1).await.unwrap().tags;
+        assert_eq!(tags, "style:blue_hair user:anime");
+    }
+}
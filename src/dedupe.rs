@@ -0,0 +1,127 @@
+//! Replace duplicated model files by links to one canonical file
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Extension of the link while it is created, before it replaces the duplicated file
+const LINK_TMP_EXT: &str = "sdmm-link";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LinkType {
+    Hardlink,
+    Symlink,
+}
+
+impl LinkType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkType::Hardlink => "hardlink",
+            LinkType::Symlink => "symlink",
+        }
+    }
+}
+
+/// True if both paths are the same file, e.g. hardlinks of each other or a symlink to the other
+pub fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(fs::canonicalize(a)? == fs::canonicalize(b)? || is_same_inode(a, b)?)
+}
+
+#[cfg(unix)]
+fn is_same_inode(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn is_same_inode(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Replace `duplicate` by a hardlink to `canonical`, or by a relative symlink if they are on different file systems.
+/// The link is created next to `duplicate` and renamed over it, so `duplicate` is never missing.
+pub fn link(canonical: &Path, duplicate: &Path) -> io::Result<LinkType> {
+    let mut tmp = duplicate.as_os_str().to_owned();
+    tmp.push(format!(".{LINK_TMP_EXT}"));
+    let tmp = PathBuf::from(tmp);
+    let _ = fs::remove_file(&tmp);
+
+    let link_type = match fs::hard_link(canonical, &tmp) {
+        Ok(_) => LinkType::Hardlink,
+        Err(_) => {
+            let dir = fs::canonicalize(duplicate.parent().unwrap_or(Path::new(".")))?;
+            symlink(&relative_path(&dir, &fs::canonicalize(canonical)?), &tmp)?;
+            LinkType::Symlink
+        }
+    };
+    if let Err(e) = fs::rename(&tmp, duplicate) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(link_type)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+/// Path of `target` relative to directory `dir`. Both are absolute. `target` is returned as it is if they have no
+/// common root, e.g. on different drives.
+fn relative_path(dir: &Path, target: &Path) -> PathBuf {
+    let dir = dir.components().collect::<Vec<Component>>();
+    let target = target.components().collect::<Vec<Component>>();
+    let common = dir.iter().zip(target.iter()).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return target.iter().collect();
+    }
+    let mut path = PathBuf::new();
+    for _ in common..dir.len() {
+        path.push("..");
+    }
+    path.extend(&target[common..]);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TestDir;
+
+    #[test]
+    fn relative_path_between_dirs() {
+        assert_eq!(
+            relative_path(
+                Path::new("/models/lora/a"),
+                Path::new("/models/checkpoint/b.safetensors")
+            ),
+            PathBuf::from("../../checkpoint/b.safetensors")
+        );
+        assert_eq!(
+            relative_path(Path::new("/models"), Path::new("/models/b.safetensors")),
+            PathBuf::from("b.safetensors")
+        );
+    }
+
+    #[test]
+    fn duplicate_is_replaced_by_link() {
+        let dir = TestDir::new("dedupe-link");
+        let canonical = dir.join("a.safetensors");
+        let duplicate = dir.join("sub").join("b.safetensors");
+        std::fs::create_dir_all(duplicate.parent().unwrap()).unwrap();
+        std::fs::write(&canonical, "weights").unwrap();
+        std::fs::write(&duplicate, "weights").unwrap();
+        assert!(!is_same_file(&canonical, &duplicate).unwrap());
+
+        assert_eq!(link(&canonical, &duplicate).unwrap(), LinkType::Hardlink);
+        assert!(is_same_file(&canonical, &duplicate).unwrap());
+        assert_eq!(std::fs::read_to_string(&duplicate).unwrap(), "weights");
+        assert!(!dir.join("sub").join("b.safetensors.sdmm-link").exists());
+    }
+}
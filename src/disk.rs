@@ -0,0 +1,245 @@
+//! Free space and quota checks before downloading a model
+
+use crate::config::Config;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+const MB: u64 = 1024 * 1024;
+
+/// Downloads which are not finished yet, see [`Reservation`]
+static IN_FLIGHT: Mutex<InFlight> = Mutex::new(InFlight {
+    by_collection: BTreeMap::new(),
+    unwritten: 0,
+});
+
+struct InFlight {
+    /// Bytes by collection with quota, which are not in the size of the collection until the file is scanned
+    by_collection: BTreeMap<String, u64>,
+    /// Bytes not written yet by all downloads, counted against the free space of every file system
+    unwritten: u64,
+}
+
+fn in_flight() -> MutexGuard<'static, InFlight> {
+    IN_FLIGHT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Space a download may use in `dir`, checked before anything is written
+pub struct SpaceLimit {
+    dir: PathBuf,
+    /// Bytes which must stay free on the file system of `dir`
+    reserve: u64,
+    quota: Option<Quota>,
+}
+
+struct Quota {
+    label: String,
+    max_bytes: u64,
+    used_bytes: u64,
+}
+
+impl SpaceLimit {
+    /// Limit of a download to `dir`. `used_bytes` is the size of model files in the collection of `dir` if it has a
+    /// quota, see [`quota_collection`].
+    pub fn new(config: &Config, dir: &Path, used_bytes: u64) -> Self {
+        let quota = quota_collection(config, dir).map(|label| Quota {
+            label: label.to_string(),
+            max_bytes: config.disk.quota_mb.get(label).copied().unwrap_or_default() * MB,
+            used_bytes,
+        });
+        Self {
+            dir: dir.to_path_buf(),
+            reserve: config.disk.reserve_mb * MB,
+            quota,
+        }
+    }
+
+    /// Error if `size` more bytes do not fit in the quota or the free space without the reserve, counting the
+    /// downloads in progress
+    pub fn check(&self, size: u64) -> anyhow::Result<()> {
+        self.check_with(&in_flight(), size)
+    }
+
+    /// Check `size` like [`SpaceLimit::check`] and count it as in progress until the reservation is dropped, so
+    /// parallel downloads cannot all use the same space
+    pub fn reserve(&self, size: u64) -> anyhow::Result<Reservation> {
+        let mut in_flight = in_flight();
+        self.check_with(&in_flight, size)?;
+        let collection = self.quota.as_ref().map(|quota| quota.label.clone());
+        if let Some(label) = collection.as_ref() {
+            *in_flight.by_collection.entry(label.clone()).or_default() += size;
+        }
+        in_flight.unwritten += size;
+        Ok(Reservation {
+            collection,
+            size,
+            unwritten: size,
+        })
+    }
+
+    fn check_with(&self, in_flight: &InFlight, size: u64) -> anyhow::Result<()> {
+        if let Some(quota) = &self.quota {
+            let used_bytes = quota.used_bytes + in_flight.by_collection.get(&quota.label).copied().unwrap_or_default();
+            if used_bytes + size > quota.max_bytes {
+                return Err(anyhow::anyhow!(
+                    "Quota of collection {} is exceeded: {} MB used or being downloaded, {} MB needed, quota is {} MB",
+                    quota.label,
+                    used_bytes / MB,
+                    size.div_ceil(MB),
+                    quota.max_bytes / MB
+                ));
+            }
+        }
+        // Directory may be created by the download
+        let dir = self.dir.ancestors().find(|dir| dir.exists()).unwrap_or(&self.dir);
+        match free_space(dir) {
+            Ok(free) if free < size + self.reserve + in_flight.unwritten => Err(anyhow::anyhow!(
+                "Not enough free space in {}: {} MB free, {} MB being downloaded, {} MB needed and {} MB must stay free",
+                self.dir.display(),
+                free / MB,
+                in_flight.unwritten.div_ceil(MB),
+                size.div_ceil(MB),
+                self.reserve / MB
+            )),
+            Ok(_) => Ok(()),
+            // Free space cannot be checked on this platform
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(()),
+            Err(e) => Err(anyhow::anyhow!(
+                "Failed to check free space of {}: {e}",
+                self.dir.display()
+            )),
+        }
+    }
+}
+
+/// Space of a download in progress, released when dropped
+pub struct Reservation {
+    collection: Option<String>,
+    size: u64,
+    unwritten: u64,
+}
+
+impl Reservation {
+    /// Count `n` bytes as written, which are in the free space of the file system from now on
+    pub fn written(&mut self, n: u64) {
+        let n = n.min(self.unwritten);
+        self.unwritten -= n;
+        in_flight().unwritten -= n;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut in_flight = in_flight();
+        in_flight.unwritten -= self.unwritten;
+        if let Some(label) = self.collection.as_ref()
+            && let Some(bytes) = in_flight.by_collection.get_mut(label)
+        {
+            *bytes -= self.size;
+            if *bytes == 0 {
+                in_flight.by_collection.remove(label);
+            }
+        }
+    }
+}
+
+/// Label of the collection which contains `dir` if it has a quota
+pub fn quota_collection<'a>(config: &'a Config, dir: &Path) -> Option<&'a str> {
+    config
+        .model_paths
+        .iter()
+        .find(|(label, base_path)| dir.starts_with(base_path) && config.disk.quota_mb.contains_key(*label))
+        .map(|(label, _)| label.as_str())
+}
+
+/// Bytes available to the user on the file system of `path`
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read after `statvfs` filled it
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn quota_of_collection() {
+        let dir = std::env::temp_dir();
+        let mut config = Config {
+            model_paths: HashMap::from([("lora".to_string(), dir.to_str().unwrap().to_string())]),
+            ..Default::default()
+        };
+        config.disk.reserve_mb = 0;
+        config.disk.quota_mb.insert("lora".to_string(), 10);
+
+        assert_eq!(quota_collection(&config, &dir.join("sub")), Some("lora"));
+        assert_eq!(quota_collection(&config, Path::new("/elsewhere")), None);
+        let limit = SpaceLimit::new(&config, &dir.join("sub"), 8 * MB);
+        assert!(limit.check(2 * MB).is_ok());
+        assert!(
+            limit
+                .check(3 * MB)
+                .unwrap_err()
+                .to_string()
+                .contains("Quota of collection lora")
+        );
+        assert!(
+            SpaceLimit::new(&config, Path::new("/elsewhere"), 0)
+                .check(3 * MB)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn downloads_in_progress_are_reserved() {
+        let dir = std::env::temp_dir();
+        let mut config = Config {
+            model_paths: HashMap::from([("reserved".to_string(), dir.to_str().unwrap().to_string())]),
+            ..Default::default()
+        };
+        config.disk.reserve_mb = 0;
+        config.disk.quota_mb.insert("reserved".to_string(), 10);
+
+        let limit = SpaceLimit::new(&config, &dir, 0);
+        let mut first = limit.reserve(6 * MB).unwrap();
+        assert!(limit.check(6 * MB).is_err());
+        assert!(limit.reserve(6 * MB).is_err());
+        // Written bytes are still in the quota until the file is scanned
+        first.written(6 * MB);
+        assert!(limit.check(6 * MB).is_err());
+        drop(first);
+        assert!(limit.reserve(6 * MB).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reserve_of_file_system() {
+        let dir = std::env::temp_dir();
+        let free = free_space(&dir).unwrap();
+        let mut config = Config::default();
+        config.disk.reserve_mb = 0;
+        assert!(SpaceLimit::new(&config, &dir, 0).check(0).is_ok());
+        config.disk.reserve_mb = free / MB + 1;
+        assert!(SpaceLimit::new(&config, &dir, 0).check(0).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Import model info written by other tools, so their libraries can be scanned without syncing Civitai again:
+//! * Civitai Helper: `<name>.civitai.info`, same format as `<name>.json`
+//! * Stability Matrix: `<name>.cm-info.json`
+//! * A1111/Forge user metadata: `activation text`, `preferred weight` and `notes` in `<name>.json`
+//! * Preview image: `<name>.preview.png` and the like, or `<name>.png` which is used as it is
+
+use crate::a1111;
+use crate::civitai::find_preview;
+use serde_json::{Map, Value, json, to_string_pretty};
+use std::path::Path;
+use tokio::fs;
+use tracing::info;
+
+pub const CIVITAI_HELPER_EXT: &str = "civitai.info";
+pub const STABILITY_MATRIX_EXT: &str = "cm-info.json";
+
+/// Preview images of other tools, in order of preference
+pub const PREVIEW_EXTS: [&str; 5] = [
+    "preview.png",
+    "preview.jpg",
+    "preview.jpeg",
+    "preview.webp",
+    "preview.gif",
+];
+
+/// `.json` which has info from Civitai, not only A1111 user metadata
+pub fn is_civitai_info(info: &Value) -> bool {
+    info["modelId"].is_i64() || info["files"].is_array()
+}
+
+/// Create `<name>.json` from Civitai Helper or Stability Matrix info if it does not have Civitai info yet,
+/// then copy their preview image. A1111 user metadata in the existing `.json` is kept.
+pub async fn import(model_path: &Path) -> anyhow::Result<()> {
+    let json_path = model_path.with_extension("json");
+    let current = read_json(&json_path).await;
+    if !is_civitai_info(&current) {
+        let imported = match read_json(&model_path.with_extension(CIVITAI_HELPER_EXT)).await {
+            info if is_civitai_info(&info) => Some(info),
+            _ => from_stability_matrix(&read_json(&model_path.with_extension(STABILITY_MATRIX_EXT)).await),
+        };
+        if let Some(mut info) = imported {
+            info!("Import model info: {}", json_path.display());
+            a1111::keep_metadata(&current, &mut info);
+            fs::write(&json_path, to_string_pretty(&info)?).await?;
+        }
+    }
+
+    import_preview(model_path).await
+}
+
+/// Copy preview of other tools to `<name>.<type of image>`, e.g. `<name>.preview.png` to `<name>.png`
+async fn import_preview(model_path: &Path) -> anyhow::Result<()> {
+    if find_preview(model_path).is_some() {
+        return Ok(());
+    }
+    for ext in PREVIEW_EXTS {
+        let image = model_path.with_extension(ext);
+        if image.exists() {
+            let image_type = ext.trim_start_matches("preview.");
+            // Copy instead of rename, so the other tool still finds its preview
+            fs::copy(&image, model_path.with_extension(image_type)).await?;
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn read_json(path: &Path) -> Value {
+    let content = fs::read_to_string(path).await.unwrap_or_default();
+    serde_json::from_str(&content).unwrap_or_default()
+}
+
+/// Field of Stability Matrix info. Its keys are PascalCase in most versions.
+fn field<'a>(info: &'a Value, key: &str) -> &'a Value {
+    info.as_object()
+        .and_then(|obj| obj.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v))
+        .unwrap_or(&Value::Null)
+}
+
+/// Convert Stability Matrix info to Civitai model version info
+fn from_stability_matrix(info: &Value) -> Option<Value> {
+    let version_id = field(info, "VersionId").as_i64()?;
+    let model_id = field(info, "ModelId").as_i64()?;
+
+    let hashes = field(info, "Hashes");
+    let metadata = field(info, "FileMetadata");
+    let stats = field(info, "Stats");
+    let mut file_metadata = Map::new();
+    for key in ["format", "fp", "size"] {
+        if let Some(value) = field(metadata, key).as_str() {
+            file_metadata.insert(key.to_string(), json!(value));
+        }
+    }
+
+    Some(json!({
+        "id": version_id,
+        "modelId": model_id,
+        "name": field(info, "VersionName"),
+        "description": field(info, "VersionDescription"),
+        "baseModel": field(info, "BaseModel"),
+        "trainedWords": field(info, "TrainedWords"),
+        "stats": {
+            "downloadCount": field(stats, "DownloadCount"),
+            "rating": field(stats, "Rating"),
+        },
+        "files": [{
+            "hashes": {
+                "SHA256": field(hashes, "SHA256"),
+                "BLAKE3": field(hashes, "BLAKE3"),
+                "AutoV2": field(hashes, "AutoV2"),
+            },
+            "metadata": file_metadata,
+        }],
+        "images": field(info, "ThumbnailImageUrl").as_str().map(|url| json!([{ "url": url }])).unwrap_or(json!([])),
+        "model": {
+            "name": field(info, "ModelName"),
+            "description": field(info, "ModelDescription"),
+            "type": field(info, "ModelType"),
+            "nsfw": field(info, "Nsfw"),
+            "tags": field(info, "Tags"),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TestDir;
+
+    #[test]
+    fn stability_matrix_info_is_converted() {
+        let info = json!({
+            "ModelId": 1,
+            "VersionId": 2,
+            "ModelName": "Model",
+            "VersionName": "v1",
+            "BaseModel": "SDXL 1.0",
+            "TrainedWords": ["blue hair", "red, eyes"],
+            "Hashes": { "SHA256": "AA", "BLAKE3": "BB" },
+            "FileMetadata": { "Format": "SafeTensor", "Fp": "fp16" },
+            "ModelType": "LORA",
+        });
+        let info = from_stability_matrix(&info).unwrap();
+        assert!(is_civitai_info(&info));
+        assert_eq!(info["modelId"], 1);
+        assert_eq!(info["id"], 2);
+        assert_eq!(info["trainedWords"], json!(["blue hair", "red, eyes"]));
+        assert_eq!(info["files"][0]["hashes"]["BLAKE3"], "BB");
+        assert_eq!(
+            info["files"][0]["metadata"],
+            json!({ "format": "SafeTensor", "fp": "fp16" })
+        );
+        assert_eq!(info["model"]["type"], "LORA");
+        assert_eq!(info["images"], json!([]));
+
+        // Keys are matched case-insensitively, and ids are required
+        assert!(from_stability_matrix(&json!({ "modelId": 1, "versionId": 2 })).is_some());
+        assert!(from_stability_matrix(&json!({ "ModelId": 1 })).is_none());
+    }
+
+    #[tokio::test]
+    async fn civitai_helper_info_keeps_user_metadata() {
+        let dir = TestDir::new("importer-helper");
+        let model = dir.join("a.safetensors");
+        std::fs::write(&model, "model").unwrap();
+        std::fs::write(
+            model.with_extension(CIVITAI_HELPER_EXT),
+            json!({ "modelId": 1, "id": 2, "files": [] }).to_string(),
+        )
+        .unwrap();
+        std::fs::write(model.with_extension("json"), json!({ "notes": "mine" }).to_string()).unwrap();
+
+        import(&model).await.unwrap();
+        let info = read_json(&model.with_extension("json")).await;
+        assert_eq!(info["modelId"], 1);
+        assert_eq!(info["notes"], "mine");
+
+        // Civitai info already in `.json` is not replaced
+        std::fs::write(
+            model.with_extension(CIVITAI_HELPER_EXT),
+            json!({ "modelId": 3, "files": [] }).to_string(),
+        )
+        .unwrap();
+        import(&model).await.unwrap();
+        assert_eq!(read_json(&model.with_extension("json")).await["modelId"], 1);
+    }
+
+    #[tokio::test]
+    async fn preview_keeps_image_type() {
+        let dir = TestDir::new("importer-preview");
+        let model = dir.join("a.safetensors");
+        std::fs::write(&model, "model").unwrap();
+        std::fs::write(model.with_extension("preview.png"), "png").unwrap();
+
+        import(&model).await.unwrap();
+        assert_eq!(std::fs::read_to_string(model.with_extension("png")).unwrap(), "png");
+        assert!(!model.with_extension("jpeg").exists());
+        assert!(model.with_extension("preview.png").exists());
+        assert_eq!(find_preview(&model), Some(model.with_extension("png")));
+
+        // An existing preview is kept
+        std::fs::write(model.with_extension("preview.webp"), "webp").unwrap();
+        std::fs::remove_file(model.with_extension("preview.png")).unwrap();
+        import(&model).await.unwrap();
+        assert!(!model.with_extension("webp").exists());
+    }
+}
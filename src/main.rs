@@ -10,11 +10,18 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+mod a1111;
 mod api;
 mod civitai;
 mod config;
 mod db;
+mod dedupe;
+mod disk;
+mod importer;
+mod safetensors;
+mod sidecar;
 mod ui;
+mod verify;
 
 use crate::civitai::update_model_info;
 use crate::config::Config;
@@ -36,6 +43,7 @@ use parking_lot::Mutex;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
 const BASE_PATH_PREFIX: &str = "base_";
@@ -50,6 +58,18 @@ struct Cli {
     /// Update model info
     #[clap(short, long, default_value = "false")]
     update_model_info: bool,
+
+    /// Export tags, notes and ratings to this file then exit. Use `.ndjson` extension for NDJSON format.
+    #[clap(long)]
+    export_catalog: Option<PathBuf>,
+
+    /// Merge tags, notes and ratings from this JSON or NDJSON file then exit
+    #[clap(long)]
+    import_catalog: Option<PathBuf>,
+
+    /// Only report what would be imported and the conflicts
+    #[clap(long, default_value = "false")]
+    dry_run: bool,
 }
 
 struct ConfigData {
@@ -79,6 +99,10 @@ async fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
+    if args.export_catalog.is_some() || args.import_catalog.is_some() {
+        return run_catalog(&args, &config).await;
+    }
+
     let stop_handle = Arc::new(RwLock::new(StopHandle::default()));
 
     loop {
@@ -98,6 +122,26 @@ async fn main() -> anyhow::Result<()> {
         });
         let broadcaster = Broadcaster::create();
 
+        tokio::spawn({
+            let db_pool = ref_db_pool.clone();
+            let config = config.clone();
+            async move {
+                if let Err(e) = api::backfill_trigger_words(&config, &db_pool).await {
+                    error!("Failed to fill trigger words of existing items: {}", e);
+                }
+                if let Err(e) = api::backfill_model_fields(&config, &db_pool).await {
+                    error!("Failed to fill model fields of existing items: {}", e);
+                }
+            }
+        });
+
+        let watch_handle = tokio::spawn(api::watch_followed_creators(
+            config.clone(),
+            ref_db_pool.clone(),
+            broadcaster.clone(),
+        ));
+        let stats_handle = tokio::spawn(api::record_daily_stats(ref_db_pool.clone()));
+
         let srv = HttpServer::new({
             let stop_handle = stop_handle.clone();
             move || {
@@ -132,6 +176,8 @@ async fn main() -> anyhow::Result<()> {
 
         // run server until stopped (either by ctrl-c or stop endpoint)
         let _ = srv.await;
+        watch_handle.abort();
+        stats_handle.abort();
 
         if !stop_handle.read().await.is_restarted {
             break;
@@ -177,6 +223,32 @@ fn load_config(config_path: &Path) -> anyhow::Result<Config> {
     }
 }
 
+async fn run_catalog(args: &Cli, config: &Config) -> anyhow::Result<()> {
+    let db_pool = DBPool::init(&config.db).await?;
+
+    if let Some(path) = args.export_catalog.as_ref() {
+        let catalog = db::catalog::export(&db_pool.sqlite_pool).await?;
+        let content = if path.extension().is_some_and(|ext| ext == "ndjson") {
+            catalog.to_ndjson()?
+        } else {
+            serde_json::to_string_pretty(&catalog)?
+        };
+        std::fs::write(path, content)?;
+        info!("Exported {} tags and {} items", catalog.tags.len(), catalog.items.len());
+    }
+
+    if let Some(path) = args.import_catalog.as_ref() {
+        let catalog = db::catalog::Catalog::parse(&std::fs::read_to_string(path)?)?;
+        let report = db::catalog::import(&db_pool.sqlite_pool, "", &catalog, args.dry_run).await?;
+        if !report.dry_run {
+            sidecar::write_all_if_enabled(config, &db_pool.sqlite_pool, &report.updated_items).await;
+        }
+        info!("{}", serde_json::to_string_pretty(&report)?);
+    }
+
+    Ok(())
+}
+
 async fn basic_auth_validator(
     req: ServiceRequest,
     credentials: BasicAuth,
@@ -0,0 +1,182 @@
+//! Read header of `.safetensors` file: 8 bytes of header length, then JSON header with `__metadata__`, then tensor
+//! data
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Header larger than this is not a valid safetensors file
+const MAX_HEADER_LEN: u64 = 100 * 1024 * 1024;
+/// Number of most frequent training tags used as trigger words
+const TOP_TAGS: usize = 5;
+
+/// Return (length of header, header)
+pub fn read_header(path: &Path) -> anyhow::Result<(u64, Value)> {
+    let mut file = File::open(path)?;
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_HEADER_LEN {
+        return Err(anyhow::anyhow!("Invalid safetensors header length {len}"));
+    }
+    let mut header = vec![0u8; len as usize];
+    file.read_exact(&mut header)?;
+    Ok((len, serde_json::from_slice(&header)?))
+}
+
+/// BLAKE3 of tensor data only, so files with the same weights have the same hash even if their `__metadata__` differs
+pub fn tensor_hash(path: &Path) -> anyhow::Result<String> {
+    let (len, _) = read_header(path)?;
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(8 + len))?;
+    let mut reader = BufReader::new(file);
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// `__metadata__` of header. All values are strings.
+pub fn read_metadata(path: &Path) -> anyhow::Result<Map<String, Value>> {
+    let (_, header) = read_header(path)?;
+    Ok(header["__metadata__"].as_object().cloned().unwrap_or_default())
+}
+
+/// Trigger words from `modelspec.trigger_phrase`, or the most frequent tags in `ss_tag_frequency` of kohya-ss
+pub fn trigger_words(metadata: &Map<String, Value>) -> Vec<String> {
+    if let Some(phrase) = metadata.get("modelspec.trigger_phrase").and_then(Value::as_str) {
+        let words = split_words(phrase);
+        if !words.is_empty() {
+            return words;
+        }
+    }
+
+    // {"dataset folder": {"tag": count}}
+    let frequency = metadata
+        .get("ss_tag_frequency")
+        .and_then(Value::as_str)
+        .and_then(|s| serde_json::from_str::<HashMap<String, HashMap<String, i64>>>(s).ok())
+        .unwrap_or_default();
+    let mut counts: HashMap<String, i64> = HashMap::new();
+    for tags in frequency.into_values() {
+        for (tag, count) in tags {
+            let tag = tag.trim().to_string();
+            if !tag.is_empty() {
+                *counts.entry(tag).or_default() += count;
+            }
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<(String, i64)>>();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.into_iter().take(TOP_TAGS).map(|(tag, _)| tag).collect()
+}
+
+/// Split comma separated words
+pub fn split_words(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(|w| w.trim().to_string())
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TestDir;
+    use serde_json::json;
+
+    /// Write safetensors file with `header` and `data`
+    fn write_file(path: &Path, header: &Value, data: &[u8]) {
+        let header = header.to_string();
+        let mut content = (header.len() as u64).to_le_bytes().to_vec();
+        content.extend(header.as_bytes());
+        content.extend(data);
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn read_header_and_metadata() {
+        let dir = TestDir::new("safetensors-header");
+        let path = dir.join("a.safetensors");
+        let header = json!({
+            "__metadata__": {"ss_output_name": "a"},
+            "w": {"dtype": "F16", "shape": [2], "data_offsets": [0, 4]},
+        });
+        write_file(&path, &header, &[1, 2, 3, 4]);
+
+        let (len, read) = read_header(&path).unwrap();
+        assert_eq!(len, header.to_string().len() as u64);
+        assert_eq!(read, header);
+        assert_eq!(read_metadata(&path).unwrap()["ss_output_name"], "a");
+    }
+
+    #[test]
+    fn tensor_hash_ignores_metadata() {
+        let dir = TestDir::new("safetensors-tensor-hash");
+        let tensors = json!({"w": {"dtype": "F16", "shape": [2], "data_offsets": [0, 4]}});
+        let mut resaved = tensors.clone();
+        resaved["__metadata__"] = json!({"modelspec.trigger_phrase": "blue hair"});
+        write_file(&dir.join("a.safetensors"), &tensors, &[1, 2, 3, 4]);
+        write_file(&dir.join("b.safetensors"), &resaved, &[1, 2, 3, 4]);
+        write_file(&dir.join("c.safetensors"), &tensors, &[1, 2, 3, 5]);
+
+        let hash = tensor_hash(&dir.join("a.safetensors")).unwrap();
+        assert_eq!(hash, blake3::hash(&[1, 2, 3, 4]).to_hex().to_string());
+        assert_eq!(tensor_hash(&dir.join("b.safetensors")).unwrap(), hash);
+        assert_ne!(tensor_hash(&dir.join("c.safetensors")).unwrap(), hash);
+    }
+
+    #[test]
+    fn invalid_header_is_rejected() {
+        let dir = TestDir::new("safetensors-invalid");
+        let too_long = dir.join("too-long.safetensors");
+        std::fs::write(&too_long, (MAX_HEADER_LEN + 1).to_le_bytes()).unwrap();
+        assert!(read_header(&too_long).is_err());
+
+        let truncated = dir.join("truncated.safetensors");
+        std::fs::write(&truncated, [100, 0, 0, 0, 0, 0, 0, 0, b'{']).unwrap();
+        assert!(read_header(&truncated).is_err());
+
+        let short = dir.join("short.safetensors");
+        std::fs::write(&short, [1, 0]).unwrap();
+        assert!(read_header(&short).is_err());
+    }
+
+    #[test]
+    fn trigger_words_prefer_trigger_phrase() {
+        let metadata = json!({
+            "modelspec.trigger_phrase": " blue hair, , red eyes ",
+            "ss_tag_frequency": r#"{"img": {"other": 10}}"#,
+        });
+        assert_eq!(
+            trigger_words(metadata.as_object().unwrap()),
+            vec!["blue hair", "red eyes"]
+        );
+    }
+
+    #[test]
+    fn trigger_words_from_most_frequent_tags() {
+        let frequency = json!({
+            "1_a": {"x": 5, "y": 2, " z ": 1, "": 9},
+            "2_b": {"y": 4, "w": 1, "v": 1, "u": 1},
+        });
+        let metadata = json!({
+            "modelspec.trigger_phrase": "",
+            "ss_tag_frequency": frequency.to_string(),
+        });
+        // Counts are summed over folders, ties are sorted by name
+        assert_eq!(
+            trigger_words(metadata.as_object().unwrap()),
+            vec!["y", "x", "u", "v", "w"]
+        );
+        assert!(trigger_words(&Map::new()).is_empty());
+    }
+}
@@ -0,0 +1,351 @@
+//! `.sdmm.json` sidecar next to model file, which keeps user data with the model when it is copied elsewhere
+
+use crate::config::{Config, SidecarPolicy};
+use crate::db;
+use crate::db::history::item_state;
+use crate::db::tag::{
+    AUTO_TAG_NAMESPACES, CORRUPT_TAG, USER_TAG_TYPE, add_tag_item, update_item_note, update_tag_item,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::error;
+
+pub const SIDECAR_EXT: &str = "sdmm.json";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Sidecar {
+    /// Tags in form `namespace:name`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub note: String,
+    #[serde(default)]
+    pub rating: Option<i64>,
+    #[serde(default)]
+    pub favorite: bool,
+    #[serde(default)]
+    pub provenance: Provenance,
+}
+
+/// Where the model comes from
+#[derive(Serialize, Deserialize, Default)]
+pub struct Provenance {
+    #[serde(default)]
+    pub blake3: String,
+    #[serde(default)]
+    pub sha256: String,
+    #[serde(default)]
+    pub civitai_model_id: Option<i64>,
+    #[serde(default)]
+    pub civitai_version_id: Option<i64>,
+    #[serde(default)]
+    pub download_url: String,
+    /// When sidecar is written, in milliseconds
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+pub fn sidecar_path(model_path: &Path) -> PathBuf {
+    model_path.with_extension(SIDECAR_EXT)
+}
+
+/// Tags which sdmm adds by itself are not user data, they are added again from model info or by verification
+fn is_user_tag(tag: &str) -> bool {
+    let namespace = tag.split_once(':').map(|(namespace, _)| namespace);
+    tag != CORRUPT_TAG && !namespace.is_some_and(|namespace| AUTO_TAG_NAMESPACES.contains(&namespace))
+}
+
+fn modified_ms(metadata: &Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Write sidecar of item if it is enabled in config. Errors are only logged.
+pub async fn write_if_enabled(config: &Config, pool: &SqlitePool, item_id: i64) {
+    if !config.sidecar.write {
+        return;
+    }
+    if let Err(e) = write(config, pool, item_id).await {
+        error!("Failed to write sidecar of item {}: {}", item_id, e);
+    }
+}
+
+/// Write sidecars of items changed in bulk, e.g. by a tag merge or catalog import
+pub async fn write_all_if_enabled(config: &Config, pool: &SqlitePool, item_ids: &[i64]) {
+    for item_id in item_ids {
+        write_if_enabled(config, pool, *item_id).await;
+    }
+}
+
+pub async fn write(config: &Config, pool: &SqlitePool, item_id: i64) -> anyhow::Result<()> {
+    let item = db::item::get_by_id(pool, item_id).await?;
+    let Some(base_path) = config.model_paths.get(&item.base_label) else {
+        return Err(anyhow::anyhow!("Unknown base path {}", item.base_label));
+    };
+    let model_path = PathBuf::from(base_path).join(&item.path);
+
+    let mut conn = pool.acquire().await?;
+    let state = item_state(&mut conn, item_id).await?;
+    let hashes = sqlx::query!("SELECT blake3, sha256 FROM item WHERE id = ?", item_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let info = fs::read_to_string(model_path.with_extension("json"))
+        .await
+        .unwrap_or_default();
+    let info: Value = serde_json::from_str(&info).unwrap_or_default();
+
+    let sidecar = Sidecar {
+        tags: state
+            .tags
+            .split_whitespace()
+            .filter(|t| is_user_tag(t))
+            .map(|t| t.to_string())
+            .collect(),
+        note: state.note,
+        rating: item.rating,
+        favorite: item.favorite,
+        provenance: Provenance {
+            blake3: hashes.blake3,
+            sha256: hashes.sha256,
+            civitai_model_id: info["modelId"].as_i64(),
+            civitai_version_id: info["id"].as_i64(),
+            download_url: info["downloadUrl"].as_str().unwrap_or_default().to_string(),
+            updated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64,
+        },
+    };
+    let path = sidecar_path(&model_path);
+    fs::write(&path, serde_json::to_string_pretty(&sidecar)?).await?;
+    // Sidecar has the same data as database, so it is not read back by next scan
+    let modified = modified_ms(&fs::metadata(&path).await?);
+    sqlx::query!("UPDATE item SET sidecar_synced_at = ? WHERE id = ?", modified, item_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Sidecar read from disk, before it is applied to database
+pub struct SidecarFile {
+    sidecar: Sidecar,
+    /// When the file was modified, in milliseconds
+    modified: i64,
+}
+
+/// Read sidecar of model, `None` if there is none. It is read before a transaction is started, see [`apply`].
+pub async fn load(model_path: &Path) -> anyhow::Result<Option<SidecarFile>> {
+    let path = sidecar_path(model_path);
+    let Ok(metadata) = fs::metadata(&path).await else {
+        return Ok(None);
+    };
+    let mut sidecar: Sidecar = serde_json::from_str(&fs::read_to_string(&path).await?)?;
+    // Older sidecars may have tags from model info
+    sidecar.tags.retain(|tag| is_user_tag(tag));
+    Ok(Some(SidecarFile {
+        sidecar,
+        modified: modified_ms(&metadata),
+    }))
+}
+
+/// Apply sidecar loaded by [`load`] to database following `policy`.
+/// Do nothing if there is no sidecar, or it is not changed since it was last written or read, so tags removed in
+/// database are not added back by every scan.
+pub async fn apply(
+    conn: &mut SqliteConnection,
+    item_id: i64,
+    file: Option<&SidecarFile>,
+    policy: SidecarPolicy,
+) -> anyhow::Result<()> {
+    let Some(SidecarFile { sidecar, modified }) = file else {
+        return Ok(());
+    };
+    let synced_at = sqlx::query_scalar!("SELECT sidecar_synced_at FROM item WHERE id = ?", item_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if synced_at == Some(*modified) {
+        return Ok(());
+    }
+
+    let mut tx = conn.begin().await?;
+    let state = item_state(&mut tx, item_id).await?;
+    let rating = sqlx::query_scalar!("SELECT rating FROM item WHERE id = ?", item_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    let is_empty = state.tags.is_empty() && state.note.is_empty() && rating.is_none();
+
+    match policy {
+        SidecarPolicy::PreferDatabase if !is_empty => {}
+        SidecarPolicy::Merge | SidecarPolicy::PreferDatabase => {
+            add_tag_item(&mut tx, item_id, &sidecar.tags, Some(USER_TAG_TYPE)).await?;
+            if state.note.is_empty() {
+                update_item_note(&mut tx, item_id, &sidecar.note).await?;
+            }
+            let rating = rating.or(sidecar.rating);
+            sqlx::query!(
+                "UPDATE item SET rating = ?, favorite = favorite OR ? WHERE id = ?",
+                rating,
+                sidecar.favorite,
+                item_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        SidecarPolicy::PreferSidecar => {
+            // Tags from model info are not in sidecar, keep them
+            let mut tags = sidecar.tags.clone();
+            tags.extend(
+                state
+                    .tags
+                    .split_whitespace()
+                    .filter(|t| !is_user_tag(t))
+                    .map(|t| t.to_string()),
+            );
+            update_tag_item(&mut tx, item_id, &tags.join(" ")).await?;
+            update_item_note(&mut tx, item_id, &sidecar.note).await?;
+            sqlx::query!(
+                "UPDATE item SET rating = ?, favorite = ? WHERE id = ?",
+                sidecar.rating,
+                sidecar.favorite,
+                item_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    sqlx::query!("UPDATE item SET sidecar_synced_at = ? WHERE id = ?", *modified, item_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{TestDir, test_pool};
+    use std::collections::HashMap;
+
+    /// Item 1 with a user tag, an auto tag, note and rating, and a sidecar with other values
+    async fn item_with_sidecar(name: &str) -> (SqlitePool, TestDir, PathBuf) {
+        let pool = test_pool().await;
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query(
+            "INSERT INTO item (id, path, base_label, note, rating) VALUES (1, 'a.safetensors', 'c', 'local', 3)",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        let tags = vec!["user:mine".to_string(), "base:sdxl".to_string()];
+        add_tag_item(&mut conn, 1, &tags, None).await.unwrap();
+
+        let dir = TestDir::new(&format!("sidecar-{name}"));
+        let model_path = dir.join("a.safetensors");
+        let sidecar = Sidecar {
+            tags: vec!["user:theirs".to_string(), "base:sd15".to_string()],
+            note: "sidecar".to_string(),
+            rating: Some(5),
+            favorite: true,
+            ..Default::default()
+        };
+        std::fs::write(sidecar_path(&model_path), serde_json::to_string(&sidecar).unwrap()).unwrap();
+        (pool, dir, model_path)
+    }
+
+    async fn state(pool: &SqlitePool) -> (String, String, Option<i64>, bool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let state = item_state(&mut conn, 1).await.unwrap();
+        let (rating, favorite): (Option<i64>, bool) = sqlx::query_as("SELECT rating, favorite FROM item WHERE id = 1")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        (state.tags, state.note, rating, favorite)
+    }
+
+    async fn read_sidecar(pool: &SqlitePool, model_path: &Path, policy: SidecarPolicy) {
+        let file = load(model_path).await.unwrap();
+        apply(&mut pool.acquire().await.unwrap(), 1, file.as_ref(), policy)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn merge_adds_tags_and_keeps_local_values() {
+        let (pool, _dir, model_path) = item_with_sidecar("merge").await;
+        read_sidecar(&pool, &model_path, SidecarPolicy::Merge).await;
+        assert_eq!(
+            state(&pool).await,
+            (
+                "base:sdxl user:mine user:theirs".to_string(),
+                "local".to_string(),
+                Some(3),
+                true
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn prefer_database_ignores_sidecar_of_curated_item() {
+        let (pool, _dir, model_path) = item_with_sidecar("prefer-database").await;
+        read_sidecar(&pool, &model_path, SidecarPolicy::PreferDatabase).await;
+        assert_eq!(
+            state(&pool).await,
+            ("base:sdxl user:mine".to_string(), "local".to_string(), Some(3), false)
+        );
+    }
+
+    #[tokio::test]
+    async fn prefer_sidecar_replaces_user_data_and_keeps_auto_tags() {
+        let (pool, _dir, model_path) = item_with_sidecar("prefer-sidecar").await;
+        read_sidecar(&pool, &model_path, SidecarPolicy::PreferSidecar).await;
+        assert_eq!(
+            state(&pool).await,
+            (
+                "base:sdxl user:theirs".to_string(),
+                "sidecar".to_string(),
+                Some(5),
+                true
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_sidecar_is_read_once() {
+        let (pool, _dir, model_path) = item_with_sidecar("read-once").await;
+        read_sidecar(&pool, &model_path, SidecarPolicy::Merge).await;
+        update_tag_item(&mut pool.acquire().await.unwrap(), 1, "user:mine")
+            .await
+            .unwrap();
+        read_sidecar(&pool, &model_path, SidecarPolicy::Merge).await;
+        assert_eq!(state(&pool).await.0, "user:mine");
+    }
+
+    #[tokio::test]
+    async fn written_sidecar_has_user_data_only() {
+        let (pool, _dir, model_path) = item_with_sidecar("write").await;
+        let config = Config {
+            model_paths: HashMap::from([(
+                "c".to_string(),
+                model_path.parent().unwrap().to_string_lossy().to_string(),
+            )]),
+            ..Default::default()
+        };
+        write(&config, &pool, 1).await.unwrap();
+
+        let sidecar: Sidecar =
+            serde_json::from_str(&std::fs::read_to_string(sidecar_path(&model_path)).unwrap()).unwrap();
+        assert_eq!(sidecar.tags, vec!["user:mine"]);
+        assert_eq!(sidecar.note, "local");
+        assert_eq!(sidecar.rating, Some(3));
+        // Written sidecar is not read back
+        read_sidecar(&pool, &model_path, SidecarPolicy::PreferSidecar).await;
+        assert_eq!(state(&pool).await.0, "base:sdxl user:mine");
+    }
+}
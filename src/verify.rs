@@ -0,0 +1,203 @@
+//! Check model files against the hashes recorded by Civitai in `files[].hashes` of their `.json`
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BUF_SIZE: usize = 1 << 20;
+
+pub struct FileHashes {
+    pub blake3: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// Hashes match a file of the Civitai model version
+    Ok,
+    /// Civitai info has no hash to compare with
+    Unknown,
+    /// File does not match, with the reason
+    Corrupt(String),
+}
+
+/// Bytes read per second by all files hashed in parallel
+pub struct RateLimit {
+    /// Unlimited if 0
+    bytes_per_sec: u64,
+    started: Instant,
+    read_bytes: AtomicU64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            started: Instant::now(),
+            read_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Count `n` read bytes and sleep until all bytes read so far are due
+    fn wait(&self, n: u64) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        let read_bytes = self.read_bytes.fetch_add(n, Ordering::Relaxed) + n;
+        let due = Duration::from_secs_f64(read_bytes as f64 / self.bytes_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// Read `path` once for BLAKE3 and SHA256, slowed down by `limit`
+pub fn hash_file(path: &Path, limit: &RateLimit) -> io::Result<FileHashes> {
+    let mut file = File::open(path)?;
+    let mut blake3 = blake3::Hasher::new();
+    let mut sha256 = Sha256::new();
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        blake3.update(&buf[..n]);
+        sha256.update(&buf[..n]);
+        limit.wait(n as u64);
+    }
+    Ok(FileHashes {
+        blake3: blake3.finalize().to_hex().to_string(),
+        sha256: format!("{:x}", sha256.finalize()),
+    })
+}
+
+/// Compare `hashes` with the files of Civitai `info`. A model version can have several files, e.g. pruned and full, so
+/// the file is found by its BLAKE3, or by its SHA256 if Civitai has no BLAKE3 for it.
+pub fn check(info: &Value, hashes: &FileHashes) -> Verdict {
+    let files = info["files"].as_array().cloned().unwrap_or_default();
+    let recorded = |file: &Value, name: &str| {
+        file["hashes"][name]
+            .as_str()
+            .filter(|hash| !hash.is_empty())
+            .map(|hash| hash.to_lowercase())
+    };
+    let files = files
+        .iter()
+        .map(|file| (recorded(file, "BLAKE3"), recorded(file, "SHA256")))
+        .filter(|(blake3, sha256)| blake3.is_some() || sha256.is_some())
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Verdict::Unknown;
+    }
+
+    let found = files.iter().find(|(blake3, sha256)| match blake3 {
+        Some(blake3) => *blake3 == hashes.blake3,
+        None => sha256.as_ref() == Some(&hashes.sha256),
+    });
+    match found {
+        Some((_, Some(sha256))) if *sha256 != hashes.sha256 => {
+            Verdict::Corrupt(format!("SHA256 {} does not match {}", hashes.sha256, sha256))
+        }
+        Some(_) => Verdict::Ok,
+        None => Verdict::Corrupt(format!(
+            "BLAKE3 {} does not match any file of the model version",
+            hashes.blake3
+        )),
+    }
+}
+
+/// File of Civitai `info` which should be at `path`: the file with the same name, the primary file or the only file
+pub fn expected_file<'a>(info: &'a Value, path: &Path) -> Option<&'a Value> {
+    let files = info["files"].as_array()?;
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    files
+        .iter()
+        .find(|file| file["name"].as_str() == Some(name))
+        .or_else(|| files.iter().find(|file| file["primary"].as_bool() == Some(true)))
+        .or(if files.len() == 1 { files.first() } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TestDir;
+    use serde_json::json;
+
+    fn hashes() -> FileHashes {
+        FileHashes {
+            blake3: "b1".to_string(),
+            sha256: "s1".to_string(),
+        }
+    }
+
+    #[test]
+    fn file_is_found_by_hash() {
+        let info = json!({"files": [
+            {"name": "a.safetensors", "hashes": {"BLAKE3": "B0", "SHA256": "S0"}},
+            {"name": "b.safetensors", "hashes": {"BLAKE3": "B1", "SHA256": "S1"}},
+        ]});
+        assert_eq!(check(&info, &hashes()), Verdict::Ok);
+
+        let info = json!({"files": [{"hashes": {"SHA256": "S1"}}]});
+        assert_eq!(check(&info, &hashes()), Verdict::Ok);
+
+        let info = json!({"files": [{"hashes": {}}]});
+        assert_eq!(check(&info, &hashes()), Verdict::Unknown);
+        assert_eq!(check(&Value::Null, &hashes()), Verdict::Unknown);
+    }
+
+    #[test]
+    fn mismatch_is_corrupt() {
+        let info = json!({"files": [{"hashes": {"BLAKE3": "B0", "SHA256": "S1"}}]});
+        assert!(matches!(check(&info, &hashes()), Verdict::Corrupt(_)));
+
+        let info = json!({"files": [{"hashes": {"BLAKE3": "B1", "SHA256": "S0"}}]});
+        assert!(matches!(check(&info, &hashes()), Verdict::Corrupt(e) if e.contains("SHA256")));
+    }
+
+    #[test]
+    fn expected_file_by_name_or_primary() {
+        let info = json!({"files": [
+            {"name": "a.safetensors", "primary": true},
+            {"name": "b.safetensors"},
+        ]});
+        assert_eq!(
+            expected_file(&info, Path::new("/m/b.safetensors")).unwrap()["name"],
+            "b.safetensors"
+        );
+        assert_eq!(
+            expected_file(&info, Path::new("/m/c.safetensors")).unwrap()["name"],
+            "a.safetensors"
+        );
+    }
+
+    #[test]
+    fn rate_is_shared_by_threads() {
+        let limit = RateLimit::new(1_000_000);
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| limit.wait(100_000));
+            }
+        });
+        assert!(limit.started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn hashes_of_file() {
+        let dir = TestDir::new("verify-hash");
+        let path = dir.join("a.safetensors");
+        std::fs::write(&path, "abc").unwrap();
+        let hashes = hash_file(&path, &RateLimit::new(0)).unwrap();
+        assert_eq!(hashes.blake3, blake3::hash(b"abc").to_hex().to_string());
+        assert_eq!(
+            hashes.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}